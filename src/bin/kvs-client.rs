@@ -1,7 +1,7 @@
 use std::{env, error::Error, net::SocketAddr};
 
-use clap::{command, Parser};
-use kvs::Client;
+use clap::Parser;
+use kvs::{Client, ListEnd};
 
 #[derive(Parser)]
 #[command(
@@ -24,6 +24,35 @@ pub enum Opts {
     Set(SetArgs),
     #[command(name = "rm")]
     Remove(RmArgs),
+    Mget(MgetArgs),
+    Mset(MsetArgs),
+    #[command(about = "Increment the integer value of a key, printing the new value")]
+    Incr(IncrArgs),
+    #[command(about = "Decrement the integer value of a key, printing the new value")]
+    Decr(IncrArgs),
+    #[command(about = "Push a value to the front of a list, printing its length")]
+    Lpush(ListPushArgs),
+    #[command(about = "Push a value to the back of a list, printing its length")]
+    Rpush(ListPushArgs),
+    #[command(about = "Pop a value from the front of a list")]
+    Lpop(CollectionArgs),
+    #[command(about = "Pop a value from the back of a list")]
+    Rpop(CollectionArgs),
+    #[command(about = "Get the elements of a list between two indexes inclusive")]
+    Lrange(ListRangeArgs),
+    #[command(about = "Set a field of a hash")]
+    Hset(HashSetArgs),
+    #[command(about = "Get a field of a hash")]
+    Hget(FieldArgs),
+    #[command(about = "Remove a field of a hash, printing 1 if it existed and 0 otherwise")]
+    Hdel(FieldArgs),
+    #[command(about = "Add a member to a set, printing 1 if it was new and 0 otherwise")]
+    Sadd(MemberArgs),
+    #[command(about = "Remove a member of a set, printing 1 if it existed and 0 otherwise")]
+    Srem(MemberArgs),
+    #[command(about = "Get the members of a set")]
+    Smembers(CollectionArgs),
+    Ns(NsArgs),
 }
 
 #[derive(clap::Args)]
@@ -31,6 +60,13 @@ pub enum Opts {
 pub struct GetArgs {
     #[arg(help = "A string key")]
     key: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
     #[arg(
         short,
         long,
@@ -48,6 +84,57 @@ pub struct SetArgs {
     key: String,
     #[arg(help = "The string value of the key")]
     value: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+#[command(about = "Get the string values of many keys in one request")]
+pub struct MgetArgs {
+    #[arg(required = true, help = "String keys")]
+    keys: Vec<String>,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the keys, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+#[command(about = "Set many string keys in one request")]
+pub struct MsetArgs {
+    #[arg(required = true, help = "Pairs of a string key and its string value")]
+    pairs: Vec<String>,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the keys, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
     #[arg(
         short,
         long,
@@ -63,6 +150,13 @@ pub struct SetArgs {
 pub struct RmArgs {
     #[arg(help = "A string key")]
     key: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
     #[arg(
         short,
         long,
@@ -73,12 +167,218 @@ pub struct RmArgs {
     addr: SocketAddr,
 }
 
+#[derive(clap::Args)]
+pub struct IncrArgs {
+    #[arg(help = "A string key")]
+    key: String,
+    #[arg(
+        help = "The amount to change the value by",
+        default_value_t = 1,
+        allow_negative_numbers = true
+    )]
+    delta: i64,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+pub struct CollectionArgs {
+    #[arg(help = "A list, hash or set key")]
+    key: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+pub struct ListPushArgs {
+    #[arg(help = "A list, hash or set key")]
+    key: String,
+    #[arg(help = "The value to push")]
+    value: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+pub struct ListRangeArgs {
+    #[arg(help = "A list, hash or set key")]
+    key: String,
+    #[arg(
+        help = "Index of the first element, negative from the end",
+        allow_negative_numbers = true
+    )]
+    start: i64,
+    #[arg(
+        help = "Index of the last element, negative from the end",
+        allow_negative_numbers = true
+    )]
+    stop: i64,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+pub struct HashSetArgs {
+    #[arg(help = "A list, hash or set key")]
+    key: String,
+    #[arg(help = "A field of the hash")]
+    field: String,
+    #[arg(help = "The string value of the field")]
+    value: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+pub struct FieldArgs {
+    #[arg(help = "A list, hash or set key")]
+    key: String,
+    #[arg(help = "A field of the hash")]
+    field: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+pub struct MemberArgs {
+    #[arg(help = "A list, hash or set key")]
+    key: String,
+    #[arg(help = "A member of the set")]
+    member: String,
+    #[arg(
+        short,
+        long,
+        help = "The namespace of the key, the default namespace if not given",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Args)]
+#[command(about = "Create, list or drop namespaces")]
+pub struct NsArgs {
+    #[command(subcommand)]
+    command: NsCommand,
+    #[arg(
+        short,
+        long,
+        help = "Sets the listening address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        global = true
+    )]
+    addr: SocketAddr,
+}
+
+#[derive(clap::Subcommand)]
+pub enum NsCommand {
+    #[command(about = "Create an empty namespace")]
+    Create {
+        #[arg(help = "The name of the namespace")]
+        name: String,
+    },
+    #[command(about = "List the namespaces")]
+    List,
+    #[command(about = "Drop a namespace with all its keys")]
+    Drop {
+        #[arg(help = "The name of the namespace")]
+        name: String,
+    },
+}
+
 pub fn main() -> Result<(), Box<dyn Error>> {
     let opts = Opts::parse();
     let _store_dir = env::current_dir().unwrap();
     match opts {
         Opts::Get(args) => {
             let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
             if let Some(value) = client.get(args.key)? {
                 println!("{}", value);
             } else {
@@ -88,13 +388,135 @@ pub fn main() -> Result<(), Box<dyn Error>> {
         Opts::Set(args) => {
             // println!("set: {}:{}", args.key, args.value);
             let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
             client.set(args.key, args.value)?
         }
         Opts::Remove(args) => {
             // println!("remove: {}", args.key);
             let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
             client.remove(args.key)?;
         }
+        Opts::Mget(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            for value in client.get_many(args.keys)? {
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        Opts::Mset(args) => {
+            if args.pairs.len() % 2 != 0 {
+                return Err("every key needs a value".into());
+            }
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            let pairs = args
+                .pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            client.set_many(pairs)?;
+        }
+        Opts::Incr(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!("{}", client.incr_by(args.key, args.delta)?);
+        }
+        Opts::Decr(args) => {
+            let delta = args.delta.checked_neg().ok_or("decrement out of range")?;
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!("{}", client.incr_by(args.key, delta)?);
+        }
+        Opts::Lpush(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!(
+                "{}",
+                client.list_push(args.key, ListEnd::Front, args.value)?
+            );
+        }
+        Opts::Rpush(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!("{}", client.list_push(args.key, ListEnd::Back, args.value)?);
+        }
+        Opts::Lpop(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            if let Some(value) = client.list_pop(args.key, ListEnd::Front)? {
+                println!("{}", value);
+            } else {
+                println!("List is empty");
+            }
+        }
+        Opts::Rpop(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            if let Some(value) = client.list_pop(args.key, ListEnd::Back)? {
+                println!("{}", value);
+            } else {
+                println!("List is empty");
+            }
+        }
+        Opts::Lrange(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            for value in client.list_range(args.key, args.start, args.stop)? {
+                println!("{}", value);
+            }
+        }
+        Opts::Hset(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            client.hash_set(args.key, args.field, args.value)?;
+        }
+        Opts::Hget(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            if let Some(value) = client.hash_get(args.key, args.field)? {
+                println!("{}", value);
+            } else {
+                println!("Field not found");
+            }
+        }
+        Opts::Hdel(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!("{}", client.hash_remove(args.key, args.field)? as u8);
+        }
+        Opts::Sadd(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!("{}", client.set_add(args.key, args.member)? as u8);
+        }
+        Opts::Srem(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            println!("{}", client.set_remove(args.key, args.member)? as u8);
+        }
+        Opts::Smembers(args) => {
+            let mut client = Client::connect(args.addr)?;
+            client.set_namespace(args.namespace);
+            for member in client.set_members(args.key)? {
+                println!("{}", member);
+            }
+        }
+        Opts::Ns(args) => {
+            let client = Client::connect(args.addr)?;
+            match args.command {
+                NsCommand::Create { name } => client.create_namespace(name)?,
+                NsCommand::List => {
+                    for name in client.list_namespaces()? {
+                        println!("{}", name);
+                    }
+                }
+                NsCommand::Drop { name } => client.drop_namespace(name)?,
+            }
+        }
     }
     Ok(())
 }
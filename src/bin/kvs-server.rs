@@ -1,35 +1,7 @@
-use std::{env, error::Error, fs, net::SocketAddr};
+use std::{env, error::Error, net::SocketAddr, path::PathBuf};
 
-use clap::{
-    builder::{IntoResettable, OsStr, Resettable},
-    command, Parser, ValueEnum,
-};
-use kvs::{KvStore, KvsEngine, Server, SledKvsEngine};
-
-#[allow(non_camel_case_types)]
-#[derive(Clone, Debug, ValueEnum)]
-enum Engine {
-    kvs,
-    sled,
-}
-
-impl ToString for Engine {
-    fn to_string(&self) -> String {
-        match *self {
-            Engine::kvs => "kvs".to_owned(),
-            Engine::sled => "sled".to_owned(),
-        }
-    }
-}
-
-impl IntoResettable<OsStr> for Engine {
-    fn into_resettable(self) -> Resettable<OsStr> {
-        match self {
-            Engine::kvs => Resettable::Value("kvs".into()),
-            Engine::sled => Resettable::Value("slet".into()),
-        }
-    }
-}
+use clap::Parser;
+use kvs::{open_engine_with_options, EngineKind, Keyring, KvStoreOptions, Protocol, Server};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -54,43 +26,51 @@ pub struct Opts {
         long,
         help = "Sets the storage engine",
         value_name = "ENGINE-NAME",
-        default_value = Engine::kvs,
+        default_value_t = EngineKind::Kvs,
+    )]
+    engine: EngineKind,
+
+    #[arg(
+        short,
+        long,
+        help = "Sets the protocol spoken to clients, `resp` for Redis clients",
+        value_name = "PROTOCOL",
+        default_value = "kvs"
     )]
-    engine: Engine,
+    protocol: Protocol,
+
+    #[arg(
+        long,
+        env = "KVS_KEY_FILE",
+        help = "Encrypts the store with the keys of a file of `<id> <64 hex digits>` lines, \
+                the highest id being the current key",
+        value_name = "PATH"
+    )]
+    key_file: Option<PathBuf>,
 }
 
 pub fn main() -> Result<(), Box<dyn Error>> {
     let opts = Opts::parse();
     println!("opts: {:?}", opts);
     eprintln!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    eprintln!("Storage engine: {:?}", opts.engine);
+    eprintln!("Storage engine: {}", opts.engine);
+    eprintln!("Protocol: {:?}", opts.protocol);
     eprintln!("Listening on {}", opts.addr);
 
-    check_engine(&opts)?;
-
-    let engine: Box<dyn KvsEngine> = match opts.engine {
-        Engine::kvs => Box::new(KvStore::open(env::current_dir()?)?),
-        Engine::sled => Box::new(SledKvsEngine::new(sled::open(env::current_dir()?)?)),
+    let keyring = match &opts.key_file {
+        Some(path) => Keyring::from_key_file(path)?,
+        None => Keyring::new(),
     };
-    Server::new(engine, opts.addr).run()?;
-    Ok(())
-}
-
-fn check_engine(opts: &Opts) -> Result<(), Box<dyn Error>> {
-    let engine_file = env::current_dir()?.join("engine");
-    let engine = fs::read_to_string(&engine_file);
-    match engine {
-        Ok(engine) => {
-            if engine != opts.engine.to_string() {
-                panic!("The engine before is {}", engine)
-            }
-        }
-        Err(e) => {
-            println!("engine file does not exist: {}, create new file", e);
-            // write engine to engine file
-            fs::write(&engine_file, format!("{:?}", opts.engine))?;
-        }
-    }
-
+    let options = KvStoreOptions {
+        keyring,
+        ..KvStoreOptions::default()
+    };
+    let engine = open_engine_with_options(env::current_dir()?, opts.engine, &options)?;
+    let mut server = Server::bind(engine, opts.addr)?;
+    server.set_protocol(opts.protocol);
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown())?;
+    server.run()?;
+    eprintln!("Shut down");
     Ok(())
 }
@@ -1,7 +1,9 @@
-use std::{env::current_dir, error::Error, process};
+use std::{env::current_dir, error::Error, path::PathBuf, process};
 
 use clap::Parser;
-use kvs::{KvStore, KvsEngine};
+use kvs::{
+    check_engine, open_engine_with_options, EngineKind, Keyring, KvStore, KvStoreOptions, KvsEngine,
+};
 
 #[derive(Parser)]
 #[command(
@@ -11,11 +13,28 @@ use kvs::{KvStore, KvsEngine};
     version = env!("CARGO_PKG_VERSION"),
     about = env!("CARGO_PKG_DESCRIPTION"),
 )]
-pub enum Opts {
+pub struct Opts {
+    #[command(subcommand)]
+    command: Command,
+
+    #[arg(
+        long,
+        global = true,
+        env = "KVS_KEY_FILE",
+        help = "Encrypts the store with the keys of a file of `<id> <64 hex digits>` lines, \
+                the highest id being the current key",
+        value_name = "PATH"
+    )]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
     Get(GetArgs),
     Set(SetArgs),
     #[command(name = "rm")]
     Remove(RmArgs),
+    Repair(RepairArgs),
 }
 
 #[derive(clap::Args)]
@@ -41,12 +60,28 @@ pub struct RmArgs {
     key: String,
 }
 
+#[derive(clap::Args)]
+#[command(
+    about = "Salvage a damaged store, moving unreadable log files and the blobs they may refer to to lost+found"
+)]
+pub struct RepairArgs {}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let opts = Opts::parse();
     let store_dir = current_dir().unwrap();
-    let mut store = KvStore::open(store_dir).unwrap();
-    match opts {
-        Opts::Get(args) => {
+    let keyring = match &opts.key_file {
+        Some(path) => Keyring::from_key_file(path)?,
+        None => Keyring::new(),
+    };
+    let options = KvStoreOptions {
+        keyring,
+        ..KvStoreOptions::default()
+    };
+    match opts.command {
+        Command::Get(args) => {
+            // a one-off read doesn't need the lock, so it works while a server runs.
+            check_engine(&store_dir, EngineKind::Kvs)?;
+            let store = KvStore::open_read_only_with_options(store_dir, &options)?;
             match store.get(args.key)? {
                 Some(value) => println!("{value}"),
                 None => {
@@ -55,17 +90,38 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
             };
         }
-        Opts::Set(args) => {
+        Command::Set(args) => {
+            let store = open_engine_with_options(store_dir, EngineKind::Kvs, &options)?;
             store.set(args.key, args.value)?;
         }
-        Opts::Remove(args) => match store.remove(args.key) {
-            Ok(_) => {}
-            Err(kvs::KvsError::KeyNotFound) => {
-                println!("Key not found");
-                process::exit(-1);
+        Command::Remove(args) => {
+            let store = open_engine_with_options(store_dir, EngineKind::Kvs, &options)?;
+            match store.remove(args.key) {
+                Ok(_) => {}
+                Err(kvs::KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    process::exit(-1);
+                }
+                _ => todo!(),
+            }
+        }
+        Command::Repair(_) => {
+            check_engine(&store_dir, EngineKind::Kvs)?;
+            let report = KvStore::repair_with_options(store_dir, &options)?;
+            println!("Recovered {} keys", report.recovered_keys);
+            for (gen, range) in &report.skipped {
+                println!(
+                    "Skipped bytes {}..{} of generation {}",
+                    range.start, range.end, gen
+                );
             }
-            _ => todo!(),
-        },
+            for path in &report.quarantined {
+                println!("Quarantined {}", path.display());
+            }
+            for key in &report.suspect_keys {
+                println!("Possibly lost: {}", key);
+            }
+        }
     }
     Ok(())
 }
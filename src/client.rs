@@ -1,63 +1,581 @@
 use std::{
+    collections::HashMap,
     io::{BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpStream},
-    ptr::read,
+    net::{Shutdown, SocketAddr, TcpStream},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
-use serde::Deserialize;
-use serde_json::{de::IoRead, Deserializer};
-
-use crate::{error::Result, GetResponse, KvsError, RemoveResponse, Request, SetResponse};
+use crate::{
+    common::{read_frame, write_frame},
+    error::Result,
+    Envelope, Hello, KvsError, ListEnd, Reply, Request, Response, Welcome, FEATURES, MAGIC,
+    PROTOCOL_VERSIONS,
+};
 
+/// A connection to a server.
+///
+/// Clones share the connection, and may send requests from many threads at
+/// once: responses are matched to their requests by id.
+#[derive(Clone)]
 pub struct Client {
-    _addr: SocketAddr,
-    writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    connection: Arc<Connection>,
+    // Namespace of the keys of all requests but the namespace ones.
+    namespace: Option<String>,
 }
 
+/// The connection shared by the clones of a `Client`.
+struct Connection {
+    writer: Mutex<BufWriter<TcpStream>>,
+    next_id: AtomicU64,
+    pending: Arc<Pending>,
+    // Negotiated in the handshake.
+    version: u16,
+    features: Vec<String>,
+}
+
+/// Senders of the responses awaited, by request id, or `None` once the
+/// connection closed.
+type Pending = Mutex<Option<HashMap<u64, Sender<Response>>>>;
+
 impl Client {
+    /// Connects to the server at `addr` with the framed binary protocol.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Handshake` if the server speaks none of the
+    /// protocol versions of the client.
     pub fn connect(addr: SocketAddr) -> Result<Self> {
         let stream = TcpStream::connect(addr)?;
-        let reader = BufReader::new(stream.try_clone()?);
-        let deserializer = Deserializer::from_reader(reader);
-        let writer = BufWriter::new(stream);
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        writer.write_all(&MAGIC)?;
+        let hello = Hello {
+            versions: PROTOCOL_VERSIONS,
+            features: FEATURES.iter().map(|&feature| feature.to_owned()).collect(),
+        };
+        write_frame(&mut writer, &hello)?;
+        writer.flush()?;
+        let welcome = read_frame(&mut reader)?
+            .ok_or_else(|| KvsError::Handshake("connection closed".to_owned()))?;
+        let (version, features) = match bincode::deserialize(&welcome)? {
+            Welcome::Ok { version, features } => (version, features),
+            Welcome::Err(msg) => return Err(KvsError::Handshake(msg)),
+        };
+
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let dispatcher = Arc::clone(&pending);
+        thread::spawn(move || dispatch(reader, &dispatcher));
+        let connection = Connection {
+            writer: Mutex::new(writer),
+            next_id: AtomicU64::new(0),
+            pending,
+            version,
+            features,
+        };
         Ok(Self {
-            _addr: addr,
-            reader: deserializer,
-            writer,
+            connection: Arc::new(connection),
+            namespace: None,
         })
     }
 
+    /// Makes key requests act on the given namespace, or on the default
+    /// namespace with `None`.
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
+    /// Starts a pipeline of requests, sent together by `Pipeline::execute`.
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Returns the protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u16 {
+        self.connection.version
+    }
+
+    /// Returns true if the server supports the protocol feature `feature`,
+    /// one of `FEATURES`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.connection.features.iter().any(|f| f == feature)
+    }
+
+    /// Returns true once the connection to the server closed.
+    pub fn is_closed(&self) -> bool {
+        self.connection
+            .pending
+            .lock()
+            .expect("pending lock poisoned")
+            .is_none()
+    }
+
+    /// Sends `request` and waits for its response, failing on `Response::Err`.
+    fn call(&self, request: Request) -> Result<Response> {
+        let receiver = self.connection.send(vec![request])?.remove(0);
+        match receiver.recv().map_err(|_| closed())? {
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            resp => Ok(resp),
+        }
+    }
+
     /// Get the value of a given key from the server.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let resp = self.call(Request::Get {
+            key,
+            namespace: self.namespace.clone(),
+        })?;
         match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
         }
     }
 
     /// Set the value of a string key in the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let resp = self.call(Request::Set {
+            key,
+            value,
+            namespace: self.namespace.clone(),
+            previous: false,
+        })?;
         match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            Response::Done => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set the value of a string key in the server, returning the value it replaced.
+    pub fn swap(&self, key: String, value: String) -> Result<Option<String>> {
+        let resp = self.call(Request::Set {
+            key,
+            value,
+            namespace: self.namespace.clone(),
+            previous: true,
+        })?;
+        match resp {
+            Response::Value(old) => Ok(old),
+            resp => Err(unexpected(resp)),
         }
     }
 
     /// Remove a string key in the server.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+    pub fn remove(&self, key: String) -> Result<()> {
+        let resp = self.call(Request::Remove {
+            key,
+            namespace: self.namespace.clone(),
+            previous: false,
+        })?;
+        match resp {
+            Response::Done => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove a string key in the server, returning its value.
+    pub fn take(&self, key: String) -> Result<String> {
+        let resp = self.call(Request::Remove {
+            key,
+            namespace: self.namespace.clone(),
+            previous: true,
+        })?;
+        match resp {
+            Response::Value(Some(value)) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the values of many keys from the server in one request.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let resp = self.call(Request::GetMany {
+            keys,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::ManyValues(values) => Ok(values),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set many string keys in the server in one request.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let resp = self.call(Request::SetMany {
+            pairs,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Done => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove many keys in the server in one request, returning how many existed.
+    pub fn remove_many(&self, keys: Vec<String>) -> Result<u64> {
+        let resp = self.call(Request::RemoveMany {
+            keys,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Len(removed) => Ok(removed),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Add `delta` to the integer value of a key in the server, returning the new value.
+    ///
+    /// A missing key counts as 0.
+    pub fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let resp = self.call(Request::Incr {
+            key,
+            delta,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Int(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Push a value to a list in the server, returning the new length of the list.
+    pub fn list_push(&self, key: String, end: ListEnd, value: String) -> Result<u64> {
+        let resp = self.call(Request::ListPush {
+            key,
+            end,
+            value,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Len(len) => Ok(len),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Pop a value from a list in the server.
+    pub fn list_pop(&self, key: String, end: ListEnd) -> Result<Option<String>> {
+        let resp = self.call(Request::ListPop {
+            key,
+            end,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the elements of a list in the server from `start` to `stop` inclusive.
+    ///
+    /// Negative indexes count from the end of the list.
+    pub fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let resp = self.call(Request::ListRange {
+            key,
+            start,
+            stop,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Values(values) => Ok(values),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set a field of a hash in the server.
+    pub fn hash_set(&self, key: String, field: String, value: String) -> Result<()> {
+        let resp = self.call(Request::HashSet {
+            key,
+            field,
+            value,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Done => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get a field of a hash from the server.
+    pub fn hash_get(&self, key: String, field: String) -> Result<Option<String>> {
+        let resp = self.call(Request::HashGet {
+            key,
+            field,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove a field of a hash in the server, returning whether it existed.
+    pub fn hash_remove(&self, key: String, field: String) -> Result<bool> {
+        let resp = self.call(Request::HashRemove {
+            key,
+            field,
+            namespace: self.namespace.clone(),
+        })?;
         match resp {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            Response::Flag(removed) => Ok(removed),
+            resp => Err(unexpected(resp)),
         }
     }
+
+    /// Add a member to a set in the server, returning whether it was new.
+    pub fn set_add(&self, key: String, member: String) -> Result<bool> {
+        let resp = self.call(Request::SetAdd {
+            key,
+            member,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Flag(added) => Ok(added),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove a member of a set in the server, returning whether it existed.
+    pub fn set_remove(&self, key: String, member: String) -> Result<bool> {
+        let resp = self.call(Request::SetRemove {
+            key,
+            member,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Flag(removed) => Ok(removed),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the members of a set from the server.
+    pub fn set_members(&self, key: String) -> Result<Vec<String>> {
+        let resp = self.call(Request::SetMembers {
+            key,
+            namespace: self.namespace.clone(),
+        })?;
+        match resp {
+            Response::Values(members) => Ok(members),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Create a namespace in the server.
+    pub fn create_namespace(&self, name: String) -> Result<()> {
+        let resp = self.call(Request::CreateNamespace { name })?;
+        match resp {
+            Response::Done => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// List the namespaces of the server.
+    pub fn list_namespaces(&self) -> Result<Vec<String>> {
+        let resp = self.call(Request::ListNamespaces)?;
+        match resp {
+            Response::Values(names) => Ok(names),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Drop a namespace and all its keys in the server.
+    pub fn drop_namespace(&self, name: String) -> Result<()> {
+        let resp = self.call(Request::DropNamespace { name })?;
+        match resp {
+            Response::Done => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Makes a round trip to the server, to check that the connection works.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ServerTimeout` if the server doesn't answer
+    /// within `timeout`.
+    pub fn ping(&self, timeout: Duration) -> Result<()> {
+        let receiver = self.connection.send(vec![Request::Ping])?.remove(0);
+        match receiver.recv_timeout(timeout) {
+            Ok(Response::Done) => Ok(()),
+            Ok(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+            Ok(resp) => Err(unexpected(resp)),
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::ServerTimeout),
+            Err(RecvTimeoutError::Disconnected) => Err(closed()),
+        }
+    }
+}
+
+/// Requests queued to be sent to the server in one go.
+///
+/// The server answers them in order, without a round trip per request.
+pub struct Pipeline<'a> {
+    client: &'a Client,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queues a request getting the value of a key.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        let namespace = self.client.namespace.clone();
+        self.requests.push(Request::Get { key, namespace });
+        self
+    }
+
+    /// Queues a request setting the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        let namespace = self.client.namespace.clone();
+        self.requests.push(Request::Set {
+            key,
+            value,
+            namespace,
+            previous: false,
+        });
+        self
+    }
+
+    /// Queues a request setting the value of a key, answered with the value it replaced.
+    pub fn swap(&mut self, key: String, value: String) -> &mut Self {
+        let namespace = self.client.namespace.clone();
+        self.requests.push(Request::Set {
+            key,
+            value,
+            namespace,
+            previous: true,
+        });
+        self
+    }
+
+    /// Queues a request removing a key.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        let namespace = self.client.namespace.clone();
+        self.requests.push(Request::Remove {
+            key,
+            namespace,
+            previous: false,
+        });
+        self
+    }
+
+    /// Queues a request removing a key, answered with its value.
+    pub fn take(&mut self, key: String) -> &mut Self {
+        let namespace = self.client.namespace.clone();
+        self.requests.push(Request::Remove {
+            key,
+            namespace,
+            previous: true,
+        });
+        self
+    }
+
+    /// Returns the number of queued requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns true if no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the queued requests, and returns the result of each, in order.
+    ///
+    /// A request answers with the value it got, replaced or removed, and `None`
+    /// for `set` and `remove`. The outer error is a failure of the connection.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        let receivers = self.client.connection.send(self.requests)?;
+        receivers
+            .into_iter()
+            .map(|receiver| {
+                Ok(match receiver.recv().map_err(|_| closed())? {
+                    Response::Done => Ok(None),
+                    Response::Value(value) => Ok(value),
+                    Response::Err(msg) => Err(KvsError::StringError(msg)),
+                    resp => Err(unexpected(resp)),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Connection {
+    /// Sends `requests` in one go, returning where their responses arrive.
+    fn send(&self, requests: Vec<Request>) -> Result<Vec<Receiver<Response>>> {
+        let count = requests.len() as u64;
+        let first = self.next_id.fetch_add(count, Ordering::Relaxed);
+        let ids = first..first + count;
+
+        // Responses may arrive as soon as the requests are written.
+        let mut receivers = Vec::with_capacity(requests.len());
+        {
+            let mut pending = self.pending.lock().expect("pending lock poisoned");
+            let pending = pending.as_mut().ok_or_else(closed)?;
+            for id in ids.clone() {
+                let (sender, receiver) = mpsc::channel();
+                pending.insert(id, sender);
+                receivers.push(receiver);
+            }
+        }
+
+        if let Err(e) = self.write(ids.clone(), requests) {
+            if let Some(pending) = self.pending.lock().expect("pending lock poisoned").as_mut() {
+                for id in ids {
+                    pending.remove(&id);
+                }
+            }
+            return Err(e);
+        }
+        Ok(receivers)
+    }
+
+    fn write(&self, ids: Range<u64>, requests: Vec<Request>) -> Result<()> {
+        let mut writer = self.writer.lock().expect("writer lock poisoned");
+        for (id, request) in ids.zip(requests) {
+            write_frame(&mut *writer, &Envelope { id, request })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // ends `dispatch` too.
+        if let Ok(writer) = self.writer.get_mut() {
+            let _ = writer.get_ref().shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Hands the replies read from a connection to the requests awaiting them,
+/// until it closes.
+fn dispatch(mut reader: BufReader<TcpStream>, pending: &Pending) {
+    while let Ok(Some(frame)) = read_frame(&mut reader) {
+        let Ok(Reply { id, response }) = bincode::deserialize(&frame) else {
+            break;
+        };
+        let sender = pending
+            .lock()
+            .expect("pending lock poisoned")
+            .as_mut()
+            .and_then(|pending| pending.remove(&id));
+        if let Some(sender) = sender {
+            let _ = sender.send(response);
+        }
+    }
+    // dropping the senders fails the requests still awaiting a response.
+    pending.lock().expect("pending lock poisoned").take();
+}
+
+fn closed() -> KvsError {
+    KvsError::StringError("connection closed".to_owned())
+}
+
+fn unexpected(resp: Response) -> KvsError {
+    KvsError::StringError(format!("unexpected response: {:?}", resp))
 }
@@ -1,26 +1,270 @@
+use std::io::{self, Read, Write};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{KvsError, ListEnd, Result};
+
+/// First bytes of a connection speaking the framed binary protocol, which
+/// no JSON request starts with.
+pub const MAGIC: [u8; 4] = *b"KVSB";
+
+/// Versions of the framed binary protocol this crate speaks.
+pub const PROTOCOL_VERSIONS: (u16, u16) = (1, 1);
+
+/// Optional features of the framed binary protocol this crate supports.
+pub const FEATURES: &[&str] = &["batches", "collections", "namespaces"];
+
+/// Largest frame accepted, in bytes.
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Opens the framed binary protocol, right after `MAGIC`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    /// The oldest and newest protocol versions the client speaks.
+    pub versions: (u16, u16),
+    /// The features the client wants.
+    pub features: Vec<String>,
+}
+
+/// The answer to `Hello`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Welcome {
+    /// The version the connection speaks, and the wanted features the server supports.
+    Ok { version: u16, features: Vec<String> },
+    /// No version is spoken by both sides, and the server closes the connection.
+    Err(String),
+}
+
+impl Hello {
+    /// Returns the answer of a server speaking `versions` and supporting `features`.
+    pub fn negotiate(&self, versions: (u16, u16), features: &[&str]) -> Welcome {
+        let version = self.versions.1.min(versions.1);
+        if version < self.versions.0.max(versions.0) {
+            return Welcome::Err(format!(
+                "no common protocol version, the server speaks {} to {}",
+                versions.0, versions.1
+            ));
+        }
+        Welcome::Ok {
+            version,
+            features: self
+                .features
+                .iter()
+                .filter(|feature| features.contains(&feature.as_str()))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Writes `message` as a frame: its bincode encoding after its length, as
+/// a big-endian `u32`.
+pub(crate) fn write_frame<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    let payload = bincode::serialize(message)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or(KvsError::FrameTooLarge(payload.len() as u64))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads the payload of a frame, or `None` at the end of the stream.
+pub(crate) fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::FrameTooLarge(len.into()));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// A request to the server. Key requests act on `namespace` if there is
+/// one, and on the default namespace otherwise.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// Sets `key`, answering with the value it replaced if `previous` is true.
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        namespace: Option<String>,
+        #[serde(default)]
+        previous: bool,
+    },
+    /// Removes `key`, answering with its value if `previous` is true.
+    Remove {
+        key: String,
+        #[serde(default)]
+        namespace: Option<String>,
+        #[serde(default)]
+        previous: bool,
+    },
+    /// Adds `delta` to the integer value of `key`.
+    Incr {
+        key: String,
+        delta: i64,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// Pushes `value` to the list `key`.
+    ListPush {
+        key: String,
+        end: ListEnd,
+        value: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    ListPop {
+        key: String,
+        end: ListEnd,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// Gets the elements of the list `key` from `start` to `stop` inclusive.
+    ListRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    HashSet {
+        key: String,
+        field: String,
+        value: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    HashGet {
+        key: String,
+        field: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    HashRemove {
+        key: String,
+        field: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    SetAdd {
+        key: String,
+        member: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    SetRemove {
+        key: String,
+        member: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    SetMembers {
+        key: String,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// Gets the values of `keys`.
+    GetMany {
+        keys: Vec<String>,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// Sets each key of `pairs` to its value.
+    SetMany {
+        pairs: Vec<(String, String)>,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// Removes `keys`, skipping the missing ones.
+    RemoveMany {
+        keys: Vec<String>,
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    CreateNamespace {
+        name: String,
+    },
+    ListNamespaces,
+    DropNamespace {
+        name: String,
+    },
+    /// Does nothing, to check that the connection works.
+    Ping,
+}
+
+/// A request tagged with an id chosen by the client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// The answer to the `Envelope` with the same id.
+///
+/// A server may answer the requests of a connection in any order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reply {
+    pub id: u64,
+    pub response: Response,
 }
 
+/// Answer to a `Request::Get` sent without an `Envelope`, as the first
+/// version of the protocol had it.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
     Err(String),
 }
 
+/// Answer to a `Request::Set` sent without an `Envelope`.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
     Err(String),
 }
 
+/// Answer to a `Request::Remove` sent without an `Envelope`.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
 }
+
+/// Response to any request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// Answers a request with no result.
+    Done,
+    /// Answers `Get`, `ListPop`, `HashGet`, and `Set` and `Remove` asking
+    /// for the previous value.
+    Value(Option<String>),
+    /// Answers `Incr` with the new value.
+    Int(i64),
+    /// Answers `ListPush` with the new length of the list, and `RemoveMany`
+    /// with the number of keys removed.
+    Len(u64),
+    /// Answers `HashRemove`, `SetAdd` and `SetRemove`, telling whether the
+    /// collection changed.
+    Flag(bool),
+    /// Answers `ListRange`, `SetMembers` and `ListNamespaces`.
+    Values(Vec<String>),
+    /// Answers `GetMany` with the value of each key.
+    ManyValues(Vec<Option<String>>),
+    /// The request failed.
+    Err(String),
+}
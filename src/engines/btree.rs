@@ -0,0 +1,1049 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io, mem,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::error::{KvsError, Result};
+
+use super::{
+    empty_range, increment,
+    kvs::{lock_dir, read_exact_at},
+    lsm::fnv1a,
+    watch::{WatchHub, Watcher},
+    EngineKind, KvsEngine, ListEnd,
+};
+
+/// Name of the file holding every page of the tree.
+const DATA_FILE: &str = "data.btree";
+
+const PAGE_SIZE: usize = 4096;
+
+/// Pages 0 and 1 are meta pages, written alternately by successive commits.
+const FIRST_NODE_PAGE: u64 = 2;
+
+const MAGIC: &[u8; 8] = b"KVSBTREE";
+
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 2;
+
+/// Size of the node header: its kind and number of entries.
+const HEADER_LEN: usize = 3;
+
+/// Largest encoded entry, so that an overfull node always splits into two that fit.
+const MAX_ENTRY_LEN: usize = (PAGE_SIZE - HEADER_LEN) / 3;
+
+/// Longest key accepted, leaving room in a leaf entry for an overflow reference.
+const MAX_KEY_LEN: usize = 1024;
+
+/// Bytes of value held by an overflow page, after the number of the next one.
+const OVERFLOW_DATA: usize = PAGE_SIZE - 8;
+
+/// B+tree engine
+///
+/// Nodes are fixed-size pages of a single file. Pages are never modified in
+/// place: a write copies the path from the root to its leaf, and commits by
+/// writing the new root to the older of two meta pages. A crash leaves the
+/// previous tree intact. Reads go on in the tree committed when they started,
+/// without waiting for a write in progress.
+pub struct BTreeStore {
+    file: File,
+    // Held by a write for its whole transaction, so writes apply one at a time.
+    tree: Mutex<Tree>,
+    // Only held to swap in a commit and to start or finish a read.
+    snapshots: Mutex<Snapshots>,
+    cache: Mutex<PageCache>,
+    watchers: WatchHub,
+    // Holds the advisory lock on the directory until the store is dropped.
+    _lock: File,
+}
+
+/// Options for opening a `BTreeStore`.
+#[derive(Debug, Clone)]
+pub struct BTreeOptions {
+    /// Number of decoded node pages kept in memory.
+    pub cache_pages: usize,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        Self { cache_pages: 1024 }
+    }
+}
+
+struct Tree {
+    meta: Meta,
+    // Pages no longer reachable from the root, rebuilt by walking the tree on open.
+    free: Vec<u64>,
+    // Pages dropped by the commit after the tree of each txid, free once no
+    // read of that tree or an older one is left.
+    retired: VecDeque<(u64, Vec<u64>)>,
+}
+
+struct Snapshots {
+    meta: Meta,
+    // Number of reads in progress in the tree of each txid.
+    readers: BTreeMap<u64, usize>,
+}
+
+/// A read of the tree last committed when it started, whose pages aren't
+/// reused until it is dropped.
+struct Snapshot<'a> {
+    store: &'a BTreeStore,
+    meta: Meta,
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        let mut snapshots = self.store.lock_snapshots();
+        if let Some(count) = snapshots.readers.get_mut(&self.meta.txid) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.readers.remove(&self.meta.txid);
+            }
+        }
+    }
+}
+
+/// The contents of a meta page.
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    txid: u64,
+    root: u64,
+    page_count: u64,
+}
+
+impl Meta {
+    const LEN: usize = 40;
+
+    fn encode(&self) -> [u8; Self::LEN] {
+        let mut buf = [0; Self::LEN];
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..16].copy_from_slice(&self.txid.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.root.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.page_count.to_le_bytes());
+        let checksum = fnv1a(&buf[..32]);
+        buf[32..].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    /// Returns `None` for a page that was never written or was torn by a crash.
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let field = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().expect("8 bytes"));
+        if &buf[..8] != MAGIC || field(32) != fnv1a(&buf[..32]) {
+            return None;
+        }
+        Some(Self {
+            txid: field(8),
+            root: field(16),
+            page_count: field(24),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf(Vec<(String, Value)>),
+    // `children[i]` holds the keys from `keys[i - 1]` up to, excluding, `keys[i]`.
+    Internal {
+        keys: Vec<String>,
+        children: Vec<u64>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Inline(String),
+    // A value too long for a leaf, in a chain of overflow pages.
+    Overflow { page: u64, len: u64 },
+}
+
+/// The result of inserting into a subtree.
+enum Split {
+    One(u64),
+    Two(u64, String, u64),
+}
+
+/// The result of removing from a subtree.
+enum Removed {
+    Node(u64),
+    Empty,
+}
+
+impl BTreeStore {
+    /// Opens the store in `path` with the default options.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store holds the directory.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, &BTreeOptions::default())
+    }
+
+    /// Opens a store like `open`, configured by `options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: &BTreeOptions) -> Result<Self> {
+        let path: PathBuf = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(DATA_FILE))?;
+
+        let meta = Meta {
+            txid: 0,
+            root: FIRST_NODE_PAGE,
+            page_count: FIRST_NODE_PAGE,
+        };
+        let mut store = Self {
+            file,
+            tree: Mutex::new(Tree {
+                meta,
+                free: Vec::new(),
+                retired: VecDeque::new(),
+            }),
+            snapshots: Mutex::new(Snapshots {
+                meta,
+                readers: BTreeMap::new(),
+            }),
+            cache: Mutex::new(PageCache::new(options.cache_pages)),
+            watchers: WatchHub::default(),
+            _lock: lock,
+        };
+        let len = store.file.metadata()?.len();
+        match store.read_meta() {
+            Some(meta) => {
+                let free = store.free_pages(&meta)?;
+                let tree = store.tree.get_mut().expect("tree lock poisoned");
+                tree.meta = meta;
+                tree.free = free;
+                store
+                    .snapshots
+                    .get_mut()
+                    .expect("snapshots lock poisoned")
+                    .meta = meta;
+            }
+            // a crash before the first commit leaves at most the first leaf,
+            // so no data is lost by starting over.
+            None if len <= (FIRST_NODE_PAGE + 1) * PAGE_SIZE as u64 => {
+                store.file.set_len(0)?;
+                // a new tree is a single empty leaf.
+                let mut tree = store.lock_tree();
+                let mut txn = Txn::new(&store, &mut tree);
+                let root = txn.write_node(Node::Leaf(Vec::new()))?;
+                txn.commit(root)?;
+            }
+            None => return Err(invalid_data("no intact meta page").into()),
+        }
+        Ok(store)
+    }
+
+    /// Returns the newest intact meta page.
+    fn read_meta(&self) -> Option<Meta> {
+        let mut newest: Option<Meta> = None;
+        for page in 0..FIRST_NODE_PAGE {
+            let mut buf = [0; Meta::LEN];
+            if read_exact_at(&self.file, &mut buf, page * PAGE_SIZE as u64).is_err() {
+                continue;
+            }
+            if let Some(meta) = Meta::decode(&buf) {
+                if newest.is_none_or(|newest| meta.txid > newest.txid) {
+                    newest = Some(meta);
+                }
+            }
+        }
+        newest
+    }
+
+    /// Returns the pages not reachable from the root of `meta`.
+    fn free_pages(&self, meta: &Meta) -> Result<Vec<u64>> {
+        let mut used = HashSet::new();
+        let mut pages = vec![meta.root];
+        while let Some(page) = pages.pop() {
+            used.insert(page);
+            match &*self.read_node(page)? {
+                Node::Internal { children, .. } => pages.extend(children),
+                Node::Leaf(entries) => {
+                    for (_, value) in entries {
+                        used.extend(self.overflow_pages(value)?);
+                    }
+                }
+            }
+        }
+        Ok((FIRST_NODE_PAGE..meta.page_count)
+            .filter(|page| !used.contains(page))
+            .collect())
+    }
+
+    fn lock_tree(&self) -> MutexGuard<'_, Tree> {
+        self.tree.lock().expect("tree lock poisoned")
+    }
+
+    fn lock_snapshots(&self) -> MutexGuard<'_, Snapshots> {
+        self.snapshots.lock().expect("snapshots lock poisoned")
+    }
+
+    /// Starts a read of the last committed tree.
+    fn snapshot(&self) -> Snapshot<'_> {
+        let mut snapshots = self.lock_snapshots();
+        let meta = snapshots.meta;
+        *snapshots.readers.entry(meta.txid).or_default() += 1;
+        Snapshot { store: self, meta }
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, PageCache> {
+        self.cache.lock().expect("page cache lock poisoned")
+    }
+
+    fn read_node(&self, page: u64) -> Result<Arc<Node>> {
+        if let Some(node) = self.lock_cache().get(page) {
+            return Ok(node);
+        }
+        let mut buf = vec![0; PAGE_SIZE];
+        read_exact_at(&self.file, &mut buf, page * PAGE_SIZE as u64)?;
+        let node = Arc::new(Node::decode(&buf)?);
+        self.lock_cache().insert(page, Arc::clone(&node));
+        Ok(node)
+    }
+
+    fn write_page(&self, page: u64, buf: &[u8]) -> Result<()> {
+        let mut padded = vec![0; PAGE_SIZE];
+        padded[..buf.len()].copy_from_slice(buf);
+        write_all_at(&self.file, &padded, page * PAGE_SIZE as u64)?;
+        Ok(())
+    }
+
+    /// Reads the number of the next page of an overflow chain and the data of this one.
+    fn read_overflow(&self, page: u64) -> Result<(u64, Vec<u8>)> {
+        let mut buf = vec![0; PAGE_SIZE];
+        read_exact_at(&self.file, &mut buf, page * PAGE_SIZE as u64)?;
+        let next = u64::from_le_bytes(buf[..8].try_into().expect("8 bytes"));
+        buf.drain(..8);
+        Ok((next, buf))
+    }
+
+    fn overflow_pages(&self, value: &Value) -> Result<Vec<u64>> {
+        let mut pages = Vec::new();
+        if let Value::Overflow { mut page, .. } = value {
+            while page != 0 {
+                pages.push(page);
+                page = self.read_overflow(page)?.0;
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Returns the value of `key` in the tree at `root`.
+    fn lookup(&self, root: u64, key: &str) -> Result<Option<String>> {
+        let mut page = root;
+        loop {
+            match &*self.read_node(page)? {
+                Node::Internal { keys, children } => {
+                    page = children[keys.partition_point(|k| k.as_str() <= key)];
+                }
+                Node::Leaf(entries) => {
+                    return match entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+                        Ok(i) => self.read_value(&entries[i].1).map(Some),
+                        Err(_) => Ok(None),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Sets `key` in `tree`, whose lock the caller holds.
+    fn set_locked(&self, tree: &mut Tree, key: String, value: String) -> Result<()> {
+        let mut feeds = self.watchers.lock();
+        let event = feeds.watches(&key).then(|| (key.clone(), value.clone()));
+        let root = tree.meta.root;
+        let mut txn = Txn::new(self, tree);
+        let root = txn.set(root, key, value)?;
+        txn.commit(root)?;
+        if let Some((key, value)) = event {
+            feeds.publish(&key, Some(&value));
+        }
+        Ok(())
+    }
+
+    /// Sets all the pairs in `tree` in a single commit, whose lock the caller holds.
+    fn set_many_locked(&self, tree: &mut Tree, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut feeds = self.watchers.lock();
+        let events: Vec<_> = pairs
+            .iter()
+            .filter(|(key, _)| feeds.watches(key))
+            .cloned()
+            .collect();
+        let mut root = tree.meta.root;
+        let mut txn = Txn::new(self, tree);
+        for (key, value) in pairs {
+            root = txn.set(root, key, value)?;
+        }
+        txn.commit(root)?;
+        for (key, value) in &events {
+            feeds.publish(key, Some(value));
+        }
+        Ok(())
+    }
+
+    /// Removes `key` from `tree`, whose lock the caller holds.
+    fn remove_locked(&self, tree: &mut Tree, key: String) -> Result<()> {
+        let root = tree.meta.root;
+        let mut txn = Txn::new(self, tree);
+        let (removed, value) = txn.remove(root, &key)?.ok_or(KvsError::KeyNotFound)?;
+        txn.free_value(&value)?;
+        let mut root = match removed {
+            Removed::Node(root) => root,
+            Removed::Empty => txn.write_node(Node::Leaf(Vec::new()))?,
+        };
+        // drop roots left with a single child.
+        while let Node::Internal { children, .. } = &*self.read_node(root)? {
+            if children.len() > 1 {
+                break;
+            }
+            txn.freed.push(root);
+            root = children[0];
+        }
+        txn.commit(root)?;
+        self.watchers.publish(&key, None);
+        Ok(())
+    }
+
+    fn read_value(&self, value: &Value) -> Result<String> {
+        match value {
+            Value::Inline(value) => Ok(value.clone()),
+            Value::Overflow { mut page, len } => {
+                let mut buf = Vec::with_capacity(*len as usize);
+                while page != 0 {
+                    let (next, data) = self.read_overflow(page)?;
+                    let rest = *len as usize - buf.len();
+                    buf.extend_from_slice(&data[..rest.min(data.len())]);
+                    page = next;
+                }
+                Ok(String::from_utf8(buf)?)
+            }
+        }
+    }
+
+    /// Appends the entries of the subtree at `page` that fall in `range` to
+    /// `out`, until it holds `limit` of them.
+    fn scan_node(
+        &self,
+        page: u64,
+        range: &(Bound<String>, Bound<String>),
+        limit: usize,
+        out: &mut Vec<(String, String)>,
+    ) -> Result<()> {
+        match &*self.read_node(page)? {
+            Node::Leaf(entries) => {
+                let entries = entries.iter().filter(|(key, _)| range.contains(key));
+                for (key, value) in entries.take(limit - out.len()) {
+                    out.push((key.clone(), self.read_value(value)?));
+                }
+            }
+            Node::Internal { keys, children } => {
+                for (i, &child) in children.iter().enumerate() {
+                    if out.len() >= limit {
+                        break;
+                    }
+                    let before_start = keys.get(i).is_some_and(|upper| match &range.0 {
+                        Bound::Included(start) | Bound::Excluded(start) => upper <= start,
+                        Bound::Unbounded => false,
+                    });
+                    let past_end = i.checked_sub(1).is_some_and(|j| match &range.1 {
+                        Bound::Included(end) => keys[j] > *end,
+                        Bound::Excluded(end) => keys[j] >= *end,
+                        Bound::Unbounded => false,
+                    });
+                    if past_end {
+                        break;
+                    }
+                    if !before_start {
+                        self.scan_node(child, range, limit, out)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A write in progress, copying the pages it changes.
+struct Txn<'a> {
+    store: &'a BTreeStore,
+    tree: &'a mut Tree,
+    page_count: u64,
+    // Pages of the current tree replaced by this write, free once it commits.
+    freed: Vec<u64>,
+}
+
+impl<'a> Txn<'a> {
+    fn new(store: &'a BTreeStore, tree: &'a mut Tree) -> Self {
+        let oldest = store.lock_snapshots().readers.keys().next().copied();
+        while let Some((txid, _)) = tree.retired.front() {
+            if oldest.is_some_and(|oldest| oldest <= *txid) {
+                break;
+            }
+            let (_, pages) = tree.retired.pop_front().expect("front exists");
+            tree.free.extend(pages);
+        }
+        let page_count = tree.meta.page_count;
+        Self {
+            store,
+            tree,
+            page_count,
+            freed: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self) -> u64 {
+        self.tree.free.pop().unwrap_or_else(|| {
+            self.page_count += 1;
+            self.page_count - 1
+        })
+    }
+
+    fn write_node(&mut self, node: Node) -> Result<u64> {
+        let page = self.alloc();
+        self.store.write_page(page, &node.encode())?;
+        self.store.lock_cache().insert(page, Arc::new(node));
+        Ok(page)
+    }
+
+    /// Writes `node`, split in two if it no longer fits in a page.
+    fn write_split(&mut self, node: Node) -> Result<Split> {
+        if node.encoded_len() <= PAGE_SIZE {
+            return Ok(Split::One(self.write_node(node)?));
+        }
+        let (left, separator, right) = node.split();
+        Ok(Split::Two(
+            self.write_node(left)?,
+            separator,
+            self.write_node(right)?,
+        ))
+    }
+
+    /// Keeps `value` in the leaf if it is short enough, or in overflow pages.
+    fn store_value(&mut self, key: &str, value: String) -> Result<Value> {
+        if leaf_entry_len(key, &Value::Inline(String::new())) + value.len() <= MAX_ENTRY_LEN {
+            return Ok(Value::Inline(value));
+        }
+        let chunks: Vec<_> = value.as_bytes().chunks(OVERFLOW_DATA).collect();
+        let pages: Vec<_> = chunks.iter().map(|_| self.alloc()).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(0);
+            let mut buf = next.to_le_bytes().to_vec();
+            buf.extend_from_slice(chunk);
+            self.store.write_page(pages[i], &buf)?;
+        }
+        Ok(Value::Overflow {
+            page: pages[0],
+            len: value.len() as u64,
+        })
+    }
+
+    /// Sets `key` in the tree rooted at `root`, returning the new root.
+    fn set(&mut self, root: u64, key: String, value: String) -> Result<u64> {
+        let value = self.store_value(&key, value)?;
+        let (split, old) = self.insert(root, key, value)?;
+        if let Some(old) = old {
+            self.free_value(&old)?;
+        }
+        match split {
+            Split::One(root) => Ok(root),
+            Split::Two(left, separator, right) => self.write_node(Node::Internal {
+                keys: vec![separator],
+                children: vec![left, right],
+            }),
+        }
+    }
+
+    fn free_value(&mut self, value: &Value) -> Result<()> {
+        let pages = self.store.overflow_pages(value)?;
+        self.freed.extend(pages);
+        Ok(())
+    }
+
+    /// Inserts into the subtree at `page`, returning the replaced value if any.
+    fn insert(&mut self, page: u64, key: String, value: Value) -> Result<(Split, Option<Value>)> {
+        let node = self.store.read_node(page)?;
+        self.freed.push(page);
+        match Node::clone(&node) {
+            Node::Leaf(mut entries) => {
+                let old = match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => Some(mem::replace(&mut entries[i].1, value)),
+                    Err(i) => {
+                        entries.insert(i, (key, value));
+                        None
+                    }
+                };
+                Ok((self.write_split(Node::Leaf(entries))?, old))
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let i = keys.partition_point(|k| *k <= key);
+                let (split, old) = self.insert(children[i], key, value)?;
+                match split {
+                    Split::One(child) => children[i] = child,
+                    Split::Two(left, separator, right) => {
+                        children[i] = left;
+                        keys.insert(i, separator);
+                        children.insert(i + 1, right);
+                    }
+                }
+                Ok((self.write_split(Node::Internal { keys, children })?, old))
+            }
+        }
+    }
+
+    /// Removes from the subtree at `page`, returning `None` without writing if
+    /// the key isn't there.
+    fn remove(&mut self, page: u64, key: &str) -> Result<Option<(Removed, Value)>> {
+        let node = self.store.read_node(page)?;
+        let (node, value) = match Node::clone(&node) {
+            Node::Leaf(mut entries) => {
+                match entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+                    Ok(i) => {
+                        let (_, value) = entries.remove(i);
+                        (Node::Leaf(entries), value)
+                    }
+                    Err(_) => return Ok(None),
+                }
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let i = keys.partition_point(|k| k.as_str() <= key);
+                let (removed, value) = match self.remove(children[i], key)? {
+                    Some(removed) => removed,
+                    None => return Ok(None),
+                };
+                match removed {
+                    Removed::Node(child) => children[i] = child,
+                    Removed::Empty => {
+                        children.remove(i);
+                        if !keys.is_empty() {
+                            keys.remove(i.saturating_sub(1));
+                        }
+                    }
+                }
+                (Node::Internal { keys, children }, value)
+            }
+        };
+        self.freed.push(page);
+        // underfull nodes are left as they are; only empty ones are dropped.
+        let removed = if node.is_empty() {
+            Removed::Empty
+        } else {
+            Removed::Node(self.write_node(node)?)
+        };
+        Ok(Some((removed, value)))
+    }
+
+    /// Makes the tree rooted at `root` durable and current.
+    fn commit(self, root: u64) -> Result<()> {
+        // the new pages must reach the disk before the meta page pointing at them.
+        self.store.file.sync_data()?;
+        let meta = Meta {
+            txid: self.tree.meta.txid + 1,
+            root,
+            page_count: self.page_count,
+        };
+        let slot = meta.txid % FIRST_NODE_PAGE;
+        self.store.write_page(slot, &meta.encode())?;
+        // until the meta page is durable, a crash recovers the previous tree,
+        // which may still use the pages this commit frees.
+        self.store.file.sync_data()?;
+        let previous = mem::replace(&mut self.tree.meta, meta);
+        self.tree.retired.push_back((previous.txid, self.freed));
+        self.store.lock_snapshots().meta = meta;
+        Ok(())
+    }
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        match self {
+            Node::Leaf(entries) => entries.is_empty(),
+            Node::Internal { children, .. } => children.is_empty(),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        HEADER_LEN
+            + match self {
+                Node::Leaf(entries) => entries
+                    .iter()
+                    .map(|(key, value)| leaf_entry_len(key, value))
+                    .sum(),
+                Node::Internal { keys, .. } => {
+                    8 + keys.iter().map(|key| 2 + key.len() + 8).sum::<usize>()
+                }
+            }
+    }
+
+    /// Splits an overfull node in two halves of about the same size, returning
+    /// the first key of the right half.
+    fn split(self) -> (Node, String, Node) {
+        match self {
+            Node::Leaf(mut entries) => {
+                let sizes: Vec<_> = entries
+                    .iter()
+                    .map(|(key, value)| leaf_entry_len(key, value))
+                    .collect();
+                let right = entries.split_off(split_point(&sizes));
+                let separator = right[0].0.clone();
+                (Node::Leaf(entries), separator, Node::Leaf(right))
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let sizes: Vec<_> = keys.iter().map(|key| 2 + key.len() + 8).collect();
+                let at = split_point(&sizes).min(keys.len() - 1);
+                let right_keys = keys.split_off(at + 1);
+                let separator = keys.pop().expect("split point is a key");
+                let right_children = children.split_off(at + 1);
+                (
+                    Node::Internal { keys, children },
+                    separator,
+                    Node::Internal {
+                        keys: right_keys,
+                        children: right_children,
+                    },
+                )
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        match self {
+            Node::Leaf(entries) => {
+                buf.push(LEAF);
+                buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+                for (key, value) in entries {
+                    put_str(&mut buf, key);
+                    match value {
+                        Value::Inline(value) => {
+                            buf.push(0);
+                            put_str(&mut buf, value);
+                        }
+                        Value::Overflow { page, len } => {
+                            buf.push(1);
+                            buf.extend_from_slice(&page.to_le_bytes());
+                            buf.extend_from_slice(&len.to_le_bytes());
+                        }
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                buf.push(INTERNAL);
+                buf.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    put_str(&mut buf, key);
+                    buf.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> io::Result<Self> {
+        let kind = take(&mut buf, 1)?[0];
+        let count = u16::from_le_bytes(take(&mut buf, 2)?.try_into().expect("2 bytes"));
+        match kind {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let key = take_str(&mut buf)?;
+                    let value = match take(&mut buf, 1)?[0] {
+                        0 => Value::Inline(take_str(&mut buf)?),
+                        _ => Value::Overflow {
+                            page: take_u64(&mut buf)?,
+                            len: take_u64(&mut buf)?,
+                        },
+                    };
+                    entries.push((key, value));
+                }
+                Ok(Node::Leaf(entries))
+            }
+            INTERNAL => {
+                let mut keys = Vec::with_capacity(count as usize);
+                let mut children = vec![take_u64(&mut buf)?];
+                for _ in 0..count {
+                    keys.push(take_str(&mut buf)?);
+                    children.push(take_u64(&mut buf)?);
+                }
+                Ok(Node::Internal { keys, children })
+            }
+            _ => Err(invalid_data("unknown node kind")),
+        }
+    }
+}
+
+fn leaf_entry_len(key: &str, value: &Value) -> usize {
+    2 + key.len()
+        + match value {
+            Value::Inline(value) => 1 + 2 + value.len(),
+            Value::Overflow { .. } => 1 + 16,
+        }
+}
+
+/// Returns where to split entries of the given sizes so both halves are about
+/// the same size, leaving at least one entry on each side.
+fn split_point(sizes: &[usize]) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut total = 0;
+    let at = sizes
+        .iter()
+        .take_while(|&&size| {
+            total += size;
+            total <= half
+        })
+        .count();
+    at.max(1).min(sizes.len() - 1)
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(invalid_data("truncated page"));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take_u64(buf: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(
+        take(buf, 8)?.try_into().expect("8 bytes"),
+    ))
+}
+
+fn take_str(buf: &mut &[u8]) -> io::Result<String> {
+    let len = u16::from_le_bytes(take(buf, 2)?.try_into().expect("2 bytes"));
+    String::from_utf8(take(buf, len as usize)?.to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A bounded LRU cache of decoded node pages.
+struct PageCache {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<u64, (Arc<Node>, u64)>,
+    // last access tick -> page, the first entry is the least recently used.
+    lru: BTreeMap<u64, u64>,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, page: u64) -> Option<Arc<Node>> {
+        self.tick += 1;
+        let (node, tick) = self.pages.get_mut(&page)?;
+        self.lru.remove(tick);
+        *tick = self.tick;
+        self.lru.insert(self.tick, page);
+        Some(Arc::clone(node))
+    }
+
+    fn insert(&mut self, page: u64, node: Arc<Node>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, tick)) = self.pages.remove(&page) {
+            self.lru.remove(&tick);
+        }
+        while self.pages.len() >= self.capacity {
+            let (_, oldest) = self.lru.pop_first().expect("page cache lru out of sync");
+            self.pages.remove(&oldest);
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, page);
+        self.pages.insert(page, (node, self.tick));
+    }
+}
+
+impl KvsEngine for BTreeStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::KeyTooLarge(key.len()));
+        }
+        let mut tree = self.lock_tree();
+        self.set_locked(&mut tree, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let snapshot = self.snapshot();
+        self.lookup(snapshot.meta.root, &key)
+    }
+
+    /// Writes all the pairs in one transaction, so they commit together.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        if let Some((key, _)) = pairs.iter().find(|(key, _)| key.len() > MAX_KEY_LEN) {
+            return Err(KvsError::KeyTooLarge(key.len()));
+        }
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let mut tree = self.lock_tree();
+        self.set_many_locked(&mut tree, pairs)
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::KeyTooLarge(key.len()));
+        }
+        let mut tree = self.lock_tree();
+        let value = increment(self.lookup(tree.meta.root, &key)?.as_deref(), delta)?;
+        self.set_locked(&mut tree, key, value.to_string())?;
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut tree = self.lock_tree();
+        self.remove_locked(&mut tree, key)
+    }
+
+    fn swap(&self, key: String, value: String) -> Result<Option<String>> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::KeyTooLarge(key.len()));
+        }
+        let mut tree = self.lock_tree();
+        let old = self.lookup(tree.meta.root, &key)?;
+        self.set_locked(&mut tree, key, value)?;
+        Ok(old)
+    }
+
+    fn take(&self, key: String) -> Result<String> {
+        let mut tree = self.lock_tree();
+        let value = self
+            .lookup(tree.meta.root, &key)?
+            .ok_or(KvsError::KeyNotFound)?;
+        self.remove_locked(&mut tree, key)?;
+        Ok(value)
+    }
+
+    /// Commits are synced already, this only syncs the metadata of the file.
+    fn flush(&self) -> Result<()> {
+        let _tree = self.lock_tree();
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn scan_limit(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (start, end);
+        if empty_range(&range) || limit == 0 {
+            return Ok(Vec::new());
+        }
+        let snapshot = self.snapshot();
+        let mut entries = Vec::new();
+        self.scan_node(snapshot.meta.root, &range, limit, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn list_push(&self, _key: String, _end: ListEnd, _value: String) -> Result<u64> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn list_pop(&self, _key: String, _end: ListEnd) -> Result<Option<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn list_range(&self, _key: String, _start: i64, _stop: i64) -> Result<Vec<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn hash_set(&self, _key: String, _field: String, _value: String) -> Result<()> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn hash_get(&self, _key: String, _field: String) -> Result<Option<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn hash_remove(&self, _key: String, _field: String) -> Result<bool> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn set_add(&self, _key: String, _member: String) -> Result<bool> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn set_remove(&self, _key: String, _member: String) -> Result<bool> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn set_members(&self, _key: String) -> Result<Vec<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    fn create_namespace(&self, _name: String) -> Result<()> {
+        Err(KvsError::NamespacesUnsupported(EngineKind::BTree))
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn drop_namespace(&self, _name: String) -> Result<()> {
+        Err(KvsError::NamespacesUnsupported(EngineKind::BTree))
+    }
+
+    fn namespace(&self, _name: String) -> Result<Box<dyn KvsEngine + '_>> {
+        Err(KvsError::NamespacesUnsupported(EngineKind::BTree))
+    }
+}
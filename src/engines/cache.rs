@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A bounded LRU cache of decoded values, sized in bytes.
+///
+/// Every entry remembers the log position its value was read from, so a
+/// lookup only hits while the index still points at that same record.
+pub struct ValueCache {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    entries: HashMap<String, CacheEntry>,
+    // last access tick -> key, the first entry is the least recently used.
+    lru: BTreeMap<u64, String>,
+    hits: u64,
+    misses: u64,
+}
+
+struct CacheEntry {
+    gen: u64,
+    pos: u64,
+    value: String,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most `capacity` bytes of keys and values.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached value of `key` if it was read from the record at `gen`/`pos`.
+    pub fn get(&mut self, key: &str, gen: u64, pos: u64) -> Option<String> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.gen == gen && entry.pos == pos => {
+                self.lru.remove(&entry.tick);
+                entry.tick = self.tick;
+                self.lru.insert(self.tick, key.to_owned());
+                self.hits += 1;
+                Some(entry.value.clone())
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches the value of `key` read from the record at `gen`/`pos`.
+    pub fn insert(&mut self, key: String, gen: u64, pos: u64, value: String) {
+        self.invalidate(&key);
+        let size = entry_size(&key, &value);
+        if size > self.capacity {
+            return;
+        }
+        while self.size + size > self.capacity {
+            let (_, oldest) = self.lru.pop_first().expect("cache size out of sync");
+            let entry = self.entries.remove(&oldest).expect("cache lru out of sync");
+            self.size -= entry_size(&oldest, &entry.value);
+        }
+
+        self.tick += 1;
+        self.size += size;
+        self.lru.insert(self.tick, key.clone());
+        let tick = self.tick;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                gen,
+                pos,
+                value,
+                tick,
+            },
+        );
+    }
+
+    /// Drops the cached value of `key`, if any.
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry_size(key, &entry.value);
+        }
+    }
+
+    /// Points the cached value of `key` at the record's new position after a compaction.
+    pub fn relocate(&mut self, key: &str, (old_gen, old_pos): (u64, u64), gen: u64, pos: u64) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            if entry.gen == old_gen && entry.pos == old_pos {
+                entry.gen = gen;
+                entry.pos = pos;
+            }
+        }
+    }
+
+    /// Number of bytes of keys and values currently cached.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of lookups served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of lookups that had to read the log.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+fn entry_size(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
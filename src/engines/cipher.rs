@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path, sync::Arc};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::error::{KvsError, Result};
+
+/// Authenticated encryption of the records a store writes to disk.
+pub trait Cipher: Send + Sync {
+    /// Encrypts `plaintext` under a fresh nonce, returning the nonce followed
+    /// by the ciphertext and its authentication tag.
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Authenticates and decrypts the output of `seal`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Decryption` if the data was tampered with or
+    /// sealed under another key.
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// XChaCha20-Poly1305 with a random 192-bit nonce per record.
+pub struct ChaChaCipher(XChaCha20Poly1305);
+
+impl ChaChaCipher {
+    /// Length in bytes of a key.
+    pub const KEY_LEN: usize = 32;
+
+    const NONCE_LEN: usize = 24;
+
+    /// Creates a cipher from a 256-bit key.
+    pub fn new(key: &[u8; Self::KEY_LEN]) -> Self {
+        ChaChaCipher(XChaCha20Poly1305::new(key.into()))
+    }
+
+    /// Generates a random key.
+    pub fn generate_key() -> [u8; Self::KEY_LEN] {
+        XChaCha20Poly1305::generate_key(&mut OsRng).into()
+    }
+}
+
+impl Cipher for ChaChaCipher {
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| KvsError::Decryption)?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < Self::NONCE_LEN {
+            return Err(KvsError::Decryption);
+        }
+        let (nonce, ciphertext) = sealed.split_at(Self::NONCE_LEN);
+        self.0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| KvsError::Decryption)
+    }
+}
+
+/// The ciphers of a store by key id.
+///
+/// New records are sealed with the key of the highest id; the others are
+/// kept to read records written before the last rotation. An empty keyring
+/// leaves records in plaintext.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    ciphers: BTreeMap<u32, Arc<dyn Cipher>>,
+}
+
+impl Keyring {
+    /// Creates an empty keyring.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `cipher` under `id`, replacing any cipher with the same id.
+    pub fn add(&mut self, id: u32, cipher: impl Cipher + 'static) {
+        self.ciphers.insert(id, Arc::new(cipher));
+    }
+
+    /// Reads a key file of `<id> <key>` lines, the key being 64 hex digits.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn from_key_file(path: impl AsRef<Path>) -> Result<Self> {
+        let mut keyring = Self::new();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || KvsError::InvalidKeyFile(n + 1);
+            let (id, key) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let id = id.parse().map_err(|_| invalid())?;
+            let mut bytes = [0; ChaChaCipher::KEY_LEN];
+            hex::decode_to_slice(key.trim(), &mut bytes).map_err(|_| invalid())?;
+            keyring.add(id, ChaChaCipher::new(&bytes));
+        }
+        Ok(keyring)
+    }
+
+    /// Returns true if the keyring holds no key.
+    pub fn is_empty(&self) -> bool {
+        self.ciphers.is_empty()
+    }
+
+    /// Id of the key new records are sealed with.
+    pub fn current_id(&self) -> Option<u32> {
+        self.ciphers.keys().next_back().copied()
+    }
+
+    /// Seals `plaintext` with the current key, returning its id along with the
+    /// sealed bytes, or `None` if the keyring is empty.
+    pub(super) fn seal(&self, plaintext: &[u8]) -> Result<Option<(u32, Vec<u8>)>> {
+        match self.ciphers.iter().next_back() {
+            Some((&id, cipher)) => Ok(Some((id, cipher.seal(plaintext)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens bytes sealed with the key `id`.
+    pub(super) fn open(&self, id: u32, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.ciphers
+            .get(&id)
+            .ok_or(KvsError::MissingKey(id))?
+            .open(sealed)
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.ciphers.keys()).finish()
+    }
+}
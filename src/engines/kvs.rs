@@ -1,334 +1,2859 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        BTreeMap, BTreeSet, HashMap,
+    },
     fmt::format,
     fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    ops::Range,
+    ops::{Bound, Range, RangeBounds},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use clap::builder::OsStr;
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 use crate::error::{KvsError, Result};
 
-use super::KvsEngine;
+use super::{
+    cache::ValueCache,
+    cipher::Keyring,
+    empty_range, increment, list_range,
+    watch::{WatchHub, Watcher},
+    KvsEngine, ListEnd,
+};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+/// Name of the directory damaged log files are moved into by `KvStore::repair`.
+const LOST_AND_FOUND: &str = "lost+found";
+
+/// Name of the file holding the advisory lock of a writable store.
+const LOCK_FILE: &str = "LOCK";
+
+/// Extension of log files.
+const LOG_EXT: &str = "x";
+
+/// Extension of the files holding values stored apart from the log.
+const BLOB_EXT: &str = "blob";
+
 /// kv store
+///
+/// Every method takes `&self`. Reads use positional I/O on shared file handles,
+/// so any number of threads can `get` in parallel; writes are serialized.
 pub struct KvStore {
     path: PathBuf,
-    readers: HashMap<u64, BufReaderWithPos<File>>,
+    state: RwLock<IndexState>,
+    blob_threshold: u64,
+    keyring: Keyring,
+    // `None` when the store is opened read-only.
+    writer: Option<Mutex<LogWriter>>,
+    cache: Option<Mutex<ValueCache>>,
+    // Watchers of the default namespace.
+    watchers: Arc<WatchHub>,
+    // Holds the advisory lock on the directory until the store is dropped.
+    _lock: Option<File>,
+}
+
+/// The in-memory index together with the log files it points into.
+struct IndexState {
+    mode: IndexMode,
+    retention: Retention,
+    // Index of the default namespace.
+    index: KeyIndex,
+    // Indexes of the named namespaces. Their records share the log files with
+    // the default namespace.
+    namespaces: BTreeMap<String, Namespace>,
+    // Collections of the default namespace.
+    collections: BTreeMap<String, Collection>,
+    // Retained versions of the keys of the default namespace, oldest first.
+    // Empty with `Retention::Latest`.
+    history: BTreeMap<String, Vec<Revision>>,
+    // Log files by generation. A read clones the handle, so a file retired by
+    // a compaction stays readable until the last read of it finishes.
+    files: HashMap<u64, Arc<File>>,
+    // Blob files by number, retired like log files.
+    blob_files: HashMap<u64, Arc<File>>,
+    // Position after the last record loaded from each log file.
+    loaded: HashMap<u64, u64>,
+    uncompacted: u64,
+    // Opens sealed records while loading the log and verifying hashed keys.
+    keyring: Keyring,
+}
+
+/// A named keyspace of the store.
+struct Namespace {
+    index: KeyIndex,
+    collections: BTreeMap<String, Collection>,
+    history: BTreeMap<String, Vec<Revision>>,
+    // Dropped along with the namespace, which disconnects its watchers.
+    watchers: Arc<WatchHub>,
+    // Length of the record that created the namespace.
+    len: u64,
+}
+
+/// A retained version of a key.
+///
+/// The latest revision of a key that still exists is the one in the index.
+#[derive(Clone, Copy)]
+struct Revision {
+    cmd_pos: CommandPos,
+    // Commit timestamp of the record, in milliseconds since the Unix epoch.
+    ts: u64,
+    // Written by `Rm`.
+    removed: bool,
+}
+
+/// The elements of a collection, by the position of the record holding them.
+///
+/// Collections are kept in memory whatever the index mode, and removed
+/// along with their last element.
+enum Collection {
+    List(BTreeMap<i64, CommandPos>),
+    Hash(BTreeMap<String, CommandPos>),
+    Set(BTreeMap<String, CommandPos>),
+}
+
+/// Identifies an element of a collection.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Element {
+    List(i64),
+    Hash(String),
+    Set(String),
+}
+
+/// Maps keys to the position of their latest `Set` command.
+enum KeyIndex {
+    Ordered(BTreeMap<String, CommandPos>),
+    Hashed {
+        by_hash: HashMap<u64, CommandPos>,
+        // full keys whose hash is already taken by another key in `by_hash`.
+        collided: BTreeMap<String, CommandPos>,
+    },
+}
+
+/// How a `KvStore` keeps its in-memory index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Every key is kept in memory in an ordered map.
+    #[default]
+    Ordered,
+    /// Only a 64-bit hash of each key is kept in memory.
+    ///
+    /// Lookups verify the key against the log record they land on, and
+    /// ordered scans read every live record. Meant for keyspaces too large to
+    /// keep in memory.
+    Hashed,
+}
+
+/// Which past versions of its keys a `KvStore` keeps.
+///
+/// Retained versions survive compactions, and can be read with
+/// `KvStore::history` and `KvStore::get_at`. Collections are not versioned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Retention {
+    /// Only the current value of each key is kept.
+    #[default]
+    Latest,
+    /// The given number of latest versions of each key are kept, counting the
+    /// current value and removals.
+    Versions(usize),
+    /// Every version that was current during the given time window is kept.
+    Window(Duration),
+}
+
+/// The writer of the current generation.
+struct LogWriter {
     writer: BufWriterWithPos<File>,
-    index: BTreeMap<String, CommandPos>,
     current_gen: u64,
-    uncompacted: u64,
+    // Opened on the first value above the blob threshold.
+    blob: Option<BlobWriter>,
+    keyring: Keyring,
+}
+
+impl LogWriter {
+    /// Appends `cmd` to the current generation, returning where it was written.
+    fn append(&mut self, cmd: &Command) -> Result<CommandPos> {
+        let pos = self.writer.pos;
+        self.writer.write_all(&seal_cmd(&self.keyring, cmd)?)?;
+        self.writer.flush()?;
+        Ok((self.current_gen, pos..self.writer.pos).into())
+    }
+
+    /// Appends `cmds` as a batch, which loads whole or not at all, returning
+    /// where each was written.
+    fn append_batch(&mut self, cmds: &[Command]) -> Result<Vec<CommandPos>> {
+        let len = cmds.len() as u64;
+        let mut batch = seal_cmd(&self.keyring, &Command::Batch { len })?;
+        let mut positions = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let pos = self.writer.pos + batch.len() as u64;
+            batch.extend(seal_cmd(&self.keyring, cmd)?);
+            let end = self.writer.pos + batch.len() as u64;
+            positions.push((self.current_gen, pos..end).into());
+        }
+        self.writer.write_all(&batch)?;
+        self.writer.flush()?;
+        Ok(positions)
+    }
+}
+
+/// The writer of the blob file new large values are appended to.
+struct BlobWriter {
+    writer: BufWriterWithPos<File>,
+    file: u64,
+}
+
+/// Options for opening a `KvStore`.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    /// Size in bytes of the cache of decoded values kept in front of `get`.
+    ///
+    /// Keys and values count towards the size. `0` disables the cache.
+    pub cache_capacity: u64,
+    /// How the in-memory index is kept.
+    pub index_mode: IndexMode,
+    /// Values longer than this many bytes are stored in separate blob files,
+    /// and only referenced from the log. `0` keeps every value in the log.
+    pub blob_threshold: u64,
+    /// Keys records and blobs are encrypted with. Empty keeps them in plaintext.
+    ///
+    /// A compaction re-encrypts everything with the current key, after which
+    /// older keys can be dropped.
+    pub keyring: Keyring,
+    /// Past versions of the keys to keep.
+    ///
+    /// Opening a store with a longer retention than before brings back the
+    /// versions that were not compacted away yet.
+    pub retention: Retention,
+}
+
+/// A version of a key, as returned by `KvStore::history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// When the version was written. Records written before timestamps were
+    /// recorded date from the Unix epoch.
+    pub timestamp: SystemTime,
+    /// The value of the key, `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// Runtime statistics of a `KvStore`.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreStats {
+    /// Number of `get` calls served by the value cache.
+    pub cache_hits: u64,
+    /// Number of `get` calls of existing keys that had to read the log.
+    pub cache_misses: u64,
+    /// Bytes of keys and values held by the value cache.
+    pub cached_bytes: u64,
+    /// Bytes of stale records a compaction would reclaim.
+    pub uncompacted: u64,
 }
 
 /// A k-v store based on memory
 impl KvStore {
     /// create memory kv store
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another `KvStore` holds the directory.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, &KvStoreOptions::default())
+    }
+
+    /// Opens a store like `open`, configured by `options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<Self> {
         let path: PathBuf = path.into();
         fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
 
-        let mut index = BTreeMap::new();
-        let mut readers = HashMap::new();
-        let mut uncompacted = 0u64;
-
-        let gens = sorted_gen_list(&path)?;
-        let current_gen = gens.last().unwrap_or(&0) + 1;
-        let writer = BufWriterWithPos::new(log_file(&path, current_gen, true)?)?;
-        let reader = BufReaderWithPos::new(log_file(&path, current_gen, false)?)?;
-        readers.insert(current_gen, reader);
-
-        for gen in gens {
-            let mut reader = BufReaderWithPos::new(log_file(&path, gen, false)?)?;
-            uncompacted += load_cmd(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
-        }
-
-        Ok(Self {
-            path,
-            current_gen,
+        let mut store = Self::load(path, options)?;
+        let state = store.state.get_mut().expect("index lock poisoned");
+        let current_gen = state.files.keys().max().unwrap_or(&0) + 1;
+        let writer = BufWriterWithPos::new(log_file(&store.path, current_gen, true)?)?;
+        let file = log_file(&store.path, current_gen, false)?;
+        state.files.insert(current_gen, Arc::new(file));
+        store.writer = Some(Mutex::new(LogWriter {
             writer,
-            readers,
-            index,
-            uncompacted,
-        })
+            current_gen,
+            blob: None,
+            keyring: options.keyring.clone(),
+        }));
+        store._lock = Some(lock);
+        Ok(store)
     }
 
-    /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file.
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = BufWriterWithPos::new(log_file(&self.path, self.current_gen, true)?)?;
+    /// Opens an existing store without taking the directory lock.
+    ///
+    /// The store can be opened while another process writes to it, and no
+    /// file or directory is ever created or modified.
+    /// `set`, `remove` and `compact` fail with `KvsError::ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::load(path.into(), &KvStoreOptions::default())
+    }
 
-        let mut compaction_writer =
-            BufWriterWithPos::new(log_file(&self.path, compaction_gen, true)?)?;
+    /// Opens a store like `open_read_only`, configured by `options`.
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<Self> {
+        Self::load(path.into(), options)
+    }
 
-        let mut new_pos = 0; // pos in the new log file.
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            }
-
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+    /// Returns the runtime statistics of the store.
+    pub fn stats(&self) -> KvStoreStats {
+        let cache = self
+            .cache
+            .as_ref()
+            .map(|cache| cache.lock().expect("cache lock poisoned"));
+        KvStoreStats {
+            cache_hits: cache.as_ref().map_or(0, |cache| cache.hits()),
+            cache_misses: cache.as_ref().map_or(0, |cache| cache.misses()),
+            cached_bytes: cache.as_ref().map_or(0, |cache| cache.size()),
+            uncompacted: self.read_state().uncompacted,
         }
-        compaction_writer.flush()?;
+    }
 
-        // remove stale log files.
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(self.path.join(format!("{stale_gen}.x")))?;
+    /// Picks up records and generations written since the store was opened.
+    ///
+    /// Only useful for stores opened with `open_read_only`; a writable store
+    /// is always up to date.
+    pub fn refresh(&self) -> Result<()> {
+        if self.writer.is_some() {
+            return Ok(());
         }
-        self.uncompacted = 0;
-
-        Ok(())
+        self.write_state().refresh(&self.path)
     }
-}
 
-fn log_file(dir: &Path, gen: u64, write: bool) -> io::Result<File> {
-    let file = dir.join(format!("{gen}.x"));
-    if write {
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(file)
-    } else {
-        OpenOptions::new().read(true).open(file)
-    }
-}
-/// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut list: Vec<_> = fs::read_dir(path)?
-        .filter_map(|res| {
-            if let Ok(dir) = res {
-                let path = dir.path();
-                if path.is_file() && path.extension() == Some("x".as_ref()) {
-                    Some(path)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        })
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(|name| name.to_str())
-                .map(|s| s.trim_end_matches(".x"))
-                .map(str::parse::<u64>)
+    /// Builds the index from every log file in `path` without opening a writer.
+    fn load(path: PathBuf, options: &KvStoreOptions) -> Result<Self> {
+        let mut state = IndexState::new(
+            options.index_mode,
+            options.retention,
+            options.keyring.clone(),
+        );
+        state.refresh(&path)?;
+        Ok(Self {
+            path,
+            state: RwLock::new(state),
+            blob_threshold: options.blob_threshold,
+            keyring: options.keyring.clone(),
+            writer: None,
+            cache: (options.cache_capacity > 0)
+                .then(|| Mutex::new(ValueCache::new(options.cache_capacity))),
+            watchers: Arc::default(),
+            _lock: None,
         })
-        .flatten()
-        .collect();
-    list.sort_unstable();
-    Ok(list)
-}
+    }
 
-struct BufReaderWithPos<R: Read + Seek> {
-    reader: BufReader<R>,
-    pos: u64,
-}
+    fn read_state(&self) -> RwLockReadGuard<'_, IndexState> {
+        self.state.read().expect("index lock poisoned")
+    }
 
-impl<R: Read + Seek> BufReaderWithPos<R> {
-    pub fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.stream_position()?;
-        Ok(Self {
-            reader: BufReader::new(inner),
-            pos,
-        })
+    fn write_state(&self) -> RwLockWriteGuard<'_, IndexState> {
+        self.state.write().expect("index lock poisoned")
     }
-}
 
-impl<R: Read + Seek> Read for BufReaderWithPos<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let size = self.reader.read(buf)?;
-        self.pos += size as u64;
-        Ok(size)
+    fn lock_writer(&self) -> Result<MutexGuard<'_, LogWriter>> {
+        let writer = self.writer.as_ref().ok_or(KvsError::ReadOnly)?;
+        Ok(writer.lock().expect("writer lock poisoned"))
     }
-}
 
-impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.reader.seek(pos)?;
-        Ok(self.pos)
+    fn invalidate_cache(&self, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.lock().expect("cache lock poisoned").invalidate(key);
+        }
     }
-}
 
-struct BufWriterWithPos<R: Write + Seek> {
-    writer: BufWriter<R>,
-    pos: u64,
-}
+    /// Returns the watchers of namespace `ns`, `None` being the default one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if there is no such namespace.
+    fn watch_hub(&self, ns: Option<&str>) -> Result<Arc<WatchHub>> {
+        match ns {
+            None => Ok(Arc::clone(&self.watchers)),
+            Some(ns) => Ok(Arc::clone(&self.read_state().namespace(ns)?.watchers)),
+        }
+    }
 
-impl<R: Write + Seek> BufWriterWithPos<R> {
-    pub fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.stream_position()?;
-        Ok(Self {
-            writer: BufWriter::new(inner),
+    /// Appends `value` to the current blob file, opening one if needed.
+    fn write_blob(&self, log: &mut LogWriter, value: &[u8]) -> Result<BlobPos> {
+        if log.blob.is_none() {
+            let file = self.read_state().blob_files.keys().max().unwrap_or(&0) + 1;
+            let writer = BufWriterWithPos::new(blob_file(&self.path, file, true)?)?;
+            let reader = blob_file(&self.path, file, false)?;
+            self.write_state().blob_files.insert(file, Arc::new(reader));
+            log.blob = Some(BlobWriter { writer, file });
+        }
+        let (cipher, data) = match self.keyring.seal(value)? {
+            Some((cipher, sealed)) => (Some(cipher), sealed),
+            None => (None, value.to_vec()),
+        };
+        let blob = log.blob.as_mut().expect("blob writer was just opened");
+        let pos = blob.writer.pos;
+        blob.writer.write_all(&data)?;
+        blob.writer.flush()?;
+        Ok(BlobPos {
+            file: blob.file,
             pos,
+            len: data.len() as u64,
+            cipher,
         })
     }
-}
 
-impl<R: Write + Seek> Write for BufWriterWithPos<R> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let size = self.writer.write(buf)?;
-        self.pos += size as u64;
-        Ok(size)
+    /// Returns the key and value of a `Set` or `SetBlob` command.
+    ///
+    /// Returns `None` if the blob file was retired since the command was read.
+    fn resolve(&self, cmd: Command) -> Result<Option<(String, String)>> {
+        match cmd {
+            Command::Set { key, value, .. } => Ok(Some((key, value))),
+            Command::SetBlob { key, blob, .. } => {
+                let file = self.read_state().blob_files.get(&blob.file).cloned();
+                match file {
+                    Some(file) => Ok(Some((key, read_blob(&file, &blob, &self.keyring)?))),
+                    None => Ok(None),
+                }
+            }
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+    /// Returns the key-value pairs whose keys fall in `range`, in key order.
+    ///
+    /// With `IndexMode::Hashed` every live record is read to find the keys.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.scan_in(None, range, usize::MAX)
     }
-}
 
-impl<R: Write + Seek> Seek for BufWriterWithPos<R> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
-        Ok(self.pos)
+    /// Scans namespace `ns` like `scan`, for the first `limit` pairs.
+    ///
+    /// With `IndexMode::Hashed` every live record is still read, but only
+    /// the blobs of the pairs returned.
+    fn scan_in<R: RangeBounds<String>>(
+        &self,
+        ns: Option<&str>,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        if empty_range(&range) || limit == 0 {
+            return Ok(Vec::new());
+        }
+        // collect the positions first, so the records are read without holding the index.
+        let (positions, files, blob_files, hashed) = {
+            let state = self.read_state();
+            let index = state.keys(ns)?;
+            let positions: Vec<_> = match index {
+                KeyIndex::Ordered(index) => index
+                    .range::<String, _>((range.start_bound(), range.end_bound()))
+                    .map(|(_, cmd_pos)| *cmd_pos)
+                    .take(limit)
+                    .collect(),
+                KeyIndex::Hashed { .. } => index.positions().copied().collect(),
+            };
+            (
+                positions,
+                state.files.clone(),
+                state.blob_files.clone(),
+                index.is_hashed(),
+            )
+        };
+
+        let mut cmds = Vec::new();
+        for cmd_pos in positions {
+            let file = files.get(&cmd_pos.gen).expect("Cannot find log file");
+            match read_cmd(&self.keyring, &read_record(file, &cmd_pos)?)? {
+                Command::Set { key, .. } | Command::SetBlob { key, .. }
+                    if hashed && !range.contains(&key) => {}
+                cmd @ (Command::Set { .. } | Command::SetBlob { .. }) => cmds.push(cmd),
+                _ => return Err(KvsError::UnexpectedCommandType),
+            }
+        }
+        if hashed {
+            cmds.sort_unstable_by(|a, b| a.key().cmp(&b.key()));
+            cmds.truncate(limit);
+        }
+        cmds.into_iter()
+            .map(|cmd| match cmd {
+                Command::SetBlob { key, blob, .. } => {
+                    let file = blob_files.get(&blob.file).expect("Cannot find blob file");
+                    Ok((key, read_blob(file, &blob, &self.keyring)?))
+                }
+                Command::Set { key, value, .. } => Ok((key, value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            })
+            .collect()
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
-}
+    /// Returns the retained versions of `key`, oldest first.
+    ///
+    /// With `Retention::Latest`, only the current value is returned.
+    pub fn history(&self, key: String) -> Result<Vec<Version>> {
+        self.versions_in(None, &key, None)
+    }
 
-impl Command {
-    fn set(key: String, value: String) -> Self {
-        Command::Set { key, value }
+    /// Gets the value `key` had at time `at`.
+    ///
+    /// Returns `None` if the key had no value then, or if that version was not
+    /// retained.
+    pub fn get_at(&self, key: String, at: SystemTime) -> Result<Option<String>> {
+        let version = self.versions_in(None, &key, Some(timestamp(at)))?.pop();
+        Ok(version.and_then(|version| version.value))
     }
 
-    fn rm(key: String) -> Self {
-        Command::Rm { key }
+    /// Clears stale entries in the log.
+    pub fn compact(&self) -> Result<()> {
+        let mut log = self.lock_writer()?;
+        self.compact_locked(&mut log)
     }
-}
 
-/// Represents the position and length of a json-serialized command in the log.
-struct CommandPos {
-    gen: u64,
-    pos: u64,
-    len: u64,
-}
+    /// Compacts the log while the caller holds the writer.
+    ///
+    /// Reads keep going during the compaction. Retired files are only unlinked,
+    /// reads that already hold their handles finish normally. Past versions are
+    /// kept as long as the retention asks for, older ones first so that they
+    /// load in order.
+    fn compact_locked(&self, log: &mut LogWriter) -> Result<()> {
+        // increase current gen by 2. current_gen + 1 is for the compaction file.
+        let compaction_gen = log.current_gen + 1;
+        let new_gen = log.current_gen + 2;
+        let writer = BufWriterWithPos::new(log_file(&self.path, new_gen, true)?)?;
+        let mut compaction_writer =
+            BufWriterWithPos::new(log_file(&self.path, compaction_gen, true)?)?;
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
-        Self {
-            gen,
-            pos: range.start,
-            len: range.end - range.start,
-        }
-    }
-}
+        // holding the writer means the index can't change until we are done.
+        self.write_state()
+            .prune_history(timestamp(SystemTime::now()));
+        let mut relocated = HashMap::new();
+        let mut blobs = Vec::new();
+        let mut pinned = BTreeSet::new();
+        let mut created = Vec::new();
+        // the state isn't held while copying, as moving a blob value may
+        // open a blob file.
+        let (namespaces, files, positions) = {
+            let state = self.read_state();
+            let past = state.past_positions().map(|cmd_pos| (*cmd_pos, true));
+            let current = state.positions().map(|cmd_pos| (*cmd_pos, false));
+            let namespaces: Vec<_> = state.namespaces.keys().cloned().collect();
+            (
+                namespaces,
+                state.files.clone(),
+                past.chain(current).collect::<Vec<_>>(),
+            )
+        };
+        let mut new_pos = 0; // pos in the new log file.
 
-fn load_cmd(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file.
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction.
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+        // namespaces are created ahead of the records of their keys.
+        for ns in namespaces {
+            let record = seal_cmd(&self.keyring, &Command::CreateNs { ns })?;
+            compaction_writer.write_all(&record)?;
+            created.push(record.len() as u64);
+            new_pos += record.len() as u64;
+        }
+        for (cmd_pos, past) in positions {
+            let file = files.get(&cmd_pos.gen).expect("Cannot find log file");
+            // records not sealed with the current key are re-encrypted.
+            let mut record = reseal(&self.keyring, read_record(file, &cmd_pos)?)?;
+            let mut cmd = read_cmd(&self.keyring, &record)?;
+            // so are blob values, past versions included, by moving them to
+            // the current blob file.
+            let mut moved = false;
+            if let Command::SetBlob { blob, .. } = &mut cmd {
+                if blob.cipher != self.keyring.current_id() {
+                    let file = self.read_state().blob_files.get(&blob.file).cloned();
+                    let file = file.ok_or(KvsError::MissingBlob(blob.file))?;
+                    let value = read_blob(&file, blob, &self.keyring)?;
+                    *blob = self.write_blob(log, value.as_bytes())?;
+                    moved = true;
                 }
             }
-            Command::Rm { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+            if moved {
+                record = seal_cmd(&self.keyring, &cmd)?;
+            }
+            compaction_writer.write_all(&record)?;
+            let len = record.len() as u64;
+            if let (Some(cache), Some(key)) = (&self.cache, cmd.key()) {
+                cache.lock().expect("cache lock poisoned").relocate(
+                    key,
+                    (cmd_pos.gen, cmd_pos.pos),
+                    compaction_gen,
+                    new_pos,
+                );
+            }
+            match cmd {
+                Command::SetBlob { blob, .. } if past => {
+                    pinned.insert(blob.file);
                 }
-                // the "remove" command itself can be deleted in the next compaction.
-                // so we add its length to `uncompacted`.
-                uncompacted += new_pos - pos;
+                Command::SetBlob { key, blob, ns, ts } => {
+                    blobs.push((ns, key, blob, ts));
+                }
+                _ => {}
             }
+            relocated.insert(
+                (cmd_pos.gen, cmd_pos.pos),
+                CommandPos::from((compaction_gen, new_pos..new_pos + len)),
+            );
+            new_pos += len;
         }
-        pos = new_pos;
-    }
-    Ok(uncompacted)
-}
+        compaction_writer.flush()?;
 
-impl KvsEngine for KvStore {
-    /// set k-v to memory
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+        let stale_gens: Vec<_> = {
+            let mut state = self.write_state();
+            for cmd_pos in state.positions_mut() {
+                *cmd_pos = relocated[&(cmd_pos.gen, cmd_pos.pos)];
+            }
+            for revision in state.revisions_mut() {
+                let cmd_pos = &mut revision.cmd_pos;
+                *cmd_pos = relocated[&(cmd_pos.gen, cmd_pos.pos)];
+            }
+            for (namespace, len) in state.namespaces.values_mut().zip(created) {
+                namespace.len = len;
+            }
+            let file = log_file(&self.path, compaction_gen, false)?;
+            state.files.insert(compaction_gen, Arc::new(file));
+            let file = log_file(&self.path, new_gen, false)?;
+            state.files.insert(new_gen, Arc::new(file));
+            state.uncompacted = 0;
 
-        if let Command::Set { key, value } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
+            let stale_gens: Vec<_> = state
+                .files
+                .keys()
+                .filter(|&&gen| gen < compaction_gen)
+                .cloned()
+                .collect();
+            for stale_gen in &stale_gens {
+                state.files.remove(stale_gen);
             }
-        }
+            stale_gens
+        };
+        log.writer = writer;
+        log.current_gen = new_gen;
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        // remove stale log files.
+        for stale_gen in stale_gens {
+            fs::remove_file(self.path.join(format!("{stale_gen}.{LOG_EXT}")))?;
         }
-        Ok(())
+
+        self.collect_blobs(log, blobs, pinned)
     }
 
+    /// Garbage-collects blob files, given every live blob reference.
     ///
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Some(reader) = self.readers.get_mut(&cmd_pos.gen) {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-                let taker = reader.take(cmd_pos.len);
-                if let Command::Set { value, .. } = serde_json::from_reader(taker)? {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
+    /// Files without live values are deleted. Files with less than half of
+    /// their bytes live have those values moved to the current blob file, and
+    /// the keys re-pointed by new log records, before they are deleted too.
+    /// Files holding past versions, listed in `pinned`, are left alone: the
+    /// compaction already moved their values not sealed by the current key.
+    fn collect_blobs(
+        &self,
+        log: &mut LogWriter,
+        blobs: Vec<(Option<String>, String, BlobPos, u64)>,
+        pinned: BTreeSet<u64>,
+    ) -> Result<()> {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for (_, _, blob, _) in &blobs {
+            *live.entry(blob.file).or_default() += blob.len;
+        }
+        let mut retired = Vec::new();
+        for (&file, handle) in &self.read_state().blob_files {
+            if pinned.contains(&file) {
+                continue;
+            }
+            let size = handle.metadata()?.len();
+            if live.get(&file).copied().unwrap_or(0) * 2 < size {
+                retired.push(file);
+            }
+        }
+        if retired.is_empty() {
+            return Ok(());
+        }
+        if log
+            .blob
+            .as_ref()
+            .is_some_and(|blob| retired.contains(&blob.file))
+        {
+            log.blob = None;
+        }
+
+        for (ns, key, blob, ts) in blobs {
+            if !retired.contains(&blob.file) {
+                continue;
+            }
+            let file = self.read_state().blob_files[&blob.file].clone();
+            let value = read_blob(&file, &blob, &self.keyring)?;
+            let cmd = Command::SetBlob {
+                key,
+                blob: self.write_blob(log, value.as_bytes())?,
+                ns: ns.clone(),
+                ts,
+            };
+            let cmd_pos = log.append(&cmd)?;
+            let key = cmd.into_key().ok_or(KvsError::UnexpectedCommandType)?;
+            let mut state = self.write_state();
+            if let Some(old_cmd) = state.insert(ns.as_deref(), key.clone(), cmd_pos)? {
+                state.uncompacted += old_cmd.len;
+                // the moved value is still the same version.
+                let history = state.history_mut(ns.as_deref())?;
+                if let Some(current) = history.get_mut(&key).and_then(|r| r.last_mut()) {
+                    current.cmd_pos = cmd_pos;
+                }
+                if let Some(cache) = &self.cache {
+                    let mut cache = cache.lock().expect("cache lock poisoned");
+                    cache.relocate(&key, (old_cmd.gen, old_cmd.pos), cmd_pos.gen, cmd_pos.pos);
                 }
-            } else {
-                Err(KvsError::KeyNotFound)
             }
-        } else {
-            Ok(None)
         }
+
+        let mut state = self.write_state();
+        for file in &retired {
+            state.blob_files.remove(file);
+        }
+        drop(state);
+        for file in retired {
+            fs::remove_file(self.path.join(format!("{file}.{BLOB_EXT}")))?;
+        }
+        Ok(())
     }
 
+    /// Salvages a damaged store in the given directory.
     ///
-    fn remove(&mut self, key: String) -> Result<()> {
-        let cmd = Command::rm(key);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    /// Every log file is scanned record by record. When a record cannot be
+    /// decoded, the scan resynchronizes on the next valid record and the skipped
+    /// bytes are reported. All recoverable entries are then written to a fresh,
+    /// compacted generation, and damaged or unreadable files are moved into the
+    /// `lost+found` subdirectory instead of being deleted, along with the blob
+    /// files holding values that weren't recovered or that a damaged region may
+    /// refer to.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<RepairReport> {
+        Self::repair_with_options(path, &KvStoreOptions::default())
+    }
 
-        if let Command::Rm { key } = cmd {
-            if let Some(value) = self.index.remove(&key) {
-                self.uncompacted += value.len;
-                Ok(())
-            } else {
-                Err(KvsError::KeyNotFound)
-            }
+    /// Salvages a store like `repair`, opening and writing records with the
+    /// keyring of `options`.
+    ///
+    /// Records sealed with a missing key, or failing to decrypt, are skipped
+    /// like damaged ones.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MissingKey` without changing anything if the
+    /// keyring is empty and the store holds encrypted records.
+    pub fn repair_with_options(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<RepairReport> {
+        let path: PathBuf = path.into();
+        let keyring = &options.keyring;
+        let _lock = lock_dir(&path)?;
+        let mut report = RepairReport::default();
+        let mut entries = BTreeMap::from([(None, BTreeMap::new())]);
+
+        let gens = sorted_gen_list(&path, LOG_EXT)?;
+        let mut damaged = Vec::new();
+        // whether every reference read to each blob file was recovered.
+        let mut blob_refs = HashMap::new();
+        for &gen in &gens {
+            let buf = match fs::read(path.join(format!("{gen}.{LOG_EXT}"))) {
+                Ok(buf) => buf,
+                Err(_) => {
+                    damaged.push(gen);
+                    continue;
+                }
+            };
+            let regions = salvage_cmd(
+                &path,
+                keyring,
+                &buf,
+                &mut entries,
+                &mut blob_refs,
+                &mut report.suspect_keys,
+            )?;
+            if !regions.is_empty() {
+                damaged.push(gen);
+                report
+                    .skipped
+                    .extend(regions.into_iter().map(|range| (gen, range)));
+            }
+        }
+
+        // write everything recovered into a fresh generation before touching the old files.
+        let repair_gen = gens.last().unwrap_or(&0) + 1;
+        let mut writer = BufWriterWithPos::new(log_file(&path, repair_gen, true)?)?;
+        for (ns, keys) in entries {
+            if let Some(ns) = &ns {
+                writer.write_all(&seal_cmd(keyring, &Command::CreateNs { ns: ns.clone() })?)?;
+            }
+            for (key, salvaged) in keys {
+                match salvaged {
+                    Salvaged::Value(value, ts) => {
+                        let cmd = Command::set(ns.clone(), key, value, ts);
+                        writer.write_all(&seal_cmd(keyring, &cmd)?)?;
+                    }
+                    Salvaged::Elements(elements) => {
+                        for cmd in elements.into_values() {
+                            writer.write_all(&seal_cmd(keyring, &cmd)?)?;
+                        }
+                    }
+                }
+                report.recovered_keys += 1;
+            }
+        }
+        writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
+
+        // a damaged region may refer to any blob, which then keeps the only
+        // copy of a value.
+        let blob_files = sorted_gen_list(&path, BLOB_EXT)?;
+        let kept_blobs: Vec<_> = blob_files
+            .into_iter()
+            .map(|file| {
+                let recovered = damaged.is_empty() && blob_refs.get(&file).copied().unwrap_or(true);
+                (format!("{file}.{BLOB_EXT}"), !recovered)
+            })
+            .collect();
+        if !damaged.is_empty() || kept_blobs.iter().any(|&(_, kept)| kept) {
+            fs::create_dir_all(path.join(LOST_AND_FOUND))?;
+        }
+        let logs = gens
+            .into_iter()
+            .map(|gen| (format!("{gen}.{LOG_EXT}"), damaged.contains(&gen)));
+        for (name, quarantine) in logs.chain(kept_blobs) {
+            let file = path.join(&name);
+            if quarantine {
+                let target = lost_and_found_path(&path, &name);
+                fs::rename(&file, &target)?;
+                report.quarantined.push(target);
+            } else {
+                fs::remove_file(file)?;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Outcome of `KvStore::repair`.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Number of live keys written to the repaired generation, counting every
+    /// namespace.
+    pub recovered_keys: usize,
+    /// Byte ranges that could not be decoded, by generation.
+    pub skipped: Vec<(u64, Range<u64>)>,
+    /// Keys mentioned in skipped regions. Their latest value or removal may have been lost.
+    pub suspect_keys: BTreeSet<String>,
+    /// Damaged or unreadable log files, and blob files that may hold values
+    /// not recovered, moved into `lost+found`.
+    pub quarantined: Vec<PathBuf>,
+}
+
+impl IndexState {
+    fn new(mode: IndexMode, retention: Retention, keyring: Keyring) -> Self {
+        Self {
+            mode,
+            retention,
+            index: KeyIndex::new(mode),
+            namespaces: BTreeMap::new(),
+            collections: BTreeMap::new(),
+            history: BTreeMap::new(),
+            files: HashMap::new(),
+            blob_files: HashMap::new(),
+            loaded: HashMap::new(),
+            uncompacted: 0,
+            keyring,
+        }
+    }
+
+    /// Loads whatever was appended to the log files in `path` since the last call.
+    fn refresh(&mut self, path: &Path) -> Result<()> {
+        let gens = sorted_gen_list(path, LOG_EXT)?;
+        if self.files.keys().any(|gen| !gens.contains(gen)) {
+            // the writer compacted the files we have loaded away, start over.
+            *self = IndexState::new(self.mode, self.retention, self.keyring.clone());
+        }
+
+        for gen in gens {
+            if let Entry::Vacant(entry) = self.files.entry(gen) {
+                entry.insert(Arc::new(log_file(path, gen, false)?));
+            }
+            let file = self.files[&gen].try_clone()?;
+            let mut reader = BufReaderWithPos::new(file)?;
+            let start = self.loaded.get(&gen).copied().unwrap_or(0);
+            let (uncompacted, end) = load_cmd(gen, &mut reader, self, start)?;
+            self.uncompacted += uncompacted;
+            self.loaded.insert(gen, end);
+        }
+
+        // blobs are written before the records pointing at them, so listing
+        // them last finds every blob the loaded records refer to.
+        let blob_files = sorted_gen_list(path, BLOB_EXT)?;
+        self.blob_files.retain(|file, _| blob_files.contains(file));
+        for file in blob_files {
+            if let Entry::Vacant(entry) = self.blob_files.entry(file) {
+                entry.insert(Arc::new(blob_file(path, file, false)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the namespace `ns`.
+    fn namespace(&self, ns: &str) -> Result<&Namespace> {
+        self.namespaces
+            .get(ns)
+            .ok_or_else(|| KvsError::NamespaceNotFound(ns.to_owned()))
+    }
+
+    /// Returns the index of namespace `ns`, `None` being the default one.
+    fn keys(&self, ns: Option<&str>) -> Result<&KeyIndex> {
+        match ns {
+            None => Ok(&self.index),
+            Some(ns) => Ok(&self.namespace(ns)?.index),
+        }
+    }
+
+    /// Adds the empty namespace `ns`, created by a record of `len` bytes.
+    fn create_namespace(&mut self, ns: String, len: u64) {
+        let index = KeyIndex::new(self.mode);
+        self.namespaces.entry(ns).or_insert_with(|| Namespace {
+            index,
+            collections: BTreeMap::new(),
+            history: BTreeMap::new(),
+            watchers: Arc::default(),
+            len,
+        });
+    }
+
+    /// Removes namespace `ns` with its keys, returning the bytes of the
+    /// records that became stale.
+    fn drop_namespace(&mut self, ns: &str) -> Option<u64> {
+        let namespace = self.namespaces.remove(ns)?;
+        let live: u64 = namespace
+            .index
+            .positions()
+            .chain(
+                namespace
+                    .collections
+                    .values()
+                    .flat_map(Collection::positions),
+            )
+            .chain(past_positions(&namespace.history))
+            .map(|cmd_pos| cmd_pos.len)
+            .sum();
+        Some(namespace.len + live)
+    }
+
+    /// Iterates the positions of all live records, in every namespace.
+    ///
+    /// The order is the same as `positions_mut` as long as the index is not modified.
+    fn positions(&self) -> impl Iterator<Item = &CommandPos> {
+        let namespaces = self.namespaces.values().flat_map(|namespace| {
+            namespace.index.positions().chain(
+                namespace
+                    .collections
+                    .values()
+                    .flat_map(Collection::positions),
+            )
+        });
+        self.index
+            .positions()
+            .chain(self.collections.values().flat_map(Collection::positions))
+            .chain(namespaces)
+    }
+
+    fn positions_mut(&mut self) -> impl Iterator<Item = &mut CommandPos> {
+        let namespaces = self.namespaces.values_mut().flat_map(|namespace| {
+            namespace.index.positions_mut().chain(
+                namespace
+                    .collections
+                    .values_mut()
+                    .flat_map(Collection::positions_mut),
+            )
+        });
+        self.index
+            .positions_mut()
+            .chain(
+                self.collections
+                    .values_mut()
+                    .flat_map(Collection::positions_mut),
+            )
+            .chain(namespaces)
+    }
+
+    fn collections(&self, ns: Option<&str>) -> Result<&BTreeMap<String, Collection>> {
+        match ns {
+            None => Ok(&self.collections),
+            Some(ns) => Ok(&self.namespace(ns)?.collections),
+        }
+    }
+
+    fn collections_mut(&mut self, ns: Option<&str>) -> Result<&mut BTreeMap<String, Collection>> {
+        match ns {
+            None => Ok(&mut self.collections),
+            Some(ns) => self
+                .namespaces
+                .get_mut(ns)
+                .map(|namespace| &mut namespace.collections)
+                .ok_or_else(|| KvsError::NamespaceNotFound(ns.to_owned())),
+        }
+    }
+
+    /// Returns true if `key` of namespace `ns` holds a string.
+    fn holds_string(&self, ns: Option<&str>, key: &str) -> Result<bool> {
+        Ok(match self.lookup(ns, key)? {
+            None => false,
+            Some((_, false)) => true,
+            Some((cmd_pos, true)) => record_key(&self.files, &self.keyring, &cmd_pos)? == key,
+        })
+    }
+
+    /// Returns the collection `key` of namespace `ns`, if any.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::WrongType` if the key holds a string.
+    fn collection(&self, ns: Option<&str>, key: &str) -> Result<Option<&Collection>> {
+        if self.holds_string(ns, key)? {
+            return Err(KvsError::WrongType);
+        }
+        Ok(self.collections(ns)?.get(key))
+    }
+
+    /// Adds or removes an element of the collection `key` of namespace `ns`,
+    /// as written by a record at `cmd_pos`, returning the bytes of the records
+    /// that became stale.
+    fn apply_element(
+        &mut self,
+        ns: Option<&str>,
+        key: &str,
+        element: Element,
+        add: bool,
+        cmd_pos: CommandPos,
+    ) -> Result<u64> {
+        let collections = self.collections_mut(ns)?;
+        if add {
+            let collection = collections
+                .entry(key.to_owned())
+                .or_insert_with(|| element.collection());
+            return Ok(collection
+                .insert(element, cmd_pos)?
+                .map_or(0, |old| old.len));
+        }
+        let old = match collections.get_mut(key) {
+            Some(collection) => collection.remove(&element)?,
+            None => None,
+        };
+        if collections.get(key).is_some_and(Collection::is_empty) {
+            collections.remove(key);
+        }
+        // the removal itself can be deleted in the next compaction, like `Rm`.
+        Ok(old.map_or(0, |old| old.len) + cmd_pos.len)
+    }
+
+    /// Removes the collection `key` of namespace `ns`, returning the bytes of
+    /// the records that became stale.
+    fn remove_collection(&mut self, ns: Option<&str>, key: &str) -> Result<Option<u64>> {
+        Ok(self
+            .collections_mut(ns)?
+            .remove(key)
+            .map(|collection| collection.positions().map(|cmd_pos| cmd_pos.len).sum()))
+    }
+
+    fn history(&self, ns: Option<&str>) -> Result<&BTreeMap<String, Vec<Revision>>> {
+        match ns {
+            None => Ok(&self.history),
+            Some(ns) => Ok(&self.namespace(ns)?.history),
+        }
+    }
+
+    fn history_mut(&mut self, ns: Option<&str>) -> Result<&mut BTreeMap<String, Vec<Revision>>> {
+        match ns {
+            None => Ok(&mut self.history),
+            Some(ns) => self
+                .namespaces
+                .get_mut(ns)
+                .map(|namespace| &mut namespace.history)
+                .ok_or_else(|| KvsError::NamespaceNotFound(ns.to_owned())),
+        }
+    }
+
+    /// Records `revision` as the latest version of `key` in namespace `ns`,
+    /// which replaced the record at `old`, returning the bytes of the records
+    /// that became stale.
+    fn add_revision(
+        &mut self,
+        ns: Option<&str>,
+        key: &str,
+        old: Option<CommandPos>,
+        revision: Revision,
+    ) -> Result<u64> {
+        let retention = self.retention;
+        if retention == Retention::Latest {
+            let removal = if revision.removed {
+                revision.cmd_pos.len
+            } else {
+                0
+            };
+            return Ok(old.map_or(0, |old| old.len) + removal);
+        }
+        let history = self.history_mut(ns)?;
+        let revisions = history.entry(key.to_owned()).or_default();
+        revisions.push(revision);
+        let stale = prune(revisions, retention, timestamp(SystemTime::now()));
+        if revisions.is_empty() {
+            history.remove(key);
+        }
+        Ok(stale)
+    }
+
+    /// Drops the versions the retention no longer keeps, in every namespace,
+    /// returning their bytes.
+    fn prune_history(&mut self, now: u64) -> u64 {
+        let retention = self.retention;
+        let mut stale = 0;
+        let histories = std::iter::once(&mut self.history)
+            .chain(self.namespaces.values_mut().map(|ns| &mut ns.history));
+        for history in histories {
+            history.retain(|_, revisions| {
+                stale += prune(revisions, retention, now);
+                !revisions.is_empty()
+            });
+        }
+        stale
+    }
+
+    /// Returns the retained versions of `key` in namespace `ns`, oldest first.
+    ///
+    /// Without a history, the current value is the only version.
+    fn revisions(&self, ns: Option<&str>, key: &str) -> Result<Vec<Revision>> {
+        if let Some(revisions) = self.history(ns)?.get(key) {
+            return Ok(revisions.clone());
+        }
+        let Some((cmd_pos, verify)) = self.lookup(ns, key)? else {
+            return Ok(Vec::new());
+        };
+        let file = self.files.get(&cmd_pos.gen).expect("Cannot find log file");
+        let cmd = read_cmd(&self.keyring, &read_record(file, &cmd_pos)?)?;
+        if verify && cmd.key() != Some(key) {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Revision {
+            cmd_pos,
+            ts: cmd.ts(),
+            removed: false,
+        }])
+    }
+
+    /// Iterates the positions of the retained versions that are not current,
+    /// in every namespace. The versions of a key come oldest first.
+    fn past_positions(&self) -> impl Iterator<Item = &CommandPos> {
+        past_positions(&self.history).chain(
+            self.namespaces
+                .values()
+                .flat_map(|namespace| past_positions(&namespace.history)),
+        )
+    }
+
+    /// Iterates every retained version, current ones included.
+    fn revisions_mut(&mut self) -> impl Iterator<Item = &mut Revision> {
+        self.history.values_mut().flatten().chain(
+            self.namespaces
+                .values_mut()
+                .flat_map(|namespace| namespace.history.values_mut().flatten()),
+        )
+    }
+
+    /// Returns where the latest `Set` of `key` in namespace `ns` may be.
+    ///
+    /// The flag tells whether the record still has to be checked to really
+    /// belong to `key`, which is the case for hashed lookups.
+    fn lookup(&self, ns: Option<&str>, key: &str) -> Result<Option<(CommandPos, bool)>> {
+        Ok(match self.keys(ns)? {
+            KeyIndex::Ordered(index) => index.get(key).map(|cmd_pos| (*cmd_pos, false)),
+            KeyIndex::Hashed { by_hash, collided } => match collided.get(key) {
+                Some(cmd_pos) => Some((*cmd_pos, false)),
+                None => by_hash.get(&hash_key(key)).map(|cmd_pos| (*cmd_pos, true)),
+            },
+        })
+    }
+
+    /// Points `key` of namespace `ns` at `cmd_pos`, returning the position it replaced.
+    fn insert(
+        &mut self,
+        ns: Option<&str>,
+        key: String,
+        cmd_pos: CommandPos,
+    ) -> Result<Option<CommandPos>> {
+        let (files, keyring) = (&self.files, &self.keyring);
+        let index = match ns {
+            None => &mut self.index,
+            Some(ns) => {
+                &mut self
+                    .namespaces
+                    .get_mut(ns)
+                    .ok_or_else(|| KvsError::NamespaceNotFound(ns.to_owned()))?
+                    .index
+            }
+        };
+        match index {
+            KeyIndex::Ordered(index) => Ok(index.insert(key, cmd_pos)),
+            KeyIndex::Hashed { by_hash, collided } => {
+                if let Some(old) = collided.get_mut(&key) {
+                    return Ok(Some(std::mem::replace(old, cmd_pos)));
+                }
+                match by_hash.entry(hash_key(&key)) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(cmd_pos);
+                        Ok(None)
+                    }
+                    Entry::Occupied(mut entry) => {
+                        if record_key(files, keyring, entry.get())? == key {
+                            Ok(Some(entry.insert(cmd_pos)))
+                        } else {
+                            collided.insert(key, cmd_pos);
+                            Ok(None)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes `key` of namespace `ns` from the index, returning its position.
+    fn remove(&mut self, ns: Option<&str>, key: &str) -> Result<Option<CommandPos>> {
+        let (files, keyring) = (&self.files, &self.keyring);
+        let index = match ns {
+            None => &mut self.index,
+            Some(ns) => {
+                &mut self
+                    .namespaces
+                    .get_mut(ns)
+                    .ok_or_else(|| KvsError::NamespaceNotFound(ns.to_owned()))?
+                    .index
+            }
+        };
+        match index {
+            KeyIndex::Ordered(index) => Ok(index.remove(key)),
+            KeyIndex::Hashed { by_hash, collided } => {
+                if let Some(old) = collided.remove(key) {
+                    return Ok(Some(old));
+                }
+                match by_hash.entry(hash_key(key)) {
+                    Entry::Occupied(entry) if record_key(files, keyring, entry.get())? == key => {
+                        Ok(Some(entry.remove()))
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+impl Collection {
+    fn is_empty(&self) -> bool {
+        match self {
+            Collection::List(list) => list.is_empty(),
+            Collection::Hash(elements) | Collection::Set(elements) => elements.is_empty(),
+        }
+    }
+
+    /// Points `element` at `cmd_pos`, returning the position it replaced.
+    fn insert(&mut self, element: Element, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        match (self, element) {
+            (Collection::List(list), Element::List(index)) => Ok(list.insert(index, cmd_pos)),
+            (Collection::Hash(elements), Element::Hash(name))
+            | (Collection::Set(elements), Element::Set(name)) => Ok(elements.insert(name, cmd_pos)),
+            _ => Err(KvsError::WrongType),
+        }
+    }
+
+    fn remove(&mut self, element: &Element) -> Result<Option<CommandPos>> {
+        match (self, element) {
+            (Collection::List(list), Element::List(index)) => Ok(list.remove(index)),
+            (Collection::Hash(elements), Element::Hash(name))
+            | (Collection::Set(elements), Element::Set(name)) => Ok(elements.remove(name)),
+            _ => Err(KvsError::WrongType),
+        }
+    }
+
+    fn positions(&self) -> Box<dyn Iterator<Item = &CommandPos> + '_> {
+        match self {
+            Collection::List(list) => Box::new(list.values()),
+            Collection::Hash(elements) | Collection::Set(elements) => Box::new(elements.values()),
+        }
+    }
+
+    fn positions_mut(&mut self) -> Box<dyn Iterator<Item = &mut CommandPos> + '_> {
+        match self {
+            Collection::List(list) => Box::new(list.values_mut()),
+            Collection::Hash(elements) | Collection::Set(elements) => {
+                Box::new(elements.values_mut())
+            }
+        }
+    }
+}
+
+impl Element {
+    /// Returns an empty collection of the kind holding this element.
+    fn collection(&self) -> Collection {
+        match self {
+            Element::List(_) => Collection::List(BTreeMap::new()),
+            Element::Hash(_) => Collection::Hash(BTreeMap::new()),
+            Element::Set(_) => Collection::Set(BTreeMap::new()),
+        }
+    }
+}
+
+impl KeyIndex {
+    fn new(mode: IndexMode) -> Self {
+        match mode {
+            IndexMode::Ordered => KeyIndex::Ordered(BTreeMap::new()),
+            IndexMode::Hashed => KeyIndex::Hashed {
+                by_hash: HashMap::new(),
+                collided: BTreeMap::new(),
+            },
+        }
+    }
+
+    fn is_hashed(&self) -> bool {
+        matches!(self, KeyIndex::Hashed { .. })
+    }
+
+    /// Iterates the positions of all live records.
+    ///
+    /// The order is the same as `positions_mut` as long as the index is not modified.
+    fn positions(&self) -> Box<dyn Iterator<Item = &CommandPos> + '_> {
+        match self {
+            KeyIndex::Ordered(index) => Box::new(index.values()),
+            KeyIndex::Hashed { by_hash, collided } => {
+                Box::new(by_hash.values().chain(collided.values()))
+            }
+        }
+    }
+
+    fn positions_mut(&mut self) -> Box<dyn Iterator<Item = &mut CommandPos> + '_> {
+        match self {
+            KeyIndex::Ordered(index) => Box::new(index.values_mut()),
+            KeyIndex::Hashed { by_hash, collided } => {
+                Box::new(by_hash.values_mut().chain(collided.values_mut()))
+            }
+        }
+    }
+}
+
+/// Drops the oldest of `revisions` that `retention` doesn't keep at time
+/// `now`, returning their bytes.
+fn prune(revisions: &mut Vec<Revision>, retention: Retention, now: u64) -> u64 {
+    let expired = match retention {
+        Retention::Latest => revisions.len().saturating_sub(1),
+        Retention::Versions(versions) => revisions.len().saturating_sub(versions.max(1)),
+        // a version was current in the window if the next one came after its start.
+        Retention::Window(window) => {
+            let start = now.saturating_sub(window.as_millis() as u64);
+            revisions
+                .windows(2)
+                .take_while(|pair| pair[1].ts < start)
+                .count()
+        }
+    };
+    let mut stale = revisions
+        .drain(..expired)
+        .map(|revision| revision.cmd_pos.len)
+        .sum();
+    // a removal of a version that is gone tells nothing.
+    if let [only] = revisions[..] {
+        if only.removed {
+            stale += only.cmd_pos.len;
+            revisions.clear();
+        }
+    }
+    stale
+}
+
+/// Iterates the positions of the revisions of `history` that are not current.
+fn past_positions(history: &BTreeMap<String, Vec<Revision>>) -> impl Iterator<Item = &CommandPos> {
+    history.values().flat_map(|revisions| {
+        let past = match revisions.last() {
+            Some(last) if !last.removed => &revisions[..revisions.len() - 1],
+            _ => &revisions[..],
+        };
+        past.iter().map(|revision| &revision.cmd_pos)
+    })
+}
+
+/// Returns `time` in milliseconds since the Unix epoch.
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Reads the key of the command at `cmd_pos`.
+fn record_key(
+    files: &HashMap<u64, Arc<File>>,
+    keyring: &Keyring,
+    cmd_pos: &CommandPos,
+) -> Result<String> {
+    let file = files.get(&cmd_pos.gen).expect("Cannot find log file");
+    read_cmd(keyring, &read_record(file, cmd_pos)?)?
+        .into_key()
+        .ok_or(KvsError::UnexpectedCommandType)
+}
+
+fn log_file(dir: &Path, gen: u64, write: bool) -> io::Result<File> {
+    open_file(&dir.join(format!("{gen}.{LOG_EXT}")), write)
+}
+
+fn blob_file(dir: &Path, file: u64, write: bool) -> io::Result<File> {
+    open_file(&dir.join(format!("{file}.{BLOB_EXT}")), write)
+}
+
+fn open_file(file: &Path, write: bool) -> io::Result<File> {
+    if write {
+        OpenOptions::new().create(true).append(true).open(file)
+    } else {
+        OpenOptions::new().read(true).open(file)
+    }
+}
+/// Takes the advisory lock of the store in `dir`, failing if another process holds it.
+pub(super) fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    file.try_lock_exclusive()
+        .map_err(|_| KvsError::Locked(dir.to_path_buf()))?;
+    Ok(file)
+}
+
+/// Returns sorted numbers of the files with extension `ext` in the given directory.
+pub(super) fn sorted_gen_list(path: &Path, ext: &str) -> Result<Vec<u64>> {
+    let mut list: Vec<_> = fs::read_dir(path)?
+        .filter_map(|res| {
+            if let Ok(dir) = res {
+                let path = dir.path();
+                if path.is_file() && path.extension() == Some(ext.as_ref()) {
+                    Some(path)
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        })
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(|name| name.to_str())
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    list.sort_unstable();
+    Ok(list)
+}
+
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(Self {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.pos += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+struct BufWriterWithPos<R: Write + Seek> {
+    writer: BufWriter<R>,
+    pos: u64,
+}
+
+impl<R: Write + Seek> BufWriterWithPos<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(Self {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Write + Seek> Write for BufWriterWithPos<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.writer.write(buf)?;
+        self.pos += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<R: Write + Seek> Seek for BufWriterWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    // `ns` is the namespace of the key, `None` for the default namespace.
+    // `ts` is the commit timestamp in milliseconds since the Unix epoch, 0 in
+    // records written before timestamps were recorded.
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+        #[serde(default)]
+        ts: u64,
+    },
+    SetBlob {
+        key: String,
+        blob: BlobPos,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+        #[serde(default)]
+        ts: u64,
+    },
+    Rm {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+        #[serde(default)]
+        ts: u64,
+    },
+    // Another command, serialized and sealed with the key `cipher` of the keyring.
+    // Opened by `read_cmd` before use.
+    Sealed {
+        cipher: u32,
+        data: String,
+    },
+    CreateNs {
+        ns: String,
+    },
+    // Drops the namespace with every key in it.
+    DropNs {
+        ns: String,
+    },
+    // Starts a batch of the `len` records that follow. They are only loaded
+    // once all of them are in the log.
+    Batch {
+        len: u64,
+    },
+    // An element of the list `key`. Values pushed to the front of a list take
+    // indexes below the first one.
+    LSet {
+        key: String,
+        index: i64,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    LRm {
+        key: String,
+        index: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    HRm {
+        key: String,
+        field: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    SAdd {
+        key: String,
+        member: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    SRm {
+        key: String,
+        member: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+}
+
+impl Command {
+    fn set(ns: Option<String>, key: String, value: String, ts: u64) -> Self {
+        Command::Set { key, value, ns, ts }
+    }
+
+    fn rm(ns: Option<String>, key: String, ts: u64) -> Self {
+        Command::Rm { key, ns, ts }
+    }
+
+    /// Returns the commit timestamp of a `Set`, `SetBlob` or `Rm` command.
+    fn ts(&self) -> u64 {
+        match self {
+            Command::Set { ts, .. } | Command::SetBlob { ts, .. } | Command::Rm { ts, .. } => *ts,
+            _ => 0,
+        }
+    }
+
+    /// Returns the key of a key command, `None` for the others.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetBlob { key, .. }
+            | Command::Rm { key, .. }
+            | Command::LSet { key, .. }
+            | Command::LRm { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HRm { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SRm { key, .. } => Some(key),
+            Command::Sealed { .. }
+            | Command::CreateNs { .. }
+            | Command::DropNs { .. }
+            | Command::Batch { .. } => None,
+        }
+    }
+
+    /// Returns the namespace, key and element of a collection command, and
+    /// whether it adds the element.
+    fn element(&self) -> Option<(Option<&str>, &str, Element, bool)> {
+        let (ns, key, element, add) = match self {
+            Command::LSet { key, index, ns, .. } => (ns, key, Element::List(*index), true),
+            Command::LRm { key, index, ns } => (ns, key, Element::List(*index), false),
+            Command::HSet { key, field, ns, .. } => (ns, key, Element::Hash(field.clone()), true),
+            Command::HRm { key, field, ns } => (ns, key, Element::Hash(field.clone()), false),
+            Command::SAdd { key, member, ns } => (ns, key, Element::Set(member.clone()), true),
+            Command::SRm { key, member, ns } => (ns, key, Element::Set(member.clone()), false),
+            _ => return None,
+        };
+        Some((ns.as_deref(), key, element, add))
+    }
+
+    /// Returns the key of a key command like `key`, without copying it.
+    fn into_key(self) -> Option<String> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetBlob { key, .. }
+            | Command::Rm { key, .. }
+            | Command::LSet { key, .. }
+            | Command::LRm { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HRm { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SRm { key, .. } => Some(key),
+            Command::Sealed { .. }
+            | Command::CreateNs { .. }
+            | Command::DropNs { .. }
+            | Command::Batch { .. } => None,
+        }
+    }
+}
+
+/// Location of a value stored in a blob file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct BlobPos {
+    file: u64,
+    pos: u64,
+    len: u64,
+    // Key the stored bytes are sealed with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cipher: Option<u32>,
+}
+
+/// Represents the position and length of a json-serialized command in the log.
+#[derive(Clone, Copy)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        Self {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+/// Reads the value stored at `blob`.
+fn read_blob(file: &File, blob: &BlobPos, keyring: &Keyring) -> Result<String> {
+    let mut buf = vec![0; blob.len as usize];
+    read_exact_at(file, &mut buf, blob.pos)?;
+    if let Some(cipher) = blob.cipher {
+        buf = keyring.open(cipher, &buf)?;
+    }
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Serializes `cmd` into a log record, sealed with the current key if there is one.
+fn seal_cmd(keyring: &Keyring, cmd: &Command) -> Result<Vec<u8>> {
+    let record = serde_json::to_vec(cmd)?;
+    match keyring.seal(&record)? {
+        Some((cipher, sealed)) => Ok(serde_json::to_vec(&Command::Sealed {
+            cipher,
+            data: hex::encode(sealed),
+        })?),
+        None => Ok(record),
+    }
+}
+
+/// Opens `cmd` if it is sealed.
+fn open_cmd(keyring: &Keyring, cmd: Command) -> Result<Command> {
+    match cmd {
+        Command::Sealed { cipher, data } => {
+            let sealed = hex::decode(data).map_err(|_| KvsError::Decryption)?;
+            Ok(serde_json::from_slice(&keyring.open(cipher, &sealed)?)?)
+        }
+        cmd => Ok(cmd),
+    }
+}
+
+/// Decodes and opens a log record.
+fn read_cmd(keyring: &Keyring, record: &[u8]) -> Result<Command> {
+    open_cmd(keyring, serde_json::from_slice(record)?)
+}
+
+/// Returns `record` as the writer would write it now, re-encrypting it unless
+/// it is already sealed with the current key, or plaintext without a key.
+fn reseal(keyring: &Keyring, record: Vec<u8>) -> Result<Vec<u8>> {
+    let cmd: Command = serde_json::from_slice(&record)?;
+    let sealed_with = match &cmd {
+        Command::Sealed { cipher, .. } => Some(*cipher),
+        _ => None,
+    };
+    if sealed_with == keyring.current_id() {
+        return Ok(record);
+    }
+    seal_cmd(keyring, &open_cmd(keyring, cmd)?)
+}
+
+/// Reads the value of the list or hash element at `cmd_pos`.
+fn read_element(keyring: &Keyring, file: &File, cmd_pos: &CommandPos) -> Result<String> {
+    match read_cmd(keyring, &read_record(file, cmd_pos)?)? {
+        Command::LSet { value, .. } | Command::HSet { value, .. } => Ok(value),
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Reads the json-serialized command at `cmd_pos` without moving any file cursor.
+fn read_record(file: &File, cmd_pos: &CommandPos) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; cmd_pos.len as usize];
+    read_exact_at(file, &mut buf, cmd_pos.pos)?;
+    Ok(buf)
+}
+
+#[cfg(unix)]
+pub(super) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(super) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads the commands of a log file into the index of `state`, starting at byte `start`.
+///
+/// A record cut off at the end of the file is left for a later call, and so
+/// is a batch missing some of its records. Returns the number of bytes that
+/// can be saved by a compaction and the position after the last complete
+/// record.
+fn load_cmd(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    state: &mut IndexState,
+    start: u64,
+) -> Result<(u64, u64)> {
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut uncompacted = 0; // number of bytes that can be saved after a compaction.
+    while let Some(cmd) = stream.next() {
+        let mut new_pos = start + stream.byte_offset() as u64;
+        let cmd = match cmd {
+            Err(e) if e.is_eof() => break,
+            cmd => open_cmd(&state.keyring, cmd?)?,
+        };
+        if let Command::Batch { len } = cmd {
+            // the header itself goes away in the next compaction.
+            let header = new_pos - pos;
+            let mut batch = Vec::new();
+            while batch.len() < len as usize {
+                let cmd = match stream.next() {
+                    None => break,
+                    Some(Err(e)) if e.is_eof() => break,
+                    Some(cmd) => open_cmd(&state.keyring, cmd?)?,
+                };
+                let end = start + stream.byte_offset() as u64;
+                batch.push((cmd, new_pos..end));
+                new_pos = end;
+            }
+            if batch.len() < len as usize {
+                break;
+            }
+            uncompacted += header;
+            for (cmd, range) in batch {
+                uncompacted += load_one(gen, state, cmd, range)?;
+            }
+        } else {
+            uncompacted += load_one(gen, state, cmd, pos..new_pos)?;
+        }
+        pos = new_pos;
+    }
+    Ok((uncompacted, pos))
+}
+
+/// Applies the opened command found at `range` of log file `gen` to the
+/// index, returning the bytes it made stale.
+fn load_one(gen: u64, state: &mut IndexState, cmd: Command, range: Range<u64>) -> Result<u64> {
+    let (pos, new_pos) = (range.start, range.end);
+    let mut uncompacted = 0;
+    match cmd {
+        Command::Set { key, ns, ts, .. } | Command::SetBlob { key, ns, ts, .. } => {
+            let cmd_pos = (gen, pos..new_pos).into();
+            let old_cmd = state.insert(ns.as_deref(), key.clone(), cmd_pos)?;
+            let revision = Revision {
+                cmd_pos,
+                ts,
+                removed: false,
+            };
+            uncompacted += state.add_revision(ns.as_deref(), &key, old_cmd, revision)?;
+        }
+        Command::Rm { key, ns, ts } => {
+            if let Some(old_cmd) = state.remove(ns.as_deref(), &key)? {
+                let revision = Revision {
+                    cmd_pos: (gen, pos..new_pos).into(),
+                    ts,
+                    removed: true,
+                };
+                uncompacted += state.add_revision(ns.as_deref(), &key, Some(old_cmd), revision)?;
+            } else {
+                if let Some(stale) = state.remove_collection(ns.as_deref(), &key)? {
+                    uncompacted += stale;
+                }
+                // the "remove" command itself can be deleted in the next compaction.
+                // so we add its length to `uncompacted`.
+                uncompacted += new_pos - pos;
+            }
+        }
+        Command::CreateNs { ns } => state.create_namespace(ns, new_pos - pos),
+        Command::DropNs { ns } => {
+            uncompacted += state.drop_namespace(&ns).unwrap_or(0) + new_pos - pos;
+        }
+        Command::Sealed { .. } | Command::Batch { .. } => {
+            return Err(KvsError::UnexpectedCommandType)
+        }
+        cmd => {
+            let (ns, key, element, add) = cmd.element().expect("a collection command");
+            uncompacted +=
+                state.apply_element(ns, key, element, add, (gen, pos..new_pos).into())?;
+        }
+    }
+    Ok(uncompacted)
+}
+
+/// Replays every decodable command in `buf` onto `entries`.
+///
+/// Returns the byte ranges that had to be skipped. Keys found inside those
+/// ranges are added to `suspect_keys`.
+fn salvage_cmd(
+    dir: &Path,
+    keyring: &Keyring,
+    buf: &[u8],
+    entries: &mut BTreeMap<Option<String>, BTreeMap<String, Salvaged>>,
+    blob_refs: &mut HashMap<u64, bool>,
+    suspect_keys: &mut BTreeSet<String>,
+) -> Result<Vec<Range<u64>>> {
+    let mut regions = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let mut stream = Deserializer::from_slice(&buf[pos..]).into_iter::<Command>();
+        match stream.next() {
+            None => break,
+            Some(Ok(cmd)) => {
+                let end = pos + stream.byte_offset();
+                match open_cmd(keyring, cmd) {
+                    // a batch is recovered whole or not at all.
+                    Ok(Command::Batch { len }) => {
+                        let (batch, batch_end) = read_batch(keyring, buf, end, len)?;
+                        match batch {
+                            Some(cmds) => {
+                                for cmd in cmds {
+                                    salvage_one(
+                                        dir,
+                                        keyring,
+                                        cmd,
+                                        entries,
+                                        blob_refs,
+                                        suspect_keys,
+                                    );
+                                }
+                            }
+                            None => {
+                                suspect_keys.extend(keys_in(&buf[pos..batch_end]));
+                                regions.push(pos as u64..batch_end as u64);
+                            }
+                        }
+                        pos = batch_end;
+                        continue;
+                    }
+                    // without any key, every sealed record would be dropped.
+                    Err(KvsError::MissingKey(cipher)) if keyring.is_empty() => {
+                        return Err(KvsError::MissingKey(cipher));
+                    }
+                    // a record that can't be opened is as good as a damaged one.
+                    Ok(Command::Sealed { .. }) | Err(_) => regions.push(pos as u64..end as u64),
+                    Ok(cmd) => salvage_one(dir, keyring, cmd, entries, blob_refs, suspect_keys),
+                }
+                pos = end;
+            }
+            Some(Err(_)) => {
+                let next = next_record(buf, pos + 1).unwrap_or(buf.len());
+                suspect_keys.extend(keys_in(&buf[pos..next]));
+                regions.push(pos as u64..next as u64);
+                pos = next;
+            }
+        }
+    }
+    Ok(regions)
+}
+
+/// Replays the opened command `cmd` onto `entries`.
+fn salvage_one(
+    dir: &Path,
+    keyring: &Keyring,
+    cmd: Command,
+    entries: &mut BTreeMap<Option<String>, BTreeMap<String, Salvaged>>,
+    blob_refs: &mut HashMap<u64, bool>,
+    suspect_keys: &mut BTreeSet<String>,
+) {
+    match cmd {
+        // a key whose namespace was created in a damaged region recreates it.
+        Command::Set { key, value, ns, ts } => {
+            entries
+                .entry(ns)
+                .or_default()
+                .insert(key, Salvaged::Value(value, ts));
+        }
+        Command::SetBlob { key, blob, ns, ts } => {
+            let value = blob_file(dir, blob.file, false)
+                .map_err(KvsError::from)
+                .and_then(|file| read_blob(&file, &blob, keyring));
+            *blob_refs.entry(blob.file).or_insert(true) &= value.is_ok();
+            let keys = entries.entry(ns).or_default();
+            match value {
+                Ok(value) => {
+                    keys.insert(key, Salvaged::Value(value, ts));
+                }
+                Err(_) => {
+                    keys.remove(&key);
+                    suspect_keys.insert(key);
+                }
+            }
+        }
+        Command::Rm { key, ns, .. } => {
+            if let Some(keys) = entries.get_mut(&ns) {
+                keys.remove(&key);
+            }
+        }
+        Command::CreateNs { ns } => {
+            entries.entry(Some(ns)).or_default();
+        }
+        Command::DropNs { ns } => {
+            entries.remove(&Some(ns));
+        }
+        Command::Sealed { .. } | Command::Batch { .. } => {}
+        cmd => salvage_element(entries, cmd),
+    }
+}
+
+/// Reads the `len` records of a batch starting at `from` in `buf`, returning
+/// them if all of them are intact, and where the batch ends.
+///
+/// A damaged record is skipped like `salvage_cmd` does, and still counts as
+/// one of the batch.
+fn read_batch(
+    keyring: &Keyring,
+    buf: &[u8],
+    from: usize,
+    len: u64,
+) -> Result<(Option<Vec<Command>>, usize)> {
+    let (mut cmds, mut pos, mut intact) = (Vec::new(), from, true);
+    for _ in 0..len {
+        let mut stream = Deserializer::from_slice(&buf[pos..]).into_iter::<Command>();
+        match stream.next() {
+            None => {
+                intact = false;
+                break;
+            }
+            Some(Ok(cmd)) => {
+                pos += stream.byte_offset();
+                match open_cmd(keyring, cmd) {
+                    Err(KvsError::MissingKey(cipher)) if keyring.is_empty() => {
+                        return Err(KvsError::MissingKey(cipher));
+                    }
+                    Ok(Command::Sealed { .. } | Command::Batch { .. }) | Err(_) => intact = false,
+                    Ok(cmd) => cmds.push(cmd),
+                }
+            }
+            Some(Err(_)) => {
+                intact = false;
+                pos = next_record(buf, pos + 1).unwrap_or(buf.len());
+            }
+        }
+    }
+    Ok((intact.then_some(cmds), pos))
+}
+
+/// A live key found by `KvStore::repair`.
+enum Salvaged {
+    // The value of a string key, and its commit timestamp.
+    Value(String, u64),
+    // The commands adding the elements of a collection.
+    Elements(BTreeMap<Element, Command>),
+}
+
+/// Replays the collection command `cmd` onto `entries`.
+fn salvage_element(
+    entries: &mut BTreeMap<Option<String>, BTreeMap<String, Salvaged>>,
+    cmd: Command,
+) {
+    let (ns, key, element, add) = cmd.element().expect("a collection command");
+    let (ns, key) = (ns.map(str::to_owned), key.to_owned());
+    let keys = entries.entry(ns).or_default();
+    let salvaged = keys
+        .entry(key.clone())
+        .or_insert_with(|| Salvaged::Elements(BTreeMap::new()));
+    // a key holding a string keeps it.
+    if let Salvaged::Elements(elements) = salvaged {
+        if add {
+            elements.insert(element, cmd);
+        } else {
+            elements.remove(&element);
+        }
+        if elements.is_empty() {
+            keys.remove(&key);
+        }
+    }
+}
+
+/// Finds the offset of the next complete, decodable command at or after `from`.
+fn next_record(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).filter(|&i| buf[i] == b'{').find(|&i| {
+        Deserializer::from_slice(&buf[i..])
+            .into_iter::<Command>()
+            .next()
+            .is_some_and(|cmd| cmd.is_ok())
+    })
+}
+
+/// Extracts the keys of any (possibly truncated) commands found in a damaged region.
+fn keys_in(region: &[u8]) -> Vec<String> {
+    const KEY_FIELD: &[u8] = br#""key":"#;
+    let mut keys = Vec::new();
+    let mut rest = region;
+    while let Some(start) = rest.windows(KEY_FIELD.len()).position(|w| w == KEY_FIELD) {
+        rest = &rest[start + KEY_FIELD.len()..];
+        if let Some(Ok(key)) = Deserializer::from_slice(rest).into_iter::<String>().next() {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+/// Returns a path in `lost+found` for the file `name` that doesn't exist yet.
+fn lost_and_found_path(dir: &Path, name: &str) -> PathBuf {
+    let dir = dir.join(LOST_AND_FOUND);
+    let mut target = dir.join(name);
+    let mut n = 1;
+    while target.exists() {
+        target = dir.join(format!("{name}.{n}"));
+        n += 1;
+    }
+    target
+}
+
+impl KvStore {
+    /// Sets `key` of namespace `ns`, `None` being the default namespace.
+    fn set_in(&self, ns: Option<&str>, key: String, value: String) -> Result<()> {
+        let mut log = self.lock_writer()?;
+        self.set_locked(&mut log, ns, key, value)
+    }
+
+    /// Sets `key` of namespace `ns` while the caller holds the writer.
+    fn set_locked(
+        &self,
+        log: &mut LogWriter,
+        ns: Option<&str>,
+        key: String,
+        value: String,
+    ) -> Result<()> {
+        // namespaces can't come and go while we hold the writer.
+        let watchers = self.watch_hub(ns)?;
+        if self.read_state().collections(ns)?.contains_key(&key) {
+            return Err(KvsError::WrongType);
+        }
+        let mut feeds = watchers.lock();
+        let watched = feeds.watches(&key).then(|| value.clone());
+        let ts = timestamp(SystemTime::now());
+        let cmd = self.set_command(log, ns, key, value, ts)?;
+        let cmd_pos = log.append(&cmd)?;
+
+        let key = cmd.into_key().ok_or(KvsError::UnexpectedCommandType)?;
+        self.invalidate_cache(&key);
+        let mut state = self.write_state();
+        let old_cmd = state.insert(ns, key.clone(), cmd_pos)?;
+        let revision = Revision {
+            cmd_pos,
+            ts,
+            removed: false,
+        };
+        let stale = state.add_revision(ns, &key, old_cmd, revision)?;
+        state.uncompacted += stale;
+        let event = watched.map(|value| (key, value));
+        let compact = state.uncompacted > COMPACTION_THRESHOLD;
+        drop(state);
+        if let Some((key, value)) = event {
+            feeds.publish(&key, Some(&value));
+        }
+        drop(feeds);
+
+        if compact {
+            self.compact_locked(log)?;
+        }
+        Ok(())
+    }
+
+    /// Sets many keys of namespace `ns` as one batch, visible to readers and
+    /// watchers all at once, and surviving a crash whole or not at all.
+    fn set_many_in(&self, ns: Option<&str>, pairs: Vec<(String, String)>) -> Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let mut log = self.lock_writer()?;
+        let watchers = self.watch_hub(ns)?;
+        {
+            let state = self.read_state();
+            let collections = state.collections(ns)?;
+            if pairs.iter().any(|(key, _)| collections.contains_key(key)) {
+                return Err(KvsError::WrongType);
+            }
+        }
+        let mut feeds = watchers.lock();
+        let ts = timestamp(SystemTime::now());
+        let mut events = Vec::new();
+        let mut cmds = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            if feeds.watches(&key) {
+                events.push((key.clone(), value.clone()));
+            }
+            cmds.push(self.set_command(&mut log, ns, key, value, ts)?);
+        }
+        let positions = log.append_batch(&cmds)?;
+
+        let mut keys = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let key = cmd.into_key().ok_or(KvsError::UnexpectedCommandType)?;
+            self.invalidate_cache(&key);
+            keys.push(key);
+        }
+        let mut state = self.write_state();
+        for (key, cmd_pos) in keys.into_iter().zip(positions) {
+            let old_cmd = state.insert(ns, key.clone(), cmd_pos)?;
+            let revision = Revision {
+                cmd_pos,
+                ts,
+                removed: false,
+            };
+            let stale = state.add_revision(ns, &key, old_cmd, revision)?;
+            state.uncompacted += stale;
+        }
+        let compact = state.uncompacted > COMPACTION_THRESHOLD;
+        drop(state);
+        for (key, value) in &events {
+            feeds.publish(key, Some(value));
+        }
+        drop(feeds);
+
+        if compact {
+            self.compact_locked(&mut log)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the record setting `key` of namespace `ns`, writing the value
+    /// to a blob file first if it is over the threshold.
+    fn set_command(
+        &self,
+        log: &mut LogWriter,
+        ns: Option<&str>,
+        key: String,
+        value: String,
+        ts: u64,
+    ) -> Result<Command> {
+        if self.blob_threshold > 0 && value.len() as u64 > self.blob_threshold {
+            let blob = self.write_blob(log, value.as_bytes())?;
+            Ok(Command::SetBlob {
+                key,
+                blob,
+                ns: ns.map(str::to_owned),
+                ts,
+            })
+        } else {
+            Ok(Command::set(ns.map(str::to_owned), key, value, ts))
+        }
+    }
+
+    /// Adds `delta` to `key` of namespace `ns`.
+    fn incr_in(&self, ns: Option<&str>, key: String, delta: i64) -> Result<i64> {
+        // holding the writer keeps the value from changing before it is set.
+        let mut log = self.lock_writer()?;
+        let value = increment(self.get_in(ns, key.clone())?.as_deref(), delta)?;
+        self.set_locked(&mut log, ns, key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// Sets `key` of namespace `ns`, returning the value it replaced.
+    fn swap_in(&self, ns: Option<&str>, key: String, value: String) -> Result<Option<String>> {
+        // holding the writer keeps the value from changing before it is replaced.
+        let mut log = self.lock_writer()?;
+        let old = self.get_in(ns, key.clone())?;
+        self.set_locked(&mut log, ns, key, value)?;
+        Ok(old)
+    }
+
+    /// Removes `key` of namespace `ns`, returning its value.
+    fn take_in(&self, ns: Option<&str>, key: String) -> Result<String> {
+        let mut log = self.lock_writer()?;
+        let value = self.get_in(ns, key.clone())?.ok_or(KvsError::KeyNotFound)?;
+        self.remove_locked(&mut log, ns, key)?;
+        Ok(value)
+    }
+
+    /// Gets `key` of namespace `ns`.
+    fn get_in(&self, ns: Option<&str>, key: String) -> Result<Option<String>> {
+        loop {
+            let (cmd_pos, verify, file) = {
+                let state = self.read_state();
+                if state.collections(ns)?.contains_key(&key) {
+                    return Err(KvsError::WrongType);
+                }
+                match state.lookup(ns, &key)? {
+                    Some((cmd_pos, verify)) => {
+                        (cmd_pos, verify, state.files.get(&cmd_pos.gen).cloned())
+                    }
+                    None => return Ok(None),
+                }
+            };
+            // a cached value is only ever stored under its own key, so a hit needs no verification.
+            if let Some(cache) = &self.cache {
+                let mut cache = cache.lock().expect("cache lock poisoned");
+                if let Some(value) = cache.get(&key, cmd_pos.gen, cmd_pos.pos) {
+                    return Ok(Some(value));
+                }
+            }
+
+            let file = file.ok_or(KvsError::KeyNotFound)?;
+            let cmd = read_cmd(&self.keyring, &read_record(&file, &cmd_pos)?)?;
+            if verify && cmd.key() != Some(key.as_str()) {
+                // another key with the same hash.
+                return Ok(None);
+            }
+            let blob = match &cmd {
+                Command::SetBlob { blob, .. } => Some(blob.file),
+                _ => None,
+            };
+            if let Some((key, value)) = self.resolve(cmd)? {
+                if let Some(cache) = &self.cache {
+                    let mut cache = cache.lock().expect("cache lock poisoned");
+                    cache.insert(key, cmd_pos.gen, cmd_pos.pos, value.clone());
+                }
+                return Ok(Some(value));
+            }
+            // the blob file is gone. Unless a compaction moved the value in the
+            // meantime, it is missing from the store.
+            let moved = self.read_state().lookup(ns, &key)?.map(|(moved, _)| moved);
+            if moved.map(|moved| (moved.gen, moved.pos)) == Some((cmd_pos.gen, cmd_pos.pos)) {
+                return Err(KvsError::MissingBlob(blob.unwrap_or_default()));
+            }
+        }
+    }
+
+    /// Removes `key` of namespace `ns`.
+    fn remove_in(&self, ns: Option<&str>, key: String) -> Result<()> {
+        let mut log = self.lock_writer()?;
+        self.remove_locked(&mut log, ns, key)
+    }
+
+    /// Removes `key` of namespace `ns` while the caller holds the writer.
+    fn remove_locked(&self, log: &mut LogWriter, ns: Option<&str>, key: String) -> Result<()> {
+        let watchers = self.watch_hub(ns)?;
+        let ts = timestamp(SystemTime::now());
+        let cmd = Command::rm(ns.map(str::to_owned), key, ts);
+        let cmd_pos = log.append(&cmd)?;
+
+        let key = cmd.into_key().ok_or(KvsError::UnexpectedCommandType)?;
+        self.invalidate_cache(&key);
+        let mut state = self.write_state();
+        if let Some(old_cmd) = state.remove(ns, &key)? {
+            let revision = Revision {
+                cmd_pos,
+                ts,
+                removed: true,
+            };
+            let stale = state.add_revision(ns, &key, Some(old_cmd), revision)?;
+            state.uncompacted += stale;
+            drop(state);
+            watchers.publish(&key, None);
+            Ok(())
+        } else if let Some(stale) = state.remove_collection(ns, &key)? {
+            state.uncompacted += stale;
+            Ok(())
         } else {
-            Err(KvsError::UnexpectedCommandType)
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Appends the collection command `cmd` and indexes it, while the caller
+    /// holds the writer.
+    fn write_element(&self, log: &mut LogWriter, cmd: Command) -> Result<()> {
+        let cmd_pos = log.append(&cmd)?;
+        let (ns, key, element, add) = cmd.element().expect("a collection command");
+        let mut state = self.write_state();
+        let stale = state.apply_element(ns, key, element, add, cmd_pos)?;
+        state.uncompacted += stale;
+        let compact = state.uncompacted > COMPACTION_THRESHOLD;
+        drop(state);
+
+        if compact {
+            self.compact_locked(log)?;
+        }
+        Ok(())
+    }
+
+    fn list_push_in(
+        &self,
+        ns: Option<&str>,
+        key: String,
+        end: ListEnd,
+        value: String,
+    ) -> Result<u64> {
+        let mut log = self.lock_writer()?;
+        let (index, len) = match self.read_state().collection(ns, &key)? {
+            None => (0, 0),
+            Some(Collection::List(list)) => {
+                let index = match end {
+                    ListEnd::Front => list.keys().next().map(|first| first - 1),
+                    ListEnd::Back => list.keys().next_back().map(|last| last + 1),
+                };
+                (index.expect("collections are never empty"), list.len())
+            }
+            Some(_) => return Err(KvsError::WrongType),
+        };
+        let cmd = Command::LSet {
+            key,
+            index,
+            value,
+            ns: ns.map(str::to_owned),
+        };
+        self.write_element(&mut log, cmd)?;
+        Ok(len as u64 + 1)
+    }
+
+    fn list_pop_in(&self, ns: Option<&str>, key: String, end: ListEnd) -> Result<Option<String>> {
+        let mut log = self.lock_writer()?;
+        let (index, cmd_pos, file) = {
+            let state = self.read_state();
+            let list = match state.collection(ns, &key)? {
+                None => return Ok(None),
+                Some(Collection::List(list)) => list,
+                Some(_) => return Err(KvsError::WrongType),
+            };
+            let (&index, &cmd_pos) = match end {
+                ListEnd::Front => list.first_key_value(),
+                ListEnd::Back => list.last_key_value(),
+            }
+            .expect("collections are never empty");
+            (index, cmd_pos, Arc::clone(&state.files[&cmd_pos.gen]))
+        };
+        let value = read_element(&self.keyring, &file, &cmd_pos)?;
+        let cmd = Command::LRm {
+            key,
+            index,
+            ns: ns.map(str::to_owned),
+        };
+        self.write_element(&mut log, cmd)?;
+        Ok(Some(value))
+    }
+
+    fn list_range_in(
+        &self,
+        ns: Option<&str>,
+        key: String,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<String>> {
+        let (positions, files) = {
+            let state = self.read_state();
+            let positions: Vec<_> = match state.collection(ns, &key)? {
+                None => return Ok(Vec::new()),
+                Some(Collection::List(list)) => {
+                    let range = list_range(list.len(), start, stop);
+                    list.values()
+                        .skip(range.start)
+                        .take(range.len())
+                        .copied()
+                        .collect()
+                }
+                Some(_) => return Err(KvsError::WrongType),
+            };
+            (positions, state.files.clone())
+        };
+        positions
+            .iter()
+            .map(|cmd_pos| read_element(&self.keyring, &files[&cmd_pos.gen], cmd_pos))
+            .collect()
+    }
+
+    fn hash_set_in(
+        &self,
+        ns: Option<&str>,
+        key: String,
+        field: String,
+        value: String,
+    ) -> Result<()> {
+        let mut log = self.lock_writer()?;
+        if let Some(Collection::List(_) | Collection::Set(_)) =
+            self.read_state().collection(ns, &key)?
+        {
+            return Err(KvsError::WrongType);
+        }
+        let cmd = Command::HSet {
+            key,
+            field,
+            value,
+            ns: ns.map(str::to_owned),
+        };
+        self.write_element(&mut log, cmd)
+    }
+
+    fn hash_get_in(&self, ns: Option<&str>, key: String, field: String) -> Result<Option<String>> {
+        let (cmd_pos, file) = {
+            let state = self.read_state();
+            let cmd_pos = match state.collection(ns, &key)? {
+                None => return Ok(None),
+                Some(Collection::Hash(hash)) => match hash.get(&field) {
+                    Some(cmd_pos) => *cmd_pos,
+                    None => return Ok(None),
+                },
+                Some(_) => return Err(KvsError::WrongType),
+            };
+            (cmd_pos, Arc::clone(&state.files[&cmd_pos.gen]))
+        };
+        read_element(&self.keyring, &file, &cmd_pos).map(Some)
+    }
+
+    fn hash_remove_in(&self, ns: Option<&str>, key: String, field: String) -> Result<bool> {
+        let mut log = self.lock_writer()?;
+        match self.read_state().collection(ns, &key)? {
+            None => return Ok(false),
+            Some(Collection::Hash(hash)) if !hash.contains_key(&field) => return Ok(false),
+            Some(Collection::Hash(_)) => {}
+            Some(_) => return Err(KvsError::WrongType),
+        }
+        let cmd = Command::HRm {
+            key,
+            field,
+            ns: ns.map(str::to_owned),
+        };
+        self.write_element(&mut log, cmd)?;
+        Ok(true)
+    }
+
+    fn set_add_in(&self, ns: Option<&str>, key: String, member: String) -> Result<bool> {
+        let mut log = self.lock_writer()?;
+        match self.read_state().collection(ns, &key)? {
+            None => {}
+            Some(Collection::Set(set)) if set.contains_key(&member) => return Ok(false),
+            Some(Collection::Set(_)) => {}
+            Some(_) => return Err(KvsError::WrongType),
+        }
+        let cmd = Command::SAdd {
+            key,
+            member,
+            ns: ns.map(str::to_owned),
+        };
+        self.write_element(&mut log, cmd)?;
+        Ok(true)
+    }
+
+    fn set_remove_in(&self, ns: Option<&str>, key: String, member: String) -> Result<bool> {
+        let mut log = self.lock_writer()?;
+        match self.read_state().collection(ns, &key)? {
+            None => return Ok(false),
+            Some(Collection::Set(set)) if !set.contains_key(&member) => return Ok(false),
+            Some(Collection::Set(_)) => {}
+            Some(_) => return Err(KvsError::WrongType),
+        }
+        let cmd = Command::SRm {
+            key,
+            member,
+            ns: ns.map(str::to_owned),
+        };
+        self.write_element(&mut log, cmd)?;
+        Ok(true)
+    }
+
+    fn set_members_in(&self, ns: Option<&str>, key: String) -> Result<Vec<String>> {
+        match self.read_state().collection(ns, &key)? {
+            None => Ok(Vec::new()),
+            Some(Collection::Set(set)) => Ok(set.keys().cloned().collect()),
+            Some(_) => Err(KvsError::WrongType),
+        }
+    }
+
+    /// Reads the retained versions of `key` in namespace `ns`, or only the
+    /// version current at `at` if given.
+    fn versions_in(&self, ns: Option<&str>, key: &str, at: Option<u64>) -> Result<Vec<Version>> {
+        'retry: loop {
+            let (mut revisions, files) = {
+                let state = self.read_state();
+                (state.revisions(ns, key)?, state.files.clone())
+            };
+            if let Some(at) = at {
+                let current = revisions.iter().rposition(|revision| revision.ts <= at);
+                revisions = current.map_or(Vec::new(), |i| vec![revisions[i]]);
+            }
+
+            let mut versions = Vec::new();
+            for revision in revisions {
+                let cmd_pos = revision.cmd_pos;
+                let value = if revision.removed {
+                    None
+                } else {
+                    let file = files.get(&cmd_pos.gen).expect("Cannot find log file");
+                    let cmd = read_cmd(&self.keyring, &read_record(file, &cmd_pos)?)?;
+                    let blob = match &cmd {
+                        Command::SetBlob { blob, .. } => Some(blob.file),
+                        _ => None,
+                    };
+                    match self.resolve(cmd)? {
+                        Some((_, value)) => Some(value),
+                        None => {
+                            // the blob file is gone, which is fine if a
+                            // compaction moved the value in the meantime.
+                            let moved = self.read_state().revisions(ns, key)?.iter().all(|r| {
+                                (r.cmd_pos.gen, r.cmd_pos.pos) != (cmd_pos.gen, cmd_pos.pos)
+                            });
+                            if moved {
+                                continue 'retry;
+                            }
+                            return Err(KvsError::MissingBlob(blob.unwrap_or_default()));
+                        }
+                    }
+                };
+                versions.push(Version {
+                    timestamp: UNIX_EPOCH + Duration::from_millis(revision.ts),
+                    value,
+                });
+            }
+            return Ok(versions);
+        }
+    }
+
+    /// Subscribes to the changes of namespace `ns`.
+    fn watch_in(&self, ns: Option<&str>, prefix: String) -> Result<Watcher> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(self.watch_hub(ns)?.subscribe(prefix))
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// set k-v to memory
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_in(None, key, value)
+    }
+
+    /// get value of the key
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_in(None, key)
+    }
+
+    /// remove the key, which may hold a collection
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_in(None, key)
+    }
+
+    /// Appends the records as one batch, then updates the index at once.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.set_many_in(None, pairs)
+    }
+
+    fn swap(&self, key: String, value: String) -> Result<Option<String>> {
+        self.swap_in(None, key, value)
+    }
+
+    fn take(&self, key: String) -> Result<String> {
+        self.take_in(None, key)
+    }
+
+    /// Syncs the current log and blob files. A read-only store has nothing to flush.
+    fn flush(&self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        let mut log = self.lock_writer()?;
+        log.writer.flush()?;
+        log.writer.writer.get_ref().sync_all()?;
+        if let Some(blob) = &mut log.blob {
+            blob.writer.flush()?;
+            blob.writer.writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr_in(None, key, delta)
+    }
+
+    fn list_push(&self, key: String, end: ListEnd, value: String) -> Result<u64> {
+        self.list_push_in(None, key, end, value)
+    }
+
+    fn list_pop(&self, key: String, end: ListEnd) -> Result<Option<String>> {
+        self.list_pop_in(None, key, end)
+    }
+
+    fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.list_range_in(None, key, start, stop)
+    }
+
+    fn hash_set(&self, key: String, field: String, value: String) -> Result<()> {
+        self.hash_set_in(None, key, field, value)
+    }
+
+    fn hash_get(&self, key: String, field: String) -> Result<Option<String>> {
+        self.hash_get_in(None, key, field)
+    }
+
+    fn hash_remove(&self, key: String, field: String) -> Result<bool> {
+        self.hash_remove_in(None, key, field)
+    }
+
+    fn set_add(&self, key: String, member: String) -> Result<bool> {
+        self.set_add_in(None, key, member)
+    }
+
+    fn set_remove(&self, key: String, member: String) -> Result<bool> {
+        self.set_remove_in(None, key, member)
+    }
+
+    fn set_members(&self, key: String) -> Result<Vec<String>> {
+        self.set_members_in(None, key)
+    }
+
+    fn scan_limit(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_in(None, (start, end), limit)
+    }
+
+    /// Fails with `KvsError::ReadOnly` on a read-only store, which never sees
+    /// the writes of other processes as they happen.
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.watch_in(None, prefix)
+    }
+
+    fn create_namespace(&self, name: String) -> Result<()> {
+        let mut log = self.lock_writer()?;
+        if self.read_state().namespaces.contains_key(&name) {
+            return Err(KvsError::NamespaceExists(name));
         }
+        let cmd_pos = log.append(&Command::CreateNs { ns: name.clone() })?;
+        self.write_state().create_namespace(name, cmd_pos.len);
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.read_state().namespaces.keys().cloned().collect())
+    }
+
+    /// Writes a single record whatever the number of keys. Their records are
+    /// reclaimed by the next compaction.
+    fn drop_namespace(&self, name: String) -> Result<()> {
+        let mut log = self.lock_writer()?;
+        self.read_state().namespace(&name)?;
+        let cmd_pos = log.append(&Command::DropNs { ns: name.clone() })?;
+        let mut state = self.write_state();
+        let stale = state.drop_namespace(&name).unwrap_or(0);
+        state.uncompacted += stale + cmd_pos.len;
+        Ok(())
+    }
+
+    fn namespace(&self, name: String) -> Result<Box<dyn KvsEngine + '_>> {
+        self.read_state().namespace(&name)?;
+        Ok(Box::new(NamespaceView { store: self, name }))
+    }
+}
+
+/// A namespace of a `KvStore`.
+///
+/// Namespaces are looked up on every call, so a view of a dropped namespace
+/// fails with `KvsError::NamespaceNotFound`.
+struct NamespaceView<'a> {
+    store: &'a KvStore,
+    name: String,
+}
+
+impl KvsEngine for NamespaceView<'_> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set_in(Some(&self.name), key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get_in(Some(&self.name), key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove_in(Some(&self.name), key)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.store.set_many_in(Some(&self.name), pairs)
+    }
+
+    fn swap(&self, key: String, value: String) -> Result<Option<String>> {
+        self.store.swap_in(Some(&self.name), key, value)
+    }
+
+    fn take(&self, key: String) -> Result<String> {
+        self.store.take_in(Some(&self.name), key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.store.incr_in(Some(&self.name), key, delta)
+    }
+
+    fn list_push(&self, key: String, end: ListEnd, value: String) -> Result<u64> {
+        self.store.list_push_in(Some(&self.name), key, end, value)
+    }
+
+    fn list_pop(&self, key: String, end: ListEnd) -> Result<Option<String>> {
+        self.store.list_pop_in(Some(&self.name), key, end)
+    }
+
+    fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.store.list_range_in(Some(&self.name), key, start, stop)
+    }
+
+    fn hash_set(&self, key: String, field: String, value: String) -> Result<()> {
+        self.store.hash_set_in(Some(&self.name), key, field, value)
+    }
+
+    fn hash_get(&self, key: String, field: String) -> Result<Option<String>> {
+        self.store.hash_get_in(Some(&self.name), key, field)
+    }
+
+    fn hash_remove(&self, key: String, field: String) -> Result<bool> {
+        self.store.hash_remove_in(Some(&self.name), key, field)
+    }
+
+    fn set_add(&self, key: String, member: String) -> Result<bool> {
+        self.store.set_add_in(Some(&self.name), key, member)
+    }
+
+    fn set_remove(&self, key: String, member: String) -> Result<bool> {
+        self.store.set_remove_in(Some(&self.name), key, member)
+    }
+
+    fn set_members(&self, key: String) -> Result<Vec<String>> {
+        self.store.set_members_in(Some(&self.name), key)
+    }
+
+    fn scan_limit(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.store.scan_in(Some(&self.name), (start, end), limit)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.store.watch_in(Some(&self.name), prefix)
+    }
+
+    fn create_namespace(&self, name: String) -> Result<()> {
+        self.store.create_namespace(name)
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        self.store.list_namespaces()
+    }
+
+    fn drop_namespace(&self, name: String) -> Result<()> {
+        self.store.drop_namespace(name)
+    }
+
+    fn namespace(&self, name: String) -> Result<Box<dyn KvsEngine + '_>> {
+        self.store.namespace(name)
     }
 }
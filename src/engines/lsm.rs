@@ -0,0 +1,1073 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    mem,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::error::{KvsError, Result};
+
+use super::{
+    empty_range, increment,
+    kvs::{lock_dir, read_exact_at, sorted_gen_list},
+    watch::{WatchHub, Watcher},
+    EngineKind, KvsEngine, ListEnd,
+};
+
+/// Name of the file listing the tables of every level.
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Extension of write-ahead log files.
+const WAL_EXT: &str = "wal";
+
+/// Extension of sorted table files.
+const TABLE_EXT: &str = "sst";
+
+/// Size of the table footer: offset and length of the table metadata.
+const FOOTER_LEN: u64 = 16;
+
+/// Length written in place of the value length of a deleted key.
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Every level below level 1 holds this many times more bytes than the one above.
+const LEVEL_SIZE_RATIO: u64 = 10;
+
+/// Bits of bloom filter spent on every key of a table.
+const BLOOM_BITS_PER_KEY: usize = 10;
+
+/// Number of hash functions of the bloom filters, optimal for `BLOOM_BITS_PER_KEY`.
+const BLOOM_HASHES: u32 = 7;
+
+/// A key and its value, `None` if the key was removed.
+type Entry = (String, Option<String>);
+
+/// A sorted run of entries merged by `MergeIter`.
+type Source = Box<dyn Iterator<Item = Result<Entry>>>;
+
+/// Tables by level. Level 0 holds flushed memtables, newest first, whose key
+/// ranges may overlap; every other level is sorted by key and never overlaps.
+type Levels = Vec<Vec<Arc<Table>>>;
+
+/// Log-structured merge-tree engine
+///
+/// Writes go to a write-ahead log and an in-memory table. A full memtable is
+/// flushed to an immutable sorted table file by a background thread, which
+/// also merges tables down the levels so reads touch few files.
+pub struct LsmStore {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+    watchers: WatchHub,
+    // Holds the advisory lock on the directory until the store is dropped.
+    _lock: File,
+}
+
+/// Options for opening an `LsmStore`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Bytes of keys and values the memtable holds before it is flushed.
+    pub memtable_capacity: u64,
+    /// Target size in bytes of the data blocks of a table.
+    pub block_size: u64,
+    /// Target size in bytes of the tables written by a compaction.
+    pub table_size: u64,
+    /// Number of level 0 tables that triggers a compaction into level 1.
+    pub level0_tables: usize,
+    /// Target size in bytes of level 1, every further level is ten times larger.
+    pub level1_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_capacity: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// State shared with the background thread.
+struct Shared {
+    path: PathBuf,
+    options: LsmOptions,
+    state: Mutex<State>,
+    // Wakes the background thread up.
+    work: Condvar,
+    // Signals writers waiting for the immutable memtable to be flushed.
+    flushed: Condvar,
+    // Serializes writes.
+    wal: Mutex<Wal>,
+}
+
+struct State {
+    mem: Memtable,
+    // The previous memtable while the background thread flushes it.
+    imm: Option<Arc<Memtable>>,
+    // Replaced as a whole, so a read can keep using the tables it started with.
+    levels: Arc<Levels>,
+    next_id: u64,
+    // Set when the background thread failed; writes fail from then on.
+    error: Option<String>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Memtable {
+    entries: BTreeMap<String, Option<String>>,
+    size: u64,
+    // Write-ahead logs holding the entries, deleted once they are flushed.
+    wals: Vec<u64>,
+}
+
+impl Memtable {
+    fn insert(&mut self, key: String, value: Option<String>) {
+        self.size += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+        self.entries.insert(key, value);
+    }
+}
+
+struct Wal {
+    writer: BufWriter<File>,
+}
+
+/// A record of the write-ahead log.
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+/// The tables of every level, persisted whenever they change.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl LsmStore {
+    /// Opens the store in `path` with the default options.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store holds the directory.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, &LsmOptions::default())
+    }
+
+    /// Opens a store like `open`, configured by `options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: &LsmOptions) -> Result<Self> {
+        let path: PathBuf = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
+
+        let manifest: Manifest = match fs::read(path.join(MANIFEST_FILE)) {
+            Ok(buf) => serde_json::from_slice(&buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels = Levels::new();
+        for ids in &manifest.levels {
+            let level = ids
+                .iter()
+                .map(|&id| Table::open(&path, id).map(Arc::new))
+                .collect::<Result<_>>()?;
+            levels.push(level);
+        }
+        if levels.len() < 2 {
+            levels.resize_with(2, Vec::new);
+        }
+
+        // tables left behind by a flush or compaction that never made it into the manifest.
+        let live: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+        let tables = sorted_gen_list(&path, TABLE_EXT)?;
+        for &id in tables.iter().filter(|id| !live.contains(id)) {
+            fs::remove_file(table_path(&path, id))?;
+        }
+
+        let wals = sorted_gen_list(&path, WAL_EXT)?;
+        let mut mem = Memtable::default();
+        for &id in &wals {
+            replay_wal(&path, id, &mut mem)?;
+        }
+        let last_id = tables.iter().chain(&wals).max().map_or(0, |id| id + 1);
+        let mut next_id = manifest.next_id.max(last_id);
+
+        let wal = Wal::create(&path, next_id)?;
+        mem.wals.push(next_id);
+        next_id += 1;
+
+        let shared = Arc::new(Shared {
+            path,
+            options: options.clone(),
+            state: Mutex::new(State {
+                mem,
+                imm: None,
+                levels: Arc::new(levels),
+                next_id,
+                error: None,
+                shutdown: false,
+            }),
+            work: Condvar::new(),
+            flushed: Condvar::new(),
+            wal: Mutex::new(wal),
+        });
+        let worker = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || shared.run())
+        };
+        Ok(Self {
+            shared,
+            worker: Some(worker),
+            watchers: WatchHub::default(),
+            _lock: lock,
+        })
+    }
+
+    /// Writes `value` for `key`, `None` removing it.
+    fn write(&self, wal: &mut Wal, key: String, value: Option<String>) -> Result<()> {
+        let record = match value {
+            Some(value) => Record::Set { key, value },
+            None => Record::Rm { key },
+        };
+        serde_json::to_writer(&mut wal.writer, &record)?;
+        wal.writer.flush()?;
+
+        let (key, value) = match record {
+            Record::Set { key, value } => (key, Some(value)),
+            Record::Rm { key } => (key, None),
+        };
+        let mut feeds = self.watchers.lock();
+        let event = feeds.watches(&key).then(|| (key.clone(), value.clone()));
+        let mut state = self.shared.lock_state();
+        state.mem.insert(key, value);
+        // still under the wal lock, so events keep the order of the log.
+        if let Some((key, value)) = event {
+            feeds.publish(&key, value.as_deref());
+        }
+        drop(feeds);
+        if state.mem.size >= self.shared.options.memtable_capacity {
+            self.shared.rotate(wal, state)?;
+        }
+        Ok(())
+    }
+
+    fn lock_wal(&self) -> Result<MutexGuard<'_, Wal>> {
+        let wal = self.shared.wal.lock().expect("wal lock poisoned");
+        match &self.shared.lock_state().error {
+            Some(e) => Err(KvsError::Background(e.clone())),
+            None => Ok(wal),
+        }
+    }
+}
+
+impl Drop for LsmStore {
+    fn drop(&mut self) {
+        self.shared.lock_state().shutdown = true;
+        self.shared.work.notify_one();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("state lock poisoned")
+    }
+
+    /// Hands the full memtable to the background thread and starts a new write-ahead log.
+    ///
+    /// Waits for the previous memtable to be flushed first, so writers can't
+    /// outrun the background thread.
+    fn rotate(&self, wal: &mut Wal, mut state: MutexGuard<'_, State>) -> Result<()> {
+        while state.imm.is_some() && state.error.is_none() {
+            state = self.flushed.wait(state).expect("state lock poisoned");
+        }
+        if let Some(e) = &state.error {
+            return Err(KvsError::Background(e.clone()));
+        }
+
+        let id = state.next_id;
+        *wal = Wal::create(&self.path, id)?;
+        state.next_id += 1;
+        let full = mem::take(&mut state.mem);
+        state.mem.wals.push(id);
+        state.imm = Some(Arc::new(full));
+        self.work.notify_one();
+        Ok(())
+    }
+
+    /// Runs the background thread, recording its error for writers to see.
+    fn run(&self) {
+        if let Err(e) = self.flush_and_compact() {
+            self.lock_state().error = Some(e.to_string());
+            self.flushed.notify_all();
+        }
+    }
+
+    fn flush_and_compact(&self) -> Result<()> {
+        loop {
+            let mut state = self.lock_state();
+            let level = loop {
+                if state.shutdown {
+                    return Ok(());
+                }
+                let level = self.pick_compaction(&state.levels);
+                if state.imm.is_some() || level.is_some() {
+                    break level;
+                }
+                state = self.work.wait(state).expect("state lock poisoned");
+            };
+
+            if let Some(imm) = state.imm.clone() {
+                drop(state);
+                self.flush(&imm)?;
+                self.flushed.notify_all();
+            } else if let Some(level) = level {
+                let levels = Arc::clone(&state.levels);
+                drop(state);
+                self.compact(&levels, level)?;
+            }
+        }
+    }
+
+    /// Writes the immutable memtable to a level 0 table.
+    fn flush(&self, imm: &Memtable) -> Result<()> {
+        let mut tables = Vec::new();
+        if !imm.entries.is_empty() {
+            let mut builder = TableBuilder::create(&self.path, self.next_id(), &self.options)?;
+            for (key, value) in &imm.entries {
+                builder.add(key, value.as_deref())?;
+            }
+            tables.push(Arc::new(builder.finish()?));
+        }
+
+        let mut state = self.lock_state();
+        let mut levels = (*state.levels).clone();
+        for table in tables {
+            levels[0].insert(0, table);
+        }
+        self.install(&mut state, levels)?;
+        state.imm = None;
+        drop(state);
+
+        for &id in &imm.wals {
+            fs::remove_file(wal_path(&self.path, id))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the level whose tables should be merged into the next level, if any.
+    fn pick_compaction(&self, levels: &Levels) -> Option<usize> {
+        if levels[0].len() >= self.options.level0_tables {
+            return Some(0);
+        }
+        let mut target = self.options.level1_size;
+        for (level, tables) in levels.iter().enumerate().skip(1) {
+            if tables.iter().map(|table| table.size).sum::<u64>() > target {
+                return Some(level);
+            }
+            target = target.saturating_mul(LEVEL_SIZE_RATIO);
+        }
+        None
+    }
+
+    /// Merges tables of `level` with the tables they overlap in the next level.
+    ///
+    /// Level 0 is merged as a whole; from any other level the first table is
+    /// pushed down.
+    fn compact(&self, levels: &Levels, level: usize) -> Result<()> {
+        let upper = if level == 0 {
+            levels[0].clone()
+        } else {
+            levels[level][..1].to_vec()
+        };
+        let first = upper.iter().map(|table| &table.meta.first_key).min();
+        let last = upper.iter().map(|table| &table.meta.last_key).max();
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(()),
+        };
+        let lower: Vec<_> = levels
+            .get(level + 1)
+            .into_iter()
+            .flatten()
+            .filter(|table| table.meta.last_key >= *first && table.meta.first_key <= *last)
+            .cloned()
+            .collect();
+        // removed keys can be forgotten once nothing older lies below.
+        let bottom = levels[level + 1..]
+            .iter()
+            .skip(1)
+            .all(|tables| tables.is_empty());
+
+        let sources = upper
+            .iter()
+            .chain(&lower)
+            .map(|table| Box::new(TableIter::new(table, Bound::Unbounded)) as Source)
+            .collect();
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in MergeIter::new(sources)? {
+            let (key, value) = entry?;
+            if bottom && value.is_none() {
+                continue;
+            }
+            let table = match &mut builder {
+                Some(table) => table,
+                None => builder.insert(TableBuilder::create(
+                    &self.path,
+                    self.next_id(),
+                    &self.options,
+                )?),
+            };
+            table.add(&key, value.as_deref())?;
+            if table.size() >= self.options.table_size {
+                if let Some(table) = builder.take() {
+                    outputs.push(Arc::new(table.finish()?));
+                }
+            }
+        }
+        if let Some(table) = builder {
+            outputs.push(Arc::new(table.finish()?));
+        }
+
+        let obsolete: HashSet<u64> = upper.iter().chain(&lower).map(|table| table.id).collect();
+        let mut state = self.lock_state();
+        let mut levels = (*state.levels).clone();
+        if levels.len() < level + 3 {
+            levels.resize_with(level + 3, Vec::new);
+        }
+        for tables in &mut levels[level..level + 2] {
+            tables.retain(|table| !obsolete.contains(&table.id));
+        }
+        levels[level + 1].extend(outputs);
+        levels[level + 1].sort_by(|a, b| a.meta.first_key.cmp(&b.meta.first_key));
+        self.install(&mut state, levels)?;
+        drop(state);
+
+        for id in obsolete {
+            fs::remove_file(table_path(&self.path, id))?;
+        }
+        Ok(())
+    }
+
+    /// Persists `levels` in the manifest and makes them visible to reads.
+    fn install(&self, state: &mut State, levels: Levels) -> Result<()> {
+        let manifest = Manifest {
+            next_id: state.next_id,
+            levels: levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let tmp = self.path.join(format!("{MANIFEST_FILE}.tmp"));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &manifest)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.path.join(MANIFEST_FILE))?;
+        state.levels = Arc::new(levels);
+        Ok(())
+    }
+
+    fn next_id(&self) -> u64 {
+        let mut state = self.lock_state();
+        state.next_id += 1;
+        state.next_id - 1
+    }
+}
+
+impl Wal {
+    fn create(dir: &Path, id: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+/// Loads the records of a write-ahead log into `mem`, ignoring a torn last record.
+fn replay_wal(dir: &Path, id: u64, mem: &mut Memtable) -> Result<()> {
+    let reader = BufReader::new(File::open(wal_path(dir, id))?);
+    for record in Deserializer::from_reader(reader).into_iter::<Record>() {
+        match record {
+            Ok(Record::Set { key, value }) => mem.insert(key, Some(value)),
+            Ok(Record::Rm { key }) => mem.insert(key, None),
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    mem.wals.push(id);
+    Ok(())
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}.{WAL_EXT}"))
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}.{TABLE_EXT}"))
+}
+
+/// An immutable sorted table file.
+///
+/// The file holds data blocks of length-prefixed entries, followed by the
+/// JSON `TableMeta` and a footer locating it.
+struct Table {
+    id: u64,
+    file: File,
+    size: u64,
+    meta: TableMeta,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TableMeta {
+    first_key: String,
+    last_key: String,
+    blocks: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+/// Where a data block lies, and the last key it holds.
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+impl Table {
+    fn open(dir: &Path, id: u64) -> Result<Self> {
+        let file = File::open(table_path(dir, id))?;
+        let size = file.metadata()?.len();
+        let mut footer = [0; FOOTER_LEN as usize];
+        read_exact_at(&file, &mut footer, size.saturating_sub(FOOTER_LEN))?;
+        let (offset, len) = footer.split_at(8);
+        let offset = u64::from_le_bytes(offset.try_into().expect("footer is 16 bytes"));
+        let len = u64::from_le_bytes(len.try_into().expect("footer is 16 bytes"));
+
+        let mut buf = vec![0; len as usize];
+        read_exact_at(&file, &mut buf, offset)?;
+        let meta = serde_json::from_slice(&buf)?;
+        Ok(Self {
+            id,
+            file,
+            size,
+            meta,
+        })
+    }
+
+    /// Looks `key` up, returning `Some(None)` if the table records its removal.
+    fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.meta.first_key.as_str()
+            || key > self.meta.last_key.as_str()
+            || !self.meta.bloom.may_contain(hash_key(key))
+        {
+            return Ok(None);
+        }
+        let block = self
+            .meta
+            .blocks
+            .partition_point(|block| block.last_key.as_str() < key);
+        if block == self.meta.blocks.len() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Returns true if some key of the table may fall in `range`.
+    fn overlaps(&self, range: &(Bound<String>, Bound<String>)) -> bool {
+        let after_start = match &range.0 {
+            Bound::Included(start) => self.meta.last_key >= *start,
+            Bound::Excluded(start) => self.meta.last_key > *start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match &range.1 {
+            Bound::Included(end) => self.meta.first_key <= *end,
+            Bound::Excluded(end) => self.meta.first_key < *end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.meta.blocks[block];
+        let mut buf = vec![0; handle.len as usize];
+        read_exact_at(&self.file, &mut buf, handle.offset)?;
+        Ok(decode_block(&buf)?)
+    }
+}
+
+/// Writes a new table, entries being added in key order.
+struct TableBuilder {
+    dir: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    block_size: u64,
+    offset: u64,
+    block: Vec<u8>,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    first_key: Option<String>,
+    last_key: String,
+}
+
+impl TableBuilder {
+    fn create(dir: &Path, id: u64, options: &LsmOptions) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(table_path(dir, id))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            id,
+            writer: BufWriter::new(file),
+            block_size: options.block_size,
+            offset: 0,
+            block: Vec::new(),
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+            first_key: None,
+            last_key: String::new(),
+        })
+    }
+
+    fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        encode_entry(&mut self.block, key, value);
+        self.hashes.push(hash_key(key));
+        self.first_key.get_or_insert_with(|| key.to_owned());
+        key.clone_into(&mut self.last_key);
+        if self.block.len() as u64 >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let meta = TableMeta {
+            first_key: self.first_key.take().unwrap_or_default(),
+            last_key: mem::take(&mut self.last_key),
+            blocks: mem::take(&mut self.blocks),
+            bloom: BloomFilter::new(&self.hashes),
+        };
+        let buf = serde_json::to_vec(&meta)?;
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&(buf.len() as u64).to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Table::open(&self.dir, self.id)
+    }
+}
+
+fn encode_entry(buf: &mut Vec<u8>, key: &str, value: Option<&str>) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    match value {
+        Some(value) => {
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value.as_bytes());
+        }
+        None => buf.extend_from_slice(&TOMBSTONE.to_le_bytes()),
+    }
+}
+
+fn decode_block(mut buf: &[u8]) -> io::Result<Vec<Entry>> {
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+        if buf.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated table block",
+            ));
+        }
+        let (head, tail) = buf.split_at(len);
+        *buf = tail;
+        Ok(head)
+    }
+    fn take_str(buf: &mut &[u8], len: usize) -> io::Result<String> {
+        String::from_utf8(take(buf, len)?.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    fn take_len(buf: &mut &[u8]) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(
+            take(buf, 4)?.try_into().expect("took 4 bytes"),
+        ))
+    }
+
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        let len = take_len(&mut buf)?;
+        let key = take_str(&mut buf, len as usize)?;
+        let value = match take_len(&mut buf)? {
+            TOMBSTONE => None,
+            len => Some(take_str(&mut buf, len as usize)?),
+        };
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+/// A bloom filter over the keys of a table.
+#[derive(Serialize, Deserialize, Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn new(hashes: &[u64]) -> Self {
+        let words = (hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(64).max(1);
+        let mut filter = Self {
+            bits: vec![0; words],
+        };
+        for &hash in hashes {
+            for bit in filter.probes(hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    fn may_contain(&self, hash: u64) -> bool {
+        self.probes(hash)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits set for `hash`, derived from its two halves by double hashing.
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let delta = hash.rotate_left(32) | 1;
+        (0..u64::from(BLOOM_HASHES))
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % len) as usize)
+    }
+}
+
+fn hash_key(key: &str) -> u64 {
+    fnv1a(key.as_bytes())
+}
+
+/// FNV-1a, stable across builds so it can be persisted.
+pub(super) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Iterates over the entries of a table, one block at a time.
+struct TableIter {
+    table: Arc<Table>,
+    block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    /// Starts at the block holding `start`, which may also hold smaller keys.
+    fn new(table: &Arc<Table>, start: Bound<&String>) -> Self {
+        let block = match start {
+            Bound::Included(start) | Bound::Excluded(start) => table
+                .meta
+                .blocks
+                .partition_point(|block| block.last_key < *start),
+            Bound::Unbounded => 0,
+        };
+        Self {
+            table: Arc::clone(table),
+            block,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.block == self.table.meta.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.block += 1;
+                }
+                Err(e) => {
+                    self.block = self.table.meta.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Merges sorted sources into one sorted sequence of distinct keys.
+///
+/// When several sources hold a key, the entry of the first one wins, so
+/// sources are given newest first.
+struct MergeIter {
+    sources: Vec<Source>,
+    heads: Vec<Option<Entry>>,
+}
+
+impl MergeIter {
+    fn new(mut sources: Vec<Source>) -> Result<Self> {
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<_>>()?;
+        Ok(Self { sources, heads })
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .heads
+            .iter()
+            .flatten()
+            .map(|(key, _)| key)
+            .min()?
+            .clone();
+        let mut winner = None;
+        for (head, source) in self.heads.iter_mut().zip(&mut self.sources) {
+            if head.as_ref().is_some_and(|(k, _)| *k == key) {
+                let next = match source.next().transpose() {
+                    Ok(next) => next,
+                    Err(e) => return Some(Err(e)),
+                };
+                let entry = mem::replace(head, next);
+                winner = winner.or(entry);
+            }
+        }
+        winner.map(Ok)
+    }
+}
+
+impl KvsEngine for LsmStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut wal = self.lock_wal()?;
+        self.write(&mut wal, key, Some(value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let levels = {
+            let state = self.shared.lock_state();
+            if let Some(value) = state.mem.entries.get(&key) {
+                return Ok(value.clone());
+            }
+            if let Some(value) = state.imm.as_ref().and_then(|imm| imm.entries.get(&key)) {
+                return Ok(value.clone());
+            }
+            Arc::clone(&state.levels)
+        };
+
+        for table in &levels[0] {
+            if let Some(value) = table.get(&key)? {
+                return Ok(value);
+            }
+        }
+        for tables in &levels[1..] {
+            let table = tables.partition_point(|table| table.meta.last_key < key);
+            if let Some(table) = tables.get(table) {
+                if let Some(value) = table.get(&key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.take(key).map(drop)
+    }
+
+    fn swap(&self, key: String, value: String) -> Result<Option<String>> {
+        let mut wal = self.lock_wal()?;
+        let old = self.get(key.clone())?;
+        self.write(&mut wal, key, Some(value))?;
+        Ok(old)
+    }
+
+    fn take(&self, key: String) -> Result<String> {
+        let mut wal = self.lock_wal()?;
+        // writes are serialized by the wal lock, so the key can't come back in between.
+        let value = self.get(key.clone())?.ok_or(KvsError::KeyNotFound)?;
+        self.write(&mut wal, key, None)?;
+        Ok(value)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut wal = self.lock_wal()?;
+        wal.writer.flush()?;
+        wal.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let mut wal = self.lock_wal()?;
+        let value = increment(self.get(key.clone())?.as_deref(), delta)?;
+        self.write(&mut wal, key, Some(value.to_string()))?;
+        Ok(value)
+    }
+
+    /// Tables are read lazily, so only the blocks up to the last pair returned
+    /// are; the memtables in the range are copied whole.
+    fn scan_limit(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (start, end);
+        if empty_range(&range) || limit == 0 {
+            return Ok(Vec::new());
+        }
+        let mut sources: Vec<Source> = Vec::new();
+        let levels = {
+            let state = self.shared.lock_state();
+            for mem in std::iter::once(&state.mem).chain(state.imm.as_deref()) {
+                let entries: Vec<_> = mem
+                    .entries
+                    .range::<String, _>((range.start_bound(), range.end_bound()))
+                    .map(|(key, value)| Ok((key.clone(), value.clone())))
+                    .collect();
+                sources.push(Box::new(entries.into_iter()));
+            }
+            Arc::clone(&state.levels)
+        };
+        for table in levels[0].iter().filter(|table| table.overlaps(&range)) {
+            sources.push(Box::new(TableIter::new(table, range.start_bound())));
+        }
+        for tables in &levels[1..] {
+            // tables of a level don't overlap, so they form one sorted run.
+            let start = range.0.clone();
+            let run: Vec<_> = tables
+                .iter()
+                .filter(|table| table.overlaps(&range))
+                .cloned()
+                .collect();
+            sources
+                .push(Box::new(run.into_iter().flat_map(move |table| {
+                    TableIter::new(&table, start.as_ref())
+                })));
+        }
+
+        let mut entries = Vec::new();
+        for entry in MergeIter::new(sources)? {
+            let (key, value) = entry?;
+            let past_end = match &range.1 {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                break;
+            }
+            if let Some(value) = value.filter(|_| range.contains(&key)) {
+                entries.push((key, value));
+                if entries.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn list_push(&self, _key: String, _end: ListEnd, _value: String) -> Result<u64> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn list_pop(&self, _key: String, _end: ListEnd) -> Result<Option<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn list_range(&self, _key: String, _start: i64, _stop: i64) -> Result<Vec<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn hash_set(&self, _key: String, _field: String, _value: String) -> Result<()> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn hash_get(&self, _key: String, _field: String) -> Result<Option<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn hash_remove(&self, _key: String, _field: String) -> Result<bool> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn set_add(&self, _key: String, _member: String) -> Result<bool> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn set_remove(&self, _key: String, _member: String) -> Result<bool> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn set_members(&self, _key: String) -> Result<Vec<String>> {
+        Err(KvsError::CollectionsUnsupported(EngineKind::Lsm))
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    fn create_namespace(&self, _name: String) -> Result<()> {
+        Err(KvsError::NamespacesUnsupported(EngineKind::Lsm))
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn drop_namespace(&self, _name: String) -> Result<()> {
+        Err(KvsError::NamespacesUnsupported(EngineKind::Lsm))
+    }
+
+    fn namespace(&self, _name: String) -> Result<Box<dyn KvsEngine + '_>> {
+        Err(KvsError::NamespacesUnsupported(EngineKind::Lsm))
+    }
+}
@@ -1,29 +1,388 @@
 //! This module provides various key value storage engines.
 
-use crate::Result;
+use std::{
+    fmt, fs, io,
+    ops::{Bound, Range, RangeBounds},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
 
 /// Trait for a key value storage engine.
-pub trait KvsEngine {
+///
+/// Engines synchronize internally, so one engine can be shared between threads.
+pub trait KvsEngine: Send + Sync {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()>;
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>>;
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Sets the value of a string key like `set`, returning the value it replaced.
+    ///
+    /// The read and the write happen atomically.
+    fn swap(&self, key: String, value: String) -> Result<Option<String>>;
+
+    /// Removes a given key like `remove`, returning its value.
+    ///
+    /// The read and the write happen atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and
+    /// `KvsError::WrongType` if it holds a collection.
+    fn take(&self, key: String) -> Result<String>;
+
+    /// Gets the values of many keys, in the order of `keys`.
+    ///
+    /// Keys holding collections read as `None`.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter()
+            .map(|key| match self.get(key) {
+                Err(KvsError::WrongType) => Ok(None),
+                value => value,
+            })
+            .collect()
+    }
+
+    /// Sets many keys, in order.
+    ///
+    /// Engines supporting batches apply them atomically, others one at a time.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        pairs
+            .into_iter()
+            .try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    /// Removes many keys like `remove`, returning how many there were.
+    ///
+    /// Missing keys are skipped.
+    fn remove_many(&self, keys: Vec<String>) -> Result<u64> {
+        let mut removed = 0;
+        for key in keys {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Writes buffered changes through and syncs them to the disk.
+    fn flush(&self) -> Result<()>;
+
+    /// Returns the key-value pairs whose keys fall between `start` and `end`, in key order.
+    ///
+    /// An empty or inverted range returns nothing.
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        self.scan_limit(start, end, usize::MAX)
+    }
+
+    /// Returns the first `limit` pairs `scan` would, reading no further.
+    fn scan_limit(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+
+    /// Adds `delta` to the integer value of a key, returning the new value.
+    ///
+    /// A missing key counts as 0. The read and the write happen atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value isn't a 64-bit signed
+    /// integer, and `KvsError::IncrementOverflow` if the result isn't either.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Pushes `value` to an end of the list `key`, returning the new length.
+    ///
+    /// A missing key is created as an empty list first.
+    ///
+    /// # Errors
+    ///
+    /// The collection methods return `KvsError::WrongType` if the key holds a
+    /// string or another kind of collection. Collections are removed with
+    /// `remove`, and when their last element is.
+    fn list_push(&self, key: String, end: ListEnd, value: String) -> Result<u64>;
+
+    /// Pops the value at an end of the list `key`, returning `None` if there is no such key.
+    fn list_pop(&self, key: String, end: ListEnd) -> Result<Option<String>>;
+
+    /// Returns the values of the list `key` between the indexes `start` and
+    /// `stop`, both included.
+    ///
+    /// Negative indexes count from the end of the list, -1 being the last value.
+    fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>>;
+
+    /// Sets `field` of the hash `key` to `value`.
+    fn hash_set(&self, key: String, field: String, value: String) -> Result<()>;
+
+    /// Gets `field` of the hash `key`.
+    fn hash_get(&self, key: String, field: String) -> Result<Option<String>>;
+
+    /// Removes `field` of the hash `key`, returning false if there was no such field.
+    fn hash_remove(&self, key: String, field: String) -> Result<bool>;
+
+    /// Adds `member` to the set `key`, returning false if it was already there.
+    fn set_add(&self, key: String, member: String) -> Result<bool>;
+
+    /// Removes `member` from the set `key`, returning false if it wasn't there.
+    fn set_remove(&self, key: String, member: String) -> Result<bool>;
+
+    /// Returns the members of the set `key`, in order.
+    fn set_members(&self, key: String) -> Result<Vec<String>>;
+
+    /// Subscribes to the changes of keys starting with `prefix`.
+    ///
+    /// The watcher receives every set and remove of a string committed after
+    /// the call, in commit order. It buffers a bounded number of events; a watcher that
+    /// falls behind has events dropped, and receives `WatchEvent::Lagged`
+    /// where they were, so writers never wait for it.
+    fn watch(&self, prefix: String) -> Result<Watcher>;
+
+    /// Creates the namespace `name`, an empty keyspace apart from the default
+    /// one and from every other namespace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceExists` if the namespace already exists.
+    fn create_namespace(&self, name: String) -> Result<()>;
+
+    /// Returns the names of the namespaces, in order.
+    fn list_namespaces(&self) -> Result<Vec<String>>;
+
+    /// Drops the namespace `name` along with every key in it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if there is no such namespace.
+    fn drop_namespace(&self, name: String) -> Result<()>;
+
+    /// Returns the namespace `name`, with the same operations as the engine.
+    ///
+    /// Namespaces don't nest: the namespace methods of the returned engine
+    /// act on the namespaces of this one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if there is no such namespace.
+    fn namespace(&self, name: String) -> Result<Box<dyn KvsEngine + '_>>;
+}
+
+/// An end of a list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ListEnd {
+    /// The first value.
+    Front,
+    /// The last value.
+    Back,
+}
+
+/// Returns the positions of a list of `len` values between the indexes
+/// `start` and `stop` of `KvsEngine::list_range`.
+fn list_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start.min(len)
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    } + 1;
+    start as usize..stop.max(start) as usize
+}
+
+/// Returns true if no key can fall in `range`, including inverted ranges,
+/// which `BTreeMap::range` panics on.
+fn empty_range<R: RangeBounds<String>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
 }
 
+/// Returns the integer `value` plus `delta`, a missing value counting as 0.
+fn increment(value: Option<&str>, delta: i64) -> Result<i64> {
+    let value = match value {
+        Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+        None => 0,
+    };
+    value.checked_add(delta).ok_or(KvsError::IncrementOverflow)
+}
+
+mod btree;
+mod cache;
+mod cipher;
 mod kvs;
+mod lsm;
 mod sled;
+mod watch;
 
-pub use self::kvs::KvStore;
+pub use self::btree::{BTreeOptions, BTreeStore};
+pub use self::cipher::{ChaChaCipher, Cipher, Keyring};
+pub use self::kvs::{
+    IndexMode, KvStore, KvStoreOptions, KvStoreStats, RepairReport, Retention, Version,
+};
+pub use self::lsm::{LsmOptions, LsmStore};
 pub use self::sled::SledKvsEngine;
+pub use self::watch::{WatchEvent, Watcher};
+
+/// Name of the marker file recording which engine owns a directory.
+const ENGINE_FILE: &str = "engine";
+
+/// The storage engines a directory can be opened with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EngineKind {
+    /// `KvStore`, the built-in log-structured engine.
+    Kvs,
+    /// `SledKvsEngine`, backed by the sled database.
+    Sled,
+    /// `LsmStore`, the built-in log-structured merge-tree engine.
+    Lsm,
+    /// `BTreeStore`, the built-in page-based B+tree engine.
+    #[value(name = "btree")]
+    BTree,
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => write!(f, "kvs"),
+            EngineKind::Sled => write!(f, "sled"),
+            EngineKind::Lsm => write!(f, "lsm"),
+            EngineKind::BTree => write!(f, "btree"),
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            "lsm" => Ok(EngineKind::Lsm),
+            "btree" => Ok(EngineKind::BTree),
+            _ => Err(KvsError::UnknownEngine(s.to_owned())),
+        }
+    }
+}
+
+/// Opens the store in `dir` with the given engine.
+///
+/// The engine that created the directory is detected from the `engine` marker
+/// file, or from the files it holds if there is no marker yet. The marker is
+/// written once the store is opened.
+///
+/// # Errors
+///
+/// It returns `KvsError::WrongEngine` if `dir` belongs to another engine.
+pub fn open_engine(dir: impl AsRef<Path>, kind: EngineKind) -> Result<Box<dyn KvsEngine>> {
+    open_engine_with_options(dir, kind, &KvStoreOptions::default())
+}
+
+/// Opens the store in `dir` like `open_engine`, passing `options` to `KvStore`.
+///
+/// # Errors
+///
+/// It returns `KvsError::EncryptionUnsupported` if `options` holds keys but
+/// the engine isn't `EngineKind::Kvs`.
+pub fn open_engine_with_options(
+    dir: impl AsRef<Path>,
+    kind: EngineKind,
+    options: &KvStoreOptions,
+) -> Result<Box<dyn KvsEngine>> {
+    if kind != EngineKind::Kvs && !options.keyring.is_empty() {
+        return Err(KvsError::EncryptionUnsupported(kind));
+    }
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    check_engine(dir, kind)?;
+
+    let engine: Box<dyn KvsEngine> = match kind {
+        EngineKind::Kvs => Box::new(KvStore::open_with_options(dir, options)?),
+        EngineKind::Sled => Box::new(SledKvsEngine::new(::sled::open(dir)?)),
+        EngineKind::Lsm => Box::new(LsmStore::open(dir)?),
+        EngineKind::BTree => Box::new(BTreeStore::open(dir)?),
+    };
+    let marker = dir.join(ENGINE_FILE);
+    if !marker.exists() {
+        fs::write(marker, kind.to_string())?;
+    }
+    Ok(engine)
+}
+
+/// Checks that `dir` is empty or belongs to the given engine.
+///
+/// # Errors
+///
+/// It returns `KvsError::WrongEngine` if `dir` belongs to another engine.
+pub fn check_engine(dir: impl AsRef<Path>, kind: EngineKind) -> Result<()> {
+    match detect_engine(dir.as_ref())? {
+        Some(found) if found != kind => Err(KvsError::WrongEngine {
+            expected: kind,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the engine that owns `dir`, if any.
+fn detect_engine(dir: &Path) -> Result<Option<EngineKind>> {
+    match fs::read_to_string(dir.join(ENGINE_FILE)) {
+        Ok(name) => return name.trim().parse().map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if path.extension() == Some("x".as_ref()) {
+            return Ok(Some(EngineKind::Kvs));
+        }
+        if matches!(name, "conf" | "db" | "blobs") || name.starts_with("snap.") {
+            return Ok(Some(EngineKind::Sled));
+        }
+        if name == "MANIFEST"
+            || matches!(path.extension(), Some(ext) if ext == "sst" || ext == "wal")
+        {
+            return Ok(Some(EngineKind::Lsm));
+        }
+        if path.extension() == Some("btree".as_ref()) {
+            return Ok(Some(EngineKind::BTree));
+        }
+    }
+    Ok(None)
+}
@@ -1,39 +1,431 @@
-use super::KvsEngine;
+use std::{
+    ops::Bound,
+    sync::{mpsc::RecvTimeoutError, Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use super::{
+    empty_range, increment, list_range,
+    watch::{self, WatchEvent, Watcher},
+    KvsEngine, ListEnd,
+};
 use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use sled::{Batch, Db, IVec, Tree};
+
+/// First byte of the keys holding collection elements. It never starts a
+/// UTF-8 string, so these keys can't collide with string keys.
+const ELEMENT: u8 = 0xff;
+
+// Kinds of collections, stored after the collection key in element keys.
+const LIST: u8 = b'l';
+const HASH: u8 = b'h';
+const SET: u8 = b's';
 
 /// Wrapper of `sled::Db`
+///
+/// Namespaces are sled trees of the same name. Each element of a collection
+/// is stored under its own key, apart from string keys.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    // The default tree, or the tree of a namespace.
+    tree: Tree,
+    // Serializes collection updates, which read the collection before writing
+    // it, and string writes, which check that the key holds no collection.
+    collections: Arc<Mutex<()>>,
+}
 
 impl SledKvsEngine {
     /// Creates a `SledKvsEngine` from `sled::Db`.
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        let tree = Tree::clone(&db);
+        SledKvsEngine {
+            db,
+            tree,
+            collections: Arc::default(),
+        }
+    }
+
+    fn lock_collections(&self) -> MutexGuard<'_, ()> {
+        self.collections.lock().expect("collections lock poisoned")
+    }
+
+    /// Returns the kind of the collection `key`, if there is one.
+    fn collection_kind(&self, key: &str) -> Result<Option<u8>> {
+        let prefix = element_prefix(key);
+        let first = self.tree.scan_prefix(&prefix).keys().next().transpose()?;
+        Ok(first.map(|element| element[prefix.len()]))
+    }
+
+    /// Checks that `key` holds no string, nor a collection of another kind.
+    fn check_kind(&self, key: &str, kind: u8) -> Result<()> {
+        if self.tree.contains_key(key)? {
+            return Err(KvsError::WrongType);
+        }
+        match self.collection_kind(key)? {
+            Some(found) if found != kind => Err(KvsError::WrongType),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the list `key` as pairs of element key and value.
+    fn list(&self, key: &str) -> impl DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>> {
+        self.tree.scan_prefix(element_key(key, LIST, &[]))
+    }
+
+    /// Returns true if `name` is a namespace, which the default tree isn't.
+    fn has_namespace(&self, name: &str) -> bool {
+        name.as_bytes() != &*self.db.name()
+            && self
+                .db
+                .tree_names()
+                .iter()
+                .any(|tree| tree == name.as_bytes())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.swap(key, value).map(drop)
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let tree = &self.tree;
+        let value = tree
+            .get(&key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
             .map(String::from_utf8)
-            .transpose()?)
+            .transpose()?;
+        // a key holds either a string or a collection.
+        if value.is_none() && self.collection_kind(&key)?.is_some() {
+            return Err(KvsError::WrongType);
+        }
+        Ok(value)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+    /// Removes a collection with all its elements.
+    fn remove(&self, key: String) -> Result<()> {
+        let tree = &self.tree;
+        if tree.remove(&key)?.is_none() {
+            let _lock = self.lock_collections();
+            let mut batch = Batch::default();
+            let mut found = false;
+            for element in tree.scan_prefix(element_prefix(&key)).keys() {
+                batch.remove(element?);
+                found = true;
+            }
+            if !found {
+                return Err(KvsError::KeyNotFound);
+            }
+            tree.apply_batch(batch)?;
+        }
         tree.flush()?;
         Ok(())
     }
+
+    fn swap(&self, key: String, value: String) -> Result<Option<String>> {
+        let tree = &self.tree;
+        let old = {
+            let _lock = self.lock_collections();
+            if self.collection_kind(&key)?.is_some() {
+                return Err(KvsError::WrongType);
+            }
+            tree.insert(key, value.into_bytes())?
+        };
+        tree.flush()?;
+        Ok(old.map(|old| String::from_utf8(old.to_vec())).transpose()?)
+    }
+
+    /// Applies the pairs as one sled batch.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let tree = &self.tree;
+        let mut batch = Batch::default();
+        let lock = self.lock_collections();
+        for (key, value) in pairs {
+            if self.collection_kind(&key)?.is_some() {
+                return Err(KvsError::WrongType);
+            }
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        tree.apply_batch(batch)?;
+        drop(lock);
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn take(&self, key: String) -> Result<String> {
+        let tree = &self.tree;
+        match tree.remove(&key)? {
+            Some(value) => {
+                tree.flush()?;
+                Ok(String::from_utf8(value.to_vec())?)
+            }
+            None if self.collection_kind(&key)?.is_some() => Err(KvsError::WrongType),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Runs in `update_and_fetch`, which retries the update until no other
+    /// write gets in between.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let lock = self.lock_collections();
+        if self.collection_kind(&key)?.is_some() {
+            return Err(KvsError::WrongType);
+        }
+        let mut result = Ok(0);
+        self.tree.update_and_fetch(key, |old| {
+            result = old
+                .map(std::str::from_utf8)
+                .transpose()
+                .map_err(|_| KvsError::NotAnInteger)
+                .and_then(|old| increment(old, delta));
+            match &result {
+                Ok(value) => Some(value.to_string().into_bytes()),
+                // leave the value as it is.
+                Err(_) => old.map(<[u8]>::to_vec),
+            }
+        })?;
+        drop(lock);
+        self.tree.flush()?;
+        result
+    }
+
+    fn scan_limit(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (start, end);
+        if empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        self.tree
+            .range(range)
+            .filter(|entry| !matches!(entry, Ok((key, _)) if key.first() == Some(&ELEMENT)))
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn list_push(&self, key: String, end: ListEnd, value: String) -> Result<u64> {
+        let _lock = self.lock_collections();
+        self.check_kind(&key, LIST)?;
+        let index = match end {
+            ListEnd::Front => self.list(&key).next().transpose()?,
+            ListEnd::Back => self.list(&key).next_back().transpose()?,
+        }
+        .map_or(0, |(element, _)| {
+            let index = list_index(&element);
+            match end {
+                ListEnd::Front => index - 1,
+                ListEnd::Back => index + 1,
+            }
+        });
+        self.tree.insert(
+            element_key(&key, LIST, &encode_index(index)),
+            value.into_bytes(),
+        )?;
+        self.tree.flush()?;
+        Ok(self.list(&key).count() as u64)
+    }
+
+    fn list_pop(&self, key: String, end: ListEnd) -> Result<Option<String>> {
+        let _lock = self.lock_collections();
+        self.check_kind(&key, LIST)?;
+        let element = match end {
+            ListEnd::Front => self.list(&key).next().transpose()?,
+            ListEnd::Back => self.list(&key).next_back().transpose()?,
+        };
+        match element {
+            Some((element, value)) => {
+                self.tree.remove(element)?;
+                self.tree.flush()?;
+                Ok(Some(String::from_utf8(value.to_vec())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.check_kind(&key, LIST)?;
+        let range = list_range(self.list(&key).count(), start, stop);
+        self.list(&key)
+            .skip(range.start)
+            .take(range.len())
+            .map(|entry| Ok(String::from_utf8(entry?.1.to_vec())?))
+            .collect()
+    }
+
+    fn hash_set(&self, key: String, field: String, value: String) -> Result<()> {
+        let _lock = self.lock_collections();
+        self.check_kind(&key, HASH)?;
+        self.tree.insert(
+            element_key(&key, HASH, field.as_bytes()),
+            value.into_bytes(),
+        )?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn hash_get(&self, key: String, field: String) -> Result<Option<String>> {
+        self.check_kind(&key, HASH)?;
+        Ok(self
+            .tree
+            .get(element_key(&key, HASH, field.as_bytes()))?
+            .map(|value| String::from_utf8(value.to_vec()))
+            .transpose()?)
+    }
+
+    fn hash_remove(&self, key: String, field: String) -> Result<bool> {
+        let _lock = self.lock_collections();
+        self.check_kind(&key, HASH)?;
+        let removed = self
+            .tree
+            .remove(element_key(&key, HASH, field.as_bytes()))?
+            .is_some();
+        self.tree.flush()?;
+        Ok(removed)
+    }
+
+    fn set_add(&self, key: String, member: String) -> Result<bool> {
+        let _lock = self.lock_collections();
+        self.check_kind(&key, SET)?;
+        let added = self
+            .tree
+            .insert(element_key(&key, SET, member.as_bytes()), &[])?
+            .is_none();
+        self.tree.flush()?;
+        Ok(added)
+    }
+
+    fn set_remove(&self, key: String, member: String) -> Result<bool> {
+        let _lock = self.lock_collections();
+        self.check_kind(&key, SET)?;
+        let removed = self
+            .tree
+            .remove(element_key(&key, SET, member.as_bytes()))?
+            .is_some();
+        self.tree.flush()?;
+        Ok(removed)
+    }
+
+    fn set_members(&self, key: String) -> Result<Vec<String>> {
+        self.check_kind(&key, SET)?;
+        let prefix = element_key(&key, SET, &[]);
+        self.tree
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|element| Ok(String::from_utf8(element?[prefix.len()..].to_vec())?))
+            .collect()
+    }
+
+    /// Forwards sled's `watch_prefix` subscriber from a thread, which ends
+    /// shortly after the watcher is dropped.
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let mut subscriber = self.tree.watch_prefix(prefix);
+        let (mut feed, watcher) = watch::channel();
+        thread::spawn(move || {
+            while feed.is_open() {
+                let event = match subscriber.next_timeout(Duration::from_millis(100)) {
+                    Ok(event) if event.key().first() == Some(&ELEMENT) => continue,
+                    Ok(sled::Event::Insert { key, value }) => WatchEvent::Set {
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                        value: String::from_utf8_lossy(&value).into_owned(),
+                    },
+                    Ok(sled::Event::Remove { key }) => WatchEvent::Remove {
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                    },
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if !feed.send(event) {
+                    break;
+                }
+            }
+        });
+        Ok(watcher)
+    }
+
+    fn create_namespace(&self, name: String) -> Result<()> {
+        if self.has_namespace(&name) || name.as_bytes() == &*self.db.name() {
+            return Err(KvsError::NamespaceExists(name));
+        }
+        self.db.open_tree(name)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let default = self.db.name();
+        let mut names = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(|name| *name != default)
+            .map(|name| Ok(String::from_utf8(name.to_vec())?))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn drop_namespace(&self, name: String) -> Result<()> {
+        if !self.has_namespace(&name) {
+            return Err(KvsError::NamespaceNotFound(name));
+        }
+        self.db.drop_tree(name)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn namespace(&self, name: String) -> Result<Box<dyn KvsEngine + '_>> {
+        if !self.has_namespace(&name) {
+            return Err(KvsError::NamespaceNotFound(name));
+        }
+        Ok(Box::new(SledKvsEngine {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+            collections: Arc::clone(&self.collections),
+        }))
+    }
+}
+
+/// Returns the prefix of the keys of the elements of the collection `key`.
+fn element_prefix(key: &str) -> Vec<u8> {
+    let mut prefix = vec![ELEMENT];
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key.as_bytes());
+    prefix
+}
+
+/// Returns the key of an element of the collection `key` of the given kind.
+fn element_key(key: &str, kind: u8, element: &[u8]) -> Vec<u8> {
+    let mut element_key = element_prefix(key);
+    element_key.push(kind);
+    element_key.extend_from_slice(element);
+    element_key
+}
+
+/// Encodes a list index so that indexes sort like their encodings.
+fn encode_index(index: i64) -> [u8; 8] {
+    ((index as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Returns the index of a list element from its key.
+fn list_index(element: &[u8]) -> i64 {
+    let mut index = [0; 8];
+    index.copy_from_slice(&element[element.len() - 8..]);
+    (u64::from_be_bytes(index) ^ (1 << 63)) as i64
 }
@@ -0,0 +1,170 @@
+use std::{
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::Duration,
+};
+
+/// Number of events a watcher buffers before it starts lagging.
+const WATCH_BUFFER: usize = 1024;
+
+/// A change delivered to a `Watcher`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The key was set to the value.
+    Set { key: String, value: String },
+    /// The key was removed.
+    Remove { key: String },
+    /// The buffer of the watcher was full, and this many events were dropped
+    /// at this point of the feed.
+    Lagged { missed: u64 },
+}
+
+/// Receives the events of a `KvsEngine::watch` subscription, in commit order.
+///
+/// Dropping the watcher ends the subscription.
+pub struct Watcher {
+    rx: Receiver<WatchEvent>,
+    // Lets a feed notice the watcher is gone without sending to it.
+    _alive: Arc<()>,
+}
+
+impl Watcher {
+    /// Blocks until the next event, returning `None` once the store is dropped.
+    pub fn recv(&self) -> Option<WatchEvent> {
+        self.rx.recv().ok()
+    }
+
+    /// Returns the next event if one is buffered.
+    pub fn try_recv(&self) -> Result<WatchEvent, TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    /// Waits at most `timeout` for the next event.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<WatchEvent, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.recv()
+    }
+}
+
+/// The sending end of a watcher.
+pub(super) struct Feed {
+    tx: SyncSender<WatchEvent>,
+    alive: Weak<()>,
+    // Events dropped since the last one delivered.
+    missed: u64,
+}
+
+/// Creates a watcher and the feed sending to it.
+pub(super) fn channel() -> (Feed, Watcher) {
+    let (tx, rx) = sync_channel(WATCH_BUFFER);
+    let alive = Arc::new(());
+    let feed = Feed {
+        tx,
+        alive: Arc::downgrade(&alive),
+        missed: 0,
+    };
+    (feed, Watcher { rx, _alive: alive })
+}
+
+impl Feed {
+    /// Returns false once the watcher is dropped.
+    pub(super) fn is_open(&self) -> bool {
+        self.alive.strong_count() > 0
+    }
+
+    /// Sends `event` without blocking, dropping it if the buffer is full.
+    ///
+    /// The watcher is told how many events it missed before the next one it
+    /// gets. Returns false once the watcher is dropped.
+    pub(super) fn send(&mut self, event: WatchEvent) -> bool {
+        if self.missed > 0 {
+            let lagged = WatchEvent::Lagged {
+                missed: self.missed,
+            };
+            match self.tx.try_send(lagged) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        match self.tx.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed = 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// The watchers of a store, fed from its write path.
+#[derive(Default)]
+pub(super) struct WatchHub {
+    feeds: Mutex<Vec<(String, Feed)>>,
+}
+
+/// The feeds of a `WatchHub`, locked by a write from before it decides what to
+/// publish until it publishes, so that no watcher subscribes in between.
+pub(super) struct Feeds<'a>(MutexGuard<'a, Vec<(String, Feed)>>);
+
+impl WatchHub {
+    pub(super) fn lock(&self) -> Feeds<'_> {
+        Feeds(self.feeds.lock().expect("watchers lock poisoned"))
+    }
+
+    /// Subscribes to the changes of keys starting with `prefix`.
+    pub(super) fn subscribe(&self, prefix: String) -> Watcher {
+        let (feed, watcher) = channel();
+        self.lock().0.push((prefix, feed));
+        watcher
+    }
+
+    /// Sends a change of `key` like `Feeds::publish`.
+    pub(super) fn publish(&self, key: &str, value: Option<&str>) {
+        self.lock().publish(key, value);
+    }
+}
+
+impl Feeds<'_> {
+    /// Returns true if some watcher wants the changes of `key`.
+    pub(super) fn watches(&self, key: &str) -> bool {
+        self.0
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    /// Sends a change of `key` to every watcher of a matching prefix, `None`
+    /// meaning the key was removed.
+    ///
+    /// Callers publish while holding their write lock, so events go out in
+    /// commit order.
+    pub(super) fn publish(&mut self, key: &str, value: Option<&str>) {
+        self.0.retain_mut(|(prefix, feed)| {
+            if !key.starts_with(prefix.as_str()) {
+                return feed.is_open();
+            }
+            feed.send(match value {
+                Some(value) => WatchEvent::Set {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                },
+                None => WatchEvent::Remove {
+                    key: key.to_owned(),
+                },
+            })
+        });
+    }
+}
@@ -1,3 +1,4 @@
+use crate::EngineKind;
 use std::io;
 use thiserror::Error;
 
@@ -11,6 +12,18 @@ pub enum KvsError {
     #[error("{0}")]
     Serde(#[from] serde_json::Error),
 
+    /// Binary encoding or decoding error.
+    #[error("{0}")]
+    Bincode(#[from] bincode::Error),
+
+    /// A frame of the binary protocol is larger than accepted.
+    #[error("frame of {0} bytes is too large")]
+    FrameTooLarge(u64),
+
+    /// The server refused the handshake of the binary protocol.
+    #[error("handshake failed: {0}")]
+    Handshake(String),
+
     /// Removing non-existent key error.
     #[error("Key not found")]
     KeyNotFound,
@@ -28,6 +41,92 @@ pub enum KvsError {
 
     #[error("{0}")]
     StringError(String),
+
+    /// The store directory is locked by another process.
+    #[error("store at {} is locked by another process", .0.display())]
+    Locked(std::path::PathBuf),
+
+    /// Writing to a store opened read-only.
+    #[error("store is opened read-only")]
+    ReadOnly,
+
+    /// The directory was created by another engine.
+    #[error("directory holds a {found} store, it cannot be opened with {expected}")]
+    WrongEngine {
+        /// The engine that was asked for.
+        expected: EngineKind,
+        /// The engine that owns the directory.
+        found: EngineKind,
+    },
+
+    /// A value stored apart from the log points at a blob file that doesn't exist.
+    #[error("blob file {0} is missing")]
+    MissingBlob(u64),
+
+    /// The background thread of an engine failed, the store no longer accepts writes.
+    #[error("background flush or compaction failed: {0}")]
+    Background(String),
+
+    /// The key is longer than the engine accepts.
+    #[error("key of {0} bytes is too long")]
+    KeyTooLarge(usize),
+
+    /// A record is sealed with a key missing from the keyring.
+    #[error("record is encrypted with unknown key {0}")]
+    MissingKey(u32),
+
+    /// A sealed record failed authentication.
+    #[error("record failed to decrypt")]
+    Decryption,
+
+    /// A line of a key file is not `<id> <64 hex digits>`.
+    #[error("invalid key on line {0} of the key file")]
+    InvalidKeyFile(usize),
+
+    /// Encryption was asked of an engine that doesn't support it.
+    #[error("the {0} engine does not support encryption")]
+    EncryptionUnsupported(EngineKind),
+
+    /// Incrementing a value that isn't an integer.
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+
+    /// An increment would take the value out of the range of `i64`.
+    #[error("increment or decrement would overflow")]
+    IncrementOverflow,
+
+    /// A collection command on a key holding a string or another kind of
+    /// collection, or a string command on a collection.
+    #[error("operation against a key holding the wrong kind of value")]
+    WrongType,
+
+    /// Collections were asked of an engine that doesn't support them.
+    #[error("the {0} engine does not support collections")]
+    CollectionsUnsupported(EngineKind),
+
+    /// Creating a namespace that already exists.
+    #[error("namespace {0} already exists")]
+    NamespaceExists(String),
+
+    /// Using a namespace that doesn't exist.
+    #[error("namespace {0} not found")]
+    NamespaceNotFound(String),
+
+    /// Namespaces were asked of an engine that doesn't support them.
+    #[error("the {0} engine does not support namespaces")]
+    NamespacesUnsupported(EngineKind),
+
+    /// No pooled connection was free before the checkout timeout.
+    #[error("timed out waiting for a pooled connection")]
+    PoolTimeout,
+
+    /// The server didn't answer a request in time.
+    #[error("timed out waiting for the server")]
+    ServerTimeout,
+
+    /// Unrecognized engine name.
+    #[error("unknown engine {0}")]
+    UnknownEngine(String),
 }
 
 // impl From<io::Error> for KvsError {
@@ -5,10 +5,19 @@ mod client;
 mod common;
 mod engines;
 mod error;
+mod pool;
+mod resp;
 mod server;
+mod thread_pool;
 
-pub use client::Client;
+pub use client::{Client, Pipeline};
 pub use common::*;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    check_engine, open_engine, open_engine_with_options, BTreeOptions, BTreeStore, ChaChaCipher,
+    Cipher, EngineKind, IndexMode, Keyring, KvStore, KvStoreOptions, KvStoreStats, KvsEngine,
+    ListEnd, LsmOptions, LsmStore, RepairReport, Retention, SledKvsEngine, Version, WatchEvent,
+    Watcher,
+};
 pub use error::{KvsError, Result};
-pub use server::Server;
+pub use pool::{ClientPool, PoolOptions, PooledClient};
+pub use server::{Protocol, Server, ServerHandle, ShutdownHandle};
@@ -0,0 +1,219 @@
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{error::Result, Client, KvsError};
+
+/// Options for a `ClientPool`.
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// Number of connections opened up front, and kept however long they idle.
+    pub min_size: usize,
+    /// Number of connections the pool opens at most.
+    pub max_size: usize,
+    /// How long a connection beyond `min_size` stays idle before it is closed.
+    pub idle_timeout: Duration,
+    /// How long a checkout waits for a connection when all are in use.
+    pub checkout_timeout: Duration,
+    /// How long a connection idles before a checkout pings it, to replace it
+    /// if the server stopped answering.
+    pub health_check_after: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 1,
+            max_size: 16,
+            idle_timeout: Duration::from_secs(60),
+            checkout_timeout: Duration::from_secs(5),
+            health_check_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A pool of connections to a server, shared between threads.
+///
+/// Connections found closed, or failing a ping after idling, are dropped at
+/// checkout and replaced by new ones. The ping waits no longer than what is
+/// left of the checkout timeout.
+pub struct ClientPool {
+    addr: SocketAddr,
+    options: PoolOptions,
+    state: Mutex<PoolState>,
+    // Signaled when a connection is checked in or dropped.
+    released: Condvar,
+}
+
+struct PoolState {
+    // Idle connections, the least recently used first.
+    idle: Vec<Idle>,
+    // Number of connections, idle or checked out.
+    size: usize,
+}
+
+struct Idle {
+    client: Client,
+    since: Instant,
+}
+
+/// A connection checked out of a `ClientPool`, checked back in when dropped.
+pub struct PooledClient<'a> {
+    pool: &'a ClientPool,
+    client: Option<Client>,
+}
+
+impl ClientPool {
+    /// Creates a pool of connections to `addr`, opening `min_size` of them.
+    pub fn new(addr: SocketAddr, options: PoolOptions) -> Result<Self> {
+        let mut idle = Vec::with_capacity(options.min_size);
+        for _ in 0..options.min_size {
+            idle.push(Idle {
+                client: Client::connect(addr)?,
+                since: Instant::now(),
+            });
+        }
+        let size = idle.len();
+        Ok(Self {
+            addr,
+            options,
+            state: Mutex::new(PoolState { idle, size }),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Checks a connection out, opening one if none is idle.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PoolTimeout` if `max_size` connections stay
+    /// checked out for the checkout timeout.
+    pub fn checkout(&self) -> Result<PooledClient<'_>> {
+        let deadline = Instant::now() + self.options.checkout_timeout;
+        let mut state = self.lock();
+        loop {
+            self.evict(&mut state);
+            while let Some(Idle { client, since }) = state.idle.pop() {
+                if client.is_closed() {
+                    state.size -= 1;
+                    continue;
+                }
+                if since.elapsed() < self.options.health_check_after {
+                    return Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    });
+                }
+                drop(state);
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                if client.ping(timeout).is_ok() {
+                    return Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    });
+                }
+                drop(client);
+                state = self.lock();
+                state.size -= 1;
+                self.released.notify_one();
+            }
+
+            if state.size < self.options.max_size {
+                state.size += 1;
+                drop(state);
+                return match Client::connect(self.addr) {
+                    Ok(client) => Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    }),
+                    Err(e) => {
+                        self.lock().size -= 1;
+                        self.released.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvsError::PoolTimeout);
+            }
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .expect("pool lock poisoned")
+                .0;
+        }
+    }
+
+    /// Returns the number of open connections, idle or checked out.
+    pub fn size(&self) -> usize {
+        self.lock().size
+    }
+
+    /// Gets the value of a given key from the server.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.checkout()?.get(key)
+    }
+
+    /// Sets the value of a string key in the server.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.checkout()?.set(key, value)
+    }
+
+    /// Removes a string key in the server.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.checkout()?.remove(key)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().expect("pool lock poisoned")
+    }
+
+    /// Closes the connections idle for longer than the idle timeout, down
+    /// to `min_size` connections.
+    fn evict(&self, state: &mut PoolState) {
+        let now = Instant::now();
+        let expired = state
+            .idle
+            .iter()
+            .take_while(|idle| now.duration_since(idle.since) >= self.options.idle_timeout)
+            .count()
+            .min(state.size.saturating_sub(self.options.min_size));
+        state.idle.drain(..expired);
+        state.size -= expired;
+    }
+
+    /// Takes a connection back, dropping it if it closed.
+    fn checkin(&self, client: Client) {
+        let mut state = self.lock();
+        if client.is_closed() {
+            state.size -= 1;
+        } else {
+            state.idle.push(Idle {
+                client,
+                since: Instant::now(),
+            });
+        }
+        self.released.notify_one();
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("client checked in")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.checkin(client);
+        }
+    }
+}
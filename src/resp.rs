@@ -0,0 +1,471 @@
+//! A front end speaking RESP2, the protocol of Redis, for its clients.
+//!
+//! It maps GET, SET, DEL, EXISTS, PING, ECHO, QUIT and SCAN onto the default
+//! namespace of the engine. Expirations set with `SET ... EX` are kept by the
+//! server in memory: they are lost on restart, and only RESP commands and a
+//! sweep at most every second remove the keys that expired.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    ops::Bound,
+    sync::{RwLock, RwLockWriteGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{error::Result, server::FlushingReader, KvsEngine, KvsError};
+
+/// Longest bulk string or array accepted from a client.
+const MAX_LEN: usize = 64 * 1024 * 1024;
+
+/// How often the expired keys are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keys set with an expiration, shared by the RESP connections of a server.
+///
+/// Its write lock also serializes the conditional sets. Reads only take the
+/// read lock, to check that they have nothing to expire.
+pub(crate) struct Expirations {
+    state: RwLock<Deadlines>,
+}
+
+struct Deadlines {
+    keys: HashMap<String, Instant>,
+    last_sweep: Instant,
+}
+
+/// A RESP2 value sent to the client.
+#[derive(Debug)]
+enum Value {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+impl Expirations {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: RwLock::new(Deadlines {
+                keys: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Removes the keys past their deadline from `engine` like `lock`, only
+    /// taking the write lock if there is one to remove or a sweep is due.
+    fn expire(&self, engine: &dyn KvsEngine, keys: &[String]) -> Result<()> {
+        {
+            let state = self.state.read().expect("expirations lock poisoned");
+            let now = Instant::now();
+            let due = now.duration_since(state.last_sweep) >= SWEEP_INTERVAL
+                || keys
+                    .iter()
+                    .any(|key| matches!(state.keys.get(key), Some(deadline) if *deadline <= now));
+            if !due {
+                return Ok(());
+            }
+        }
+        self.lock(engine, keys).map(drop)
+    }
+
+    /// Locks the deadlines, removing the keys past theirs from `engine`
+    /// first: those of `keys`, or all of them if it is time for a sweep.
+    fn lock(
+        &self,
+        engine: &dyn KvsEngine,
+        keys: &[String],
+    ) -> Result<RwLockWriteGuard<'_, Deadlines>> {
+        let mut state = self.state.write().expect("expirations lock poisoned");
+        let now = Instant::now();
+        let expired: Vec<String> = if now.duration_since(state.last_sweep) >= SWEEP_INTERVAL {
+            state.last_sweep = now;
+            state
+                .keys
+                .iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(key, _)| key.clone())
+                .collect()
+        } else {
+            keys.iter()
+                .filter(|key| matches!(state.keys.get(*key), Some(deadline) if *deadline <= now))
+                .cloned()
+                .collect()
+        };
+        for key in expired {
+            state.keys.remove(&key);
+            match engine.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl Value {
+    fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Value::Error(msg) => write!(writer, "-{}\r\n", msg.replace(['\r', '\n'], " "))?,
+            Value::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Value::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Value::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s)?,
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<KvsError> for Value {
+    fn from(e: KvsError) -> Self {
+        match e {
+            KvsError::WrongType => Value::Error(format!("WRONGTYPE {}", e)),
+            e => Value::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+/// Serves a connection speaking RESP2.
+pub(crate) fn serve(
+    engine: &dyn KvsEngine,
+    expirations: &Expirations,
+    tcp: TcpStream,
+) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let writer = RefCell::new(BufWriter::new(&tcp));
+    let mut reader = BufReader::new(FlushingReader {
+        tcp: &tcp,
+        writer: &writer,
+    });
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            // the stream can't be resynchronized, Redis closes it too.
+            Err(e) => {
+                Value::Error(format!("ERR Protocol error: {}", e))
+                    .write_to(&mut *writer.borrow_mut())?;
+                break;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        println!("Receive RESP command from {}: {:?}", peer_addr, args);
+        let quit = args[0].eq_ignore_ascii_case(b"quit");
+        let reply = if quit {
+            Value::Simple("OK")
+        } else {
+            execute(engine, expirations, args).unwrap_or_else(Value::from)
+        };
+        reply.write_to(&mut *writer.borrow_mut())?;
+        if quit {
+            break;
+        }
+    }
+
+    writer.borrow_mut().flush()?;
+    Ok(())
+}
+
+/// Reads a command, an array of bulk strings or an inline command, or
+/// `None` at the end of the stream.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    };
+
+    let count = parse_len(count)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| protocol_error("expected a bulk string"))?;
+        let len = parse_len(len)?;
+        // grows with the data received rather than with the declared length.
+        let mut arg = Vec::new();
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(protocol_error("unexpected end"));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not ended by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its CRLF, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader
+        .by_ref()
+        .take(64 * 1024)
+        .read_until(b'\n', &mut line)?
+        == 0
+    {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(protocol_error("line too long"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(len: &[u8]) -> Result<usize> {
+    std::str::from_utf8(len)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|len| *len <= MAX_LEN)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::StringError(msg.to_owned())
+}
+
+/// Executes a command, answering errors of the client with `Value::Error`.
+fn execute(engine: &dyn KvsEngine, expirations: &Expirations, args: Vec<Vec<u8>>) -> Result<Value> {
+    let mut args = args.into_iter();
+    let name = String::from_utf8_lossy(&args.next().unwrap_or_default()).to_ascii_lowercase();
+    let args = match args
+        .map(String::from_utf8)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(args) => args,
+        Err(_) => return Ok(Value::Error("ERR arguments must be UTF-8".to_owned())),
+    };
+    let arity_error = || {
+        Ok(Value::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )))
+    };
+
+    match name.as_str() {
+        "ping" => match args.as_slice() {
+            [] => Ok(Value::Simple("PONG")),
+            [message] => Ok(Value::Bulk(Some(message.clone()))),
+            _ => arity_error(),
+        },
+        "echo" => match args.as_slice() {
+            [message] => Ok(Value::Bulk(Some(message.clone()))),
+            _ => arity_error(),
+        },
+        "get" => match args.as_slice() {
+            [key] => {
+                expirations.expire(engine, &args)?;
+                Ok(Value::Bulk(engine.get(key.clone())?))
+            }
+            _ => arity_error(),
+        },
+        "set" => {
+            if args.len() < 2 {
+                return arity_error();
+            }
+            set(engine, expirations, args)
+        }
+        "del" => {
+            if args.is_empty() {
+                return arity_error();
+            }
+            let mut state = expirations.lock(engine, &args)?;
+            for key in &args {
+                state.keys.remove(key);
+            }
+            Ok(Value::Integer(engine.remove_many(args)? as i64))
+        }
+        "exists" => {
+            if args.is_empty() {
+                return arity_error();
+            }
+            expirations.expire(engine, &args)?;
+            let mut count = 0;
+            for key in args {
+                match engine.get(key) {
+                    Ok(Some(_)) | Err(KvsError::WrongType) => count += 1,
+                    Ok(None) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Value::Integer(count))
+        }
+        "scan" => {
+            if args.is_empty() {
+                return arity_error();
+            }
+            scan(engine, expirations, args)
+        }
+        _ => Ok(Value::Error(format!("ERR unknown command '{}'", name))),
+    }
+}
+
+/// `SET key value [NX | XX] [EX seconds | PX milliseconds]`
+fn set(engine: &dyn KvsEngine, expirations: &Expirations, args: Vec<String>) -> Result<Value> {
+    let mut args = args.into_iter();
+    let (key, value) = (args.next().unwrap(), args.next().unwrap());
+    let (mut nx, mut xx, mut deadline) = (false, false, None);
+    while let Some(option) = args.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "nx" if !xx => nx = true,
+            "xx" if !nx => xx = true,
+            unit @ ("ex" | "px") if deadline.is_none() => {
+                let Some(amount) = args.next().and_then(|amount| amount.parse::<u64>().ok()) else {
+                    return Ok(Value::Error(
+                        "ERR value is not an integer or out of range".to_owned(),
+                    ));
+                };
+                let millis = match unit {
+                    "ex" => amount.checked_mul(1000),
+                    _ => Some(amount),
+                };
+                // Redis keeps expirations as milliseconds in an i64, and
+                // refuses the ones that don't fit.
+                let at = millis
+                    .filter(|millis| (1..=i64::MAX as u64).contains(millis))
+                    .and_then(|millis| Instant::now().checked_add(Duration::from_millis(millis)));
+                match at {
+                    Some(at) => deadline = Some(at),
+                    None => {
+                        return Ok(Value::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                }
+            }
+            _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    let mut state = expirations.lock(engine, std::slice::from_ref(&key))?;
+    if nx || xx {
+        let exists = match engine.get(key.clone()) {
+            Ok(value) => value.is_some(),
+            Err(KvsError::WrongType) => true,
+            Err(e) => return Err(e),
+        };
+        if exists != xx {
+            return Ok(Value::Bulk(None));
+        }
+    }
+    engine.set(key.clone(), value)?;
+    match deadline {
+        Some(deadline) => state.keys.insert(key, deadline),
+        None => state.keys.remove(&key),
+    };
+    Ok(Value::Simple("OK"))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// Pages go in key order. The cursor is `0` for the first page, and the last
+/// key of the previous page in hex otherwise, so keys set or removed between
+/// pages don't shift the next ones.
+fn scan(engine: &dyn KvsEngine, expirations: &Expirations, args: Vec<String>) -> Result<Value> {
+    let mut args = args.into_iter();
+    let cursor = args.next().unwrap();
+    let start = if cursor == "0" {
+        Bound::Unbounded
+    } else {
+        match decode_hex(&cursor) {
+            Some(last_key) => Bound::Excluded(last_key),
+            None => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+        }
+    };
+    let (mut pattern, mut count) = (None, 10);
+    while let Some(option) = args.next() {
+        match (option.to_ascii_lowercase().as_str(), args.next()) {
+            ("match", Some(glob)) => pattern = Some(glob),
+            ("count", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+            },
+            _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    expirations.expire(engine, &[])?;
+    // one more than the page tells whether there is a next one.
+    let pairs = engine.scan_limit(start, Bound::Unbounded, count.saturating_add(1))?;
+    let page = &pairs[..count.min(pairs.len())];
+    let keys = page
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| pattern.as_ref().is_none_or(|glob| matches(glob, key)))
+        .map(|key| Value::Bulk(Some(key.clone())))
+        .collect();
+    let next = match page.last() {
+        Some((last_key, _)) if pairs.len() > count => encode_hex(last_key),
+        _ => "0".to_owned(),
+    };
+    Ok(Value::Array(vec![
+        Value::Bulk(Some(next)),
+        Value::Array(keys),
+    ]))
+}
+
+fn encode_hex(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a key encoded by `encode_hex`.
+fn decode_hex(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Returns true if `key` matches the glob `pattern`, where `*` matches any
+/// characters and `?` one.
+fn matches(pattern: &str, key: &str) -> bool {
+    let (pattern, key): (Vec<char>, Vec<char>) = (pattern.chars().collect(), key.chars().collect());
+    // the last `*` seen, and the position in `key` it matches up to.
+    let (mut p, mut k, mut star) = (0, 0, None);
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, k));
+            p += 1;
+        } else if let Some((star_p, star_k)) = star {
+            p = star_p + 1;
+            k = star_k + 1;
+            star = Some((star_p, star_k + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -1,73 +1,730 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{Shutdown as Direction, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+use serde::{Deserialize, Serialize};
 use serde_json::{Deserializer, Serializer};
 
-use crate::{error::Result, GetResponse, KvsEngine, RemoveResponse, Request, SetResponse};
+use crate::{
+    common::{read_frame, write_frame},
+    error::Result,
+    resp::{self, Expirations},
+    thread_pool::ThreadPool,
+    Envelope, GetResponse, Hello, KvsEngine, KvsError, RemoveResponse, Reply, Request, Response,
+    SetResponse, Welcome, FEATURES, MAGIC, PROTOCOL_VERSIONS,
+};
+
+/// How long a shutdown waits for in-flight requests by default.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many connections are served at once by default.
+const MAX_CONNECTIONS: usize = 256;
+
+/// How many requests run at once by default, across connections.
+const REQUEST_THREADS: usize = 16;
 
 pub struct Server {
-    engine: Box<dyn KvsEngine>,
+    engine: Arc<dyn KvsEngine>,
+    protocol: Protocol,
+    expirations: Arc<Expirations>,
+    listener: TcpListener,
+    shutdown: Arc<Shutdown>,
+    shutdown_timeout: Duration,
+    max_connections: usize,
+    request_threads: usize,
+}
+
+/// The protocols a `Server` can speak.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    /// The protocol of `Client`, framed binary or JSON.
+    #[default]
+    Kvs,
+    /// RESP2, the protocol of Redis, for its clients and tools.
+    Resp,
+}
+
+/// Stops a `Server`, making its `run` return.
+///
+/// The server stops accepting connections, closes the reading side of the
+/// open ones so that they end after their current request, waits for them
+/// until the shutdown timeout, and flushes the engine.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<Shutdown>,
+}
+
+/// A server running on a background thread, returned by `Server::spawn`.
+pub struct ServerHandle {
     addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+/// State shared between a server, its connections and its shutdown handles.
+struct Shutdown {
+    requested: AtomicBool,
+    // Where the server listens. `shutdown` connects to it to wake the
+    // accepting thread up.
+    addr: SocketAddr,
+    // The connections being served, by id.
+    connections: Mutex<HashMap<u64, TcpStream>>,
+    // Signaled when a connection ends.
+    closed: Condvar,
+    next_id: AtomicU64,
+}
+
+/// Unregisters a connection when its thread ends, even by panicking.
+struct Connection {
+    shutdown: Arc<Shutdown>,
+    id: u64,
 }
 
 impl Server {
-    pub fn new(engine: Box<dyn KvsEngine>, addr: SocketAddr) -> Self {
-        Self { engine, addr }
+    /// Binds a server of `engine` to `addr`.
+    ///
+    /// Port 0 binds to a port picked by the system, which `local_addr` reports.
+    pub fn bind(engine: Box<dyn KvsEngine>, addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let shutdown = Shutdown {
+            requested: AtomicBool::new(false),
+            addr: listener.local_addr()?,
+            connections: Mutex::default(),
+            closed: Condvar::new(),
+            next_id: AtomicU64::new(0),
+        };
+        Ok(Self {
+            engine: Arc::from(engine),
+            protocol: Protocol::Kvs,
+            expirations: Arc::new(Expirations::new()),
+            listener,
+            shutdown: Arc::new(shutdown),
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            max_connections: MAX_CONNECTIONS,
+            request_threads: REQUEST_THREADS,
+        })
+    }
+
+    /// Returns the address the server is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shutdown.addr
+    }
+
+    /// Returns a handle stopping the server, even before it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: Arc::clone(&self.shutdown),
+        }
+    }
+
+    /// Sets the protocol the server speaks, `Protocol::Kvs` by default.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Sets how long a shutdown waits for in-flight requests to finish.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Sets how many connections are served at once, 256 by default.
+    ///
+    /// Further connections wait for one of those to close.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Sets how many requests run at once across connections, 16 by default.
+    ///
+    /// The requests of a connection run concurrently, but in order when they
+    /// use the same keys.
+    pub fn set_request_threads(&mut self, request_threads: usize) {
+        self.request_threads = request_threads;
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        println!("run: listening on {}", self.addr);
-        let listener = TcpListener::bind(self.addr)?;
-        for stream in listener.incoming() {
+    /// Runs the server on a background thread.
+    pub fn spawn(self) -> ServerHandle {
+        let addr = self.local_addr();
+        let shutdown = self.shutdown_handle();
+        let thread = thread::spawn(move || self.run());
+        ServerHandle {
+            addr,
+            shutdown,
+            thread,
+        }
+    }
+
+    /// Serves clients until the server is shut down.
+    pub fn run(self) -> Result<()> {
+        println!("run: listening on {}", self.local_addr());
+        let connections = ThreadPool::new(self.max_connections);
+        let requests = Arc::new(ThreadPool::new(self.request_threads));
+        for stream in self.listener.incoming() {
+            if self.shutdown.is_requested() {
+                break;
+            }
             match stream {
                 Ok(stream) => {
-                    if let Err(e) = self.server(stream) {
-                        eprintln!("Error on serving client: {}", e);
-                    }
+                    let engine = Arc::clone(&self.engine);
+                    let expirations = Arc::clone(&self.expirations);
+                    let requests = Arc::clone(&requests);
+                    let protocol = self.protocol;
+                    let connection = Shutdown::register(&self.shutdown, &stream)?;
+                    connections.spawn(move || {
+                        let served = match protocol {
+                            Protocol::Kvs => serve(&engine, &requests, stream),
+                            Protocol::Resp => resp::serve(&*engine, &expirations, stream),
+                        };
+                        if let Err(e) = served {
+                            eprintln!("Error on serving client: {}", e);
+                        }
+                        drop(connection);
+                    });
                 }
                 Err(e) => eprintln!("Connection failed: {}", e),
             }
         }
+        drop(self.listener);
 
-        Ok(())
-    }
-
-    fn server(&mut self, tcp: TcpStream) -> Result<()> {
-        let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
-        let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-
-        macro_rules! send_resp {
-            ($resp:expr) => {{
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                println!("Response sent to {}: {:?}", peer_addr, resp);
-            }};
-        }
-
-        for req in req_reader {
-            let req = req?;
-            println!("Receive request from {}: {:?}", peer_addr, req);
-            match req {
-                Request::Get { key } => send_resp!(match self.engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
-                }),
-                Request::Set { key, value } => send_resp!(match self.engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
-                }),
-                Request::Remove { key } => send_resp!(match self.engine.remove(key) {
-                    Ok(_) => RemoveResponse::Ok(()),
-                    Err(e) => RemoveResponse::Err(format!("{}", e)),
-                }),
+        if !self.shutdown.drain(self.shutdown_timeout) {
+            eprintln!("Shutdown timed out with requests in flight");
+        }
+        self.engine.flush()
+    }
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down, without waiting for it.
+    pub fn shutdown(&self) {
+        self.shutdown.requested.store(true, Ordering::SeqCst);
+        // the accepting thread checks the flag once it gets this connection.
+        let _ = TcpStream::connect(self.shutdown.addr);
+    }
+}
+
+impl ServerHandle {
+    /// Returns the address the server is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a handle stopping the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Shuts the server down, and waits for it to stop.
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.join()
+    }
+
+    /// Waits for the server to stop, returning the result of its `run`.
+    pub fn join(self) -> Result<()> {
+        self.thread
+            .join()
+            .map_err(|_| KvsError::StringError("server thread panicked".to_owned()))?
+    }
+}
+
+impl Shutdown {
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Registers a connection, so that a shutdown can end it.
+    fn register(shutdown: &Arc<Shutdown>, stream: &TcpStream) -> Result<Connection> {
+        let id = shutdown.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut connections = shutdown.connections.lock().expect("shutdown lock poisoned");
+        connections.insert(id, stream.try_clone()?);
+        Ok(Connection {
+            shutdown: Arc::clone(shutdown),
+            id,
+        })
+    }
+
+    /// Stops reading from every connection, and waits for them to end until
+    /// `timeout`. Returns false if some didn't.
+    fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut connections = self.connections.lock().expect("shutdown lock poisoned");
+        for stream in connections.values() {
+            // the request being served still gets its response.
+            let _ = stream.shutdown(Direction::Read);
+        }
+        while !connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
             }
+            connections = self
+                .closed
+                .wait_timeout(connections, deadline - now)
+                .expect("shutdown lock poisoned")
+                .0;
         }
+        true
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = self
+            .shutdown
+            .connections
+            .lock()
+            .expect("shutdown lock poisoned");
+        connections.remove(&self.id);
+        self.shutdown.closed.notify_all();
+    }
+}
+
+/// Reads a connection, flushing the responses to it first.
+///
+/// Responses are only written out when the server runs out of requests to
+/// read, so those of pipelined requests go together.
+pub(crate) struct FlushingReader<'a, 'b> {
+    pub(crate) tcp: &'a TcpStream,
+    pub(crate) writer: &'b RefCell<BufWriter<&'a TcpStream>>,
+}
+
+impl Read for FlushingReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.writer.borrow_mut().flush()?;
+        self.tcp.read(buf)
+    }
+}
+
+/// Serves a connection in the protocol told by its first byte.
+fn serve(engine: &Arc<dyn KvsEngine>, requests: &ThreadPool, tcp: TcpStream) -> Result<()> {
+    let mut first = [0];
+    match tcp.peek(&mut first)? {
+        0 => Ok(()),
+        _ if first[0] == MAGIC[0] => serve_framed(engine, requests, tcp),
+        _ => serve_json(engine, requests, tcp),
+    }
+}
+
+/// Serves the framed binary protocol.
+///
+/// A request that fails to decode is answered with an error, and the
+/// connection goes on with the next frame.
+fn serve_framed(engine: &Arc<dyn KvsEngine>, requests: &ThreadPool, tcp: TcpStream) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(tcp.try_clone()?);
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(KvsError::Handshake("unknown protocol".to_owned()));
+    }
+    let hello: Hello = match read_frame(&mut reader)? {
+        Some(frame) => bincode::deserialize(&frame)?,
+        None => return Ok(()),
+    };
+    let welcome = hello.negotiate(PROTOCOL_VERSIONS, FEATURES);
+    println!("Handshake with {}: {:?}", peer_addr, welcome);
+    write_frame(&mut writer, &welcome)?;
+    writer.flush()?;
+    if let Welcome::Err(_) = welcome {
+        return Ok(());
+    }
+
+    let replies = Arc::new(Replies::new(peer_addr, writer, Format::Framed));
+    while let Some(frame) = read_frame(&mut reader)? {
+        match bincode::deserialize::<Envelope>(&frame) {
+            Ok(Envelope { id, request }) => {
+                println!("Receive request {} from {}: {:?}", id, peer_addr, request);
+                dispatch(engine, requests, &replies, id, request);
+            }
+            // the id leads the envelope.
+            Err(e) => {
+                let reply = Reply {
+                    id: bincode::deserialize(&frame).unwrap_or_default(),
+                    response: Response::Err(format!("malformed request: {}", e)),
+                };
+                replies.begin(Some(&[]));
+                replies.finish(Some(&[]), &reply);
+            }
+        }
+    }
+    replies.wait_idle();
+    Ok(())
+}
+
+/// Serves the JSON protocol, a stream of `Envelope` answered by `Reply`.
+fn serve_json(engine: &Arc<dyn KvsEngine>, requests: &ThreadPool, tcp: TcpStream) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let writer = BufWriter::new(tcp.try_clone()?);
+    let replies = Arc::new(Replies::new(peer_addr, writer, Format::Json));
+    let req_reader = Deserializer::from_reader(BufReader::new(&tcp)).into_iter::<JsonRequest>();
+
+    for message in req_reader {
+        match message {
+            Ok(JsonRequest::Tagged(Envelope { id, request })) => {
+                println!("Receive request {} from {}: {:?}", id, peer_addr, request);
+                dispatch(engine, requests, &replies, id, request);
+            }
+            Ok(JsonRequest::Bare(request)) => {
+                println!("Receive request from {}: {:?}", peer_addr, request);
+                // bare requests are answered in order, as they carry no id.
+                replies.wait_idle();
+                answer_bare(&**engine, &replies, request);
+            }
+            Err(e) => {
+                replies.wait_idle();
+                return Err(e.into());
+            }
+        }
+    }
+    replies.wait_idle();
+    Ok(())
+}
+
+/// A request of a JSON connection, with or without an id.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonRequest {
+    Tagged(Envelope),
+    /// Sent by clients of the first version of the protocol.
+    Bare(Request),
+}
+
+/// Runs a request sent without an `Envelope` and answers it without a
+/// `Reply`: `Get`, `Set` and `Remove` in the shapes of the first version of
+/// the protocol, and the others with a bare `Response`.
+fn answer_bare(engine: &dyn KvsEngine, replies: &Replies, request: Request) {
+    enum Kind {
+        Get,
+        Set,
+        Remove,
+        Other,
+    }
+    let kind = match request {
+        Request::Get { .. } => Kind::Get,
+        Request::Set { .. } => Kind::Set,
+        Request::Remove { .. } => Kind::Remove,
+        _ => Kind::Other,
+    };
+    let response = panic::catch_unwind(AssertUnwindSafe(|| respond(engine, request)))
+        .unwrap_or_else(|_| Response::Err("request failed".to_owned()));
+    match (kind, response) {
+        (Kind::Get, Response::Err(e)) => replies.send(&GetResponse::Err(e)),
+        (Kind::Get, Response::Value(value)) => replies.send(&GetResponse::Ok(value)),
+        (Kind::Set, Response::Err(e)) => replies.send(&SetResponse::Err(e)),
+        (Kind::Set, _) => replies.send(&SetResponse::Ok(())),
+        (Kind::Remove, Response::Err(e)) => replies.send(&RemoveResponse::Err(e)),
+        (Kind::Remove, _) => replies.send(&RemoveResponse::Ok(())),
+        (_, response) => replies.send(&response),
+    }
+}
+
+/// Runs `request` on a thread of `requests` once the requests of the
+/// connection using the same keys are done, and sends its reply.
+fn dispatch(
+    engine: &Arc<dyn KvsEngine>,
+    requests: &ThreadPool,
+    replies: &Arc<Replies>,
+    id: u64,
+    request: Request,
+) {
+    let keys = footprint(&request);
+    replies.begin(keys.as_deref());
+    let engine = Arc::clone(engine);
+    let replies = Arc::clone(replies);
+    requests.spawn(move || {
+        let response = panic::catch_unwind(AssertUnwindSafe(|| respond(&*engine, request)))
+            .unwrap_or_else(|_| Response::Err("request failed".to_owned()));
+        replies.finish(keys.as_deref(), &Reply { id, response });
+    });
+}
+
+/// Returns hashes of the keys `request` uses, with their namespace, or `None`
+/// if it may use any.
+fn footprint(request: &Request) -> Option<Vec<u64>> {
+    let hash = |namespace: &Option<String>, key: &String| {
+        let mut hasher = DefaultHasher::new();
+        (namespace, key).hash(&mut hasher);
+        hasher.finish()
+    };
+    Some(match request {
+        Request::Get { key, namespace }
+        | Request::Set { key, namespace, .. }
+        | Request::Remove { key, namespace, .. }
+        | Request::Incr { key, namespace, .. }
+        | Request::ListPush { key, namespace, .. }
+        | Request::ListPop { key, namespace, .. }
+        | Request::ListRange { key, namespace, .. }
+        | Request::HashSet { key, namespace, .. }
+        | Request::HashGet { key, namespace, .. }
+        | Request::HashRemove { key, namespace, .. }
+        | Request::SetAdd { key, namespace, .. }
+        | Request::SetRemove { key, namespace, .. }
+        | Request::SetMembers { key, namespace } => vec![hash(namespace, key)],
+        Request::GetMany { keys, namespace } | Request::RemoveMany { keys, namespace } => {
+            keys.iter().map(|key| hash(namespace, key)).collect()
+        }
+        Request::SetMany { pairs, namespace } => {
+            pairs.iter().map(|(key, _)| hash(namespace, key)).collect()
+        }
+        Request::CreateNamespace { .. }
+        | Request::ListNamespaces
+        | Request::DropNamespace { .. } => return None,
+        Request::Ping => Vec::new(),
+    })
+}
+
+/// How replies are encoded on a connection.
+#[derive(Clone, Copy)]
+enum Format {
+    Framed,
+    Json,
+}
+
+/// The replies of a connection, sent by the threads running its requests.
+///
+/// Requests using the same keys run one after the other, in the order they
+/// were read. Replies are flushed once no request is left running, so those
+/// of pipelined requests go together.
+struct Replies {
+    peer_addr: SocketAddr,
+    writer: Mutex<BufWriter<TcpStream>>,
+    format: Format,
+    running: Mutex<Running>,
+    // Signaled when a request is done.
+    done: Condvar,
+}
+
+/// The requests of a connection being run.
+#[derive(Default)]
+struct Running {
+    count: usize,
+    // By hash of the keys they use.
+    keys: HashMap<u64, usize>,
+    // Set while a request that may use any key runs.
+    exclusive: bool,
+}
+
+impl Replies {
+    fn new(peer_addr: SocketAddr, writer: BufWriter<TcpStream>, format: Format) -> Self {
+        Self {
+            peer_addr,
+            writer: Mutex::new(writer),
+            format,
+            running: Mutex::default(),
+            done: Condvar::new(),
+        }
+    }
+
+    fn lock_running(&self) -> std::sync::MutexGuard<'_, Running> {
+        self.running.lock().expect("requests lock poisoned")
+    }
+
+    /// Waits for the running requests using any of `keys`, or for all of them
+    /// with `None`, and registers a request using them.
+    fn begin(&self, keys: Option<&[u64]>) {
+        let mut running = self.lock_running();
+        loop {
+            let conflict = running.exclusive
+                || match keys {
+                    Some(keys) => keys.iter().any(|key| running.keys.contains_key(key)),
+                    None => running.count > 0,
+                };
+            if !conflict {
+                break;
+            }
+            running = self.done.wait(running).expect("requests lock poisoned");
+        }
+        running.count += 1;
+        match keys {
+            Some(keys) => {
+                for &key in keys {
+                    *running.keys.entry(key).or_default() += 1;
+                }
+            }
+            None => running.exclusive = true,
+        }
+    }
+
+    /// Writes the reply of a request registered by `begin` with `keys`, and
+    /// unregisters it.
+    fn finish(&self, keys: Option<&[u64]>, reply: &Reply) {
+        let mut writer = self.writer.lock().expect("writer lock poisoned");
+        let written = match self.format {
+            Format::Framed => write_frame(&mut *writer, reply),
+            Format::Json => serde_json::to_writer(&mut *writer, reply).map_err(KvsError::from),
+        };
+        match written {
+            Ok(()) => println!("Response sent to {}: {:?}", self.peer_addr, reply),
+            Err(e) => eprintln!("Error on replying to {}: {}", self.peer_addr, e),
+        }
+
+        let mut running = self.lock_running();
+        running.count -= 1;
+        match keys {
+            Some(keys) => {
+                for key in keys {
+                    if let Some(count) = running.keys.get_mut(key) {
+                        *count -= 1;
+                        if *count == 0 {
+                            running.keys.remove(key);
+                        }
+                    }
+                }
+            }
+            None => running.exclusive = false,
+        }
+        let idle = running.count == 0;
+        drop(running);
+        self.done.notify_all();
+        if idle {
+            if let Err(e) = writer.flush() {
+                eprintln!("Error on replying to {}: {}", self.peer_addr, e);
+            }
+        }
+    }
+
+    /// Writes and flushes an answer sent without a `Reply`.
+    fn send(&self, answer: &(impl Serialize + fmt::Debug)) {
+        let mut writer = self.writer.lock().expect("writer lock poisoned");
+        let written = serde_json::to_writer(&mut *writer, answer)
+            .map_err(KvsError::from)
+            .and_then(|()| Ok(writer.flush()?));
+        match written {
+            Ok(()) => println!("Response sent to {}: {:?}", self.peer_addr, answer),
+            Err(e) => eprintln!("Error on replying to {}: {}", self.peer_addr, e),
+        }
+    }
+
+    /// Waits for every running request to be done.
+    fn wait_idle(&self) {
+        let mut running = self.lock_running();
+        while running.count > 0 {
+            running = self.done.wait(running).expect("requests lock poisoned");
+        }
+    }
+}
+
+/// Executes `request` on `engine`.
+fn respond(engine: &dyn KvsEngine, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key, namespace } => {
+            in_namespace(engine, namespace, |ns| ns.get(key)).map(Response::Value)
+        }
+        Request::Set {
+            key,
+            value,
+            namespace,
+            previous,
+        } => in_namespace(engine, namespace, |ns| {
+            if previous {
+                ns.swap(key, value).map(Response::Value)
+            } else {
+                ns.set(key, value).map(|_| Response::Done)
+            }
+        }),
+        Request::Remove {
+            key,
+            namespace,
+            previous,
+        } => in_namespace(engine, namespace, |ns| {
+            if previous {
+                ns.take(key).map(|value| Response::Value(Some(value)))
+            } else {
+                ns.remove(key).map(|_| Response::Done)
+            }
+        }),
+        Request::Incr {
+            key,
+            delta,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.incr_by(key, delta)).map(Response::Int),
+        Request::ListPush {
+            key,
+            end,
+            value,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.list_push(key, end, value)).map(Response::Len),
+        Request::ListPop {
+            key,
+            end,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.list_pop(key, end)).map(Response::Value),
+        Request::ListRange {
+            key,
+            start,
+            stop,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.list_range(key, start, stop))
+            .map(Response::Values),
+        Request::HashSet {
+            key,
+            field,
+            value,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.hash_set(key, field, value))
+            .map(|_| Response::Done),
+        Request::HashGet {
+            key,
+            field,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.hash_get(key, field)).map(Response::Value),
+        Request::HashRemove {
+            key,
+            field,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.hash_remove(key, field)).map(Response::Flag),
+        Request::SetAdd {
+            key,
+            member,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.set_add(key, member)).map(Response::Flag),
+        Request::SetRemove {
+            key,
+            member,
+            namespace,
+        } => in_namespace(engine, namespace, |ns| ns.set_remove(key, member)).map(Response::Flag),
+        Request::SetMembers { key, namespace } => {
+            in_namespace(engine, namespace, |ns| ns.set_members(key)).map(Response::Values)
+        }
+        Request::GetMany { keys, namespace } => {
+            in_namespace(engine, namespace, |ns| ns.get_many(keys)).map(Response::ManyValues)
+        }
+        Request::SetMany { pairs, namespace } => {
+            in_namespace(engine, namespace, |ns| ns.set_many(pairs)).map(|_| Response::Done)
+        }
+        Request::RemoveMany { keys, namespace } => {
+            in_namespace(engine, namespace, |ns| ns.remove_many(keys)).map(Response::Len)
+        }
+        Request::CreateNamespace { name } => engine.create_namespace(name).map(|_| Response::Done),
+        Request::ListNamespaces => engine.list_namespaces().map(Response::Values),
+        Request::DropNamespace { name } => engine.drop_namespace(name).map(|_| Response::Done),
+        Request::Ping => Ok(Response::Done),
+    };
+    result.unwrap_or_else(|e| Response::Err(format!("{}", e)))
+}
 
-        Ok(())
+/// Runs `f` on the given namespace of `engine`, or on `engine` itself without one.
+fn in_namespace<T>(
+    engine: &dyn KvsEngine,
+    namespace: Option<String>,
+    f: impl FnOnce(&dyn KvsEngine) -> Result<T>,
+) -> Result<T> {
+    match namespace {
+        Some(name) => f(&*engine.namespace(name)?),
+        None => f(engine),
     }
 }
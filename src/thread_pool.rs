@@ -0,0 +1,92 @@
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs jobs on at most `max_threads` threads, started as they are needed.
+///
+/// Jobs beyond what the threads can take wait in a queue. A job that panics
+/// doesn't take its thread down.
+pub(crate) struct ThreadPool {
+    shared: Arc<Shared>,
+    max_threads: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Signaled when a job is queued or the pool is dropped.
+    available: Condvar,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    // Threads waiting for a job.
+    idle: usize,
+    closed: bool,
+}
+
+impl ThreadPool {
+    pub(crate) fn new(max_threads: usize) -> Self {
+        let state = State {
+            jobs: VecDeque::new(),
+            threads: 0,
+            idle: 0,
+            closed: false,
+        };
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                available: Condvar::new(),
+            }),
+            max_threads: max_threads.max(1),
+        }
+    }
+
+    /// Runs `job` on a thread of the pool, once one is free.
+    pub(crate) fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.shared.state.lock().expect("pool lock poisoned");
+        state.jobs.push_back(Box::new(job));
+        if state.jobs.len() > state.idle && state.threads < self.max_threads {
+            state.threads += 1;
+            let shared = Arc::clone(&self.shared);
+            thread::spawn(move || shared.work());
+        } else {
+            self.shared.available.notify_one();
+        }
+    }
+}
+
+impl Shared {
+    fn work(&self) {
+        let mut state = self.state.lock().expect("pool lock poisoned");
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                // the job reports its own errors.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                state = self.state.lock().expect("pool lock poisoned");
+            } else if state.closed {
+                state.threads -= 1;
+                return;
+            } else {
+                state.idle += 1;
+                state = self.available.wait(state).expect("pool lock poisoned");
+                state.idle -= 1;
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Lets the threads end once the queued jobs are done, without waiting for them.
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().expect("pool lock poisoned");
+        state.closed = true;
+        self.shared.available.notify_all();
+    }
+}
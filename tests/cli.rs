@@ -1,7 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::{open_engine, EngineKind, Server, ServerHandle};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +23,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +55,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +94,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +126,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +137,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +148,81 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs` should read and repair an encrypted store only with its key file.
+#[test]
+fn cli_key_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("keys");
+    fs::write(&key_file, format!("1 {}\n", "11".repeat(32))).unwrap();
+    let store_dir = temp_dir.path().join("store");
+    fs::create_dir(&store_dir).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&store_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&store_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env("KVS_KEY_FILE", &key_file)
+        .current_dir(&store_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&store_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .env("KVS_KEY_FILE", &key_file)
+        .current_dir(&store_dir)
+        .assert()
+        .success()
+        .stdout(contains("Recovered 1 keys"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&store_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +237,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +257,64 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
-    let (sender, receiver) = mpsc::sync_channel(0);
-    let temp_dir = TempDir::new().unwrap();
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
-    let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
-        .current_dir(&temp_dir)
+// Starts `kvs-server` in `dir` on a free port, and waits for it to listen.
+// Returns the server and the address it reported.
+fn start_server(dir: &Path, args: &[&str]) -> (Child, String) {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .args(["--addr", "127.0.0.1:0"])
+        .current_dir(dir)
+        .stdout(Stdio::piped())
         .spawn()
         .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    // keeps reading, so that the server never blocks on a full pipe.
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            let line = line.unwrap();
+            if let Some(addr) = line.strip_prefix("run: listening on ") {
+                let _ = sender.send(addr.to_owned());
+            }
+        }
+    });
+    let addr = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("server didn't start listening");
+    (child, addr)
+}
+
+fn cli_access_server(engine: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let (mut child, addr) = start_server(temp_dir.path(), &["--engine", engine]);
+    let addr = addr.as_str();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
-    thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +322,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +330,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +338,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +346,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +354,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +362,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +370,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -296,28 +381,24 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     // Reopen and check value
     let (sender, receiver) = mpsc::sync_channel(0);
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
-    let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
-        .current_dir(&temp_dir)
-        .spawn()
-        .unwrap();
+    let (mut child, addr) = start_server(temp_dir.path(), &["--engine", engine]);
+    let addr = addr.as_str();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
-    thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -328,10 +409,213 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled");
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm");
+}
+
+#[test]
+fn cli_access_server_btree_engine() {
+    cli_access_server("btree");
+}
+
+// Runs a kvs server on a free port in the test process, storing in `dir`.
+fn spawn_server(dir: &TempDir) -> ServerHandle {
+    let engine = open_engine(dir.path(), EngineKind::Kvs).unwrap();
+    Server::bind(engine, "127.0.0.1:0").unwrap().spawn()
+}
+
+#[test]
+fn cli_namespaces() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let addr = &server.local_addr().to_string();
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "default"]).assert().success();
+    client(&["set", "key1", "team-a", "--namespace", "a"])
+        .assert()
+        .failure()
+        .stderr(contains("namespace a not found"));
+    client(&["ns", "create", "a"]).assert().success();
+    client(&["ns", "create", "b"]).assert().success();
+    client(&["set", "key1", "team-a", "--namespace", "a"])
+        .assert()
+        .success();
+    client(&["get", "key1", "-n", "a"])
+        .assert()
+        .success()
+        .stdout("team-a\n");
+    client(&["get", "key1", "-n", "b"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("default\n");
+    client(&["ns", "list"]).assert().success().stdout("a\nb\n");
+
+    client(&["ns", "drop", "a"]).assert().success();
+    client(&["ns", "list"]).assert().success().stdout("b\n");
+    client(&["get", "key1", "-n", "a"])
+        .assert()
+        .failure()
+        .stderr(contains("namespace a not found"));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("default\n");
+
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn cli_incr_decr() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let addr = &server.local_addr().to_string();
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["incr", "hits"]).assert().success().stdout("1\n");
+    client(&["incr", "hits", "10"])
+        .assert()
+        .success()
+        .stdout("11\n");
+    client(&["decr", "hits"]).assert().success().stdout("10\n");
+    client(&["incr", "hits", "-20"])
+        .assert()
+        .success()
+        .stdout("-10\n");
+    client(&["get", "hits"]).assert().success().stdout("-10\n");
+    client(&["set", "name", "kvs"]).assert().success();
+    client(&["incr", "name"])
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn cli_batches() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let addr = &server.local_addr().to_string();
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["mset", "a", "1", "b", "2"]).assert().success();
+    client(&["mget", "a", "missing", "b"])
+        .assert()
+        .success()
+        .stdout("1\nKey not found\n2\n");
+    client(&["mset", "a", "1", "b"])
+        .assert()
+        .failure()
+        .stderr(contains("every key needs a value"));
+
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn cli_collections() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let addr = &server.local_addr().to_string();
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["rpush", "list", "b"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["lpush", "list", "a"])
+        .assert()
+        .success()
+        .stdout("2\n");
+    client(&["lrange", "list", "0", "-1"])
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+    client(&["rpop", "list"]).assert().success().stdout("b\n");
+    client(&["hset", "hash", "field", "value"])
+        .assert()
+        .success();
+    client(&["hget", "hash", "field"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    client(&["hdel", "hash", "field"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["sadd", "set", "x"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["sadd", "set", "x"])
+        .assert()
+        .success()
+        .stdout("0\n");
+    client(&["smembers", "set"])
+        .assert()
+        .success()
+        .stdout("x\n");
+    client(&["get", "set"])
+        .assert()
+        .failure()
+        .stderr(contains("wrong kind of value"));
+
+    server.shutdown().unwrap();
+}
+
+// The server should flush and exit successfully on SIGTERM, keeping what was set.
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = || start_server(temp_dir.path(), &["--engine", "kvs"]);
+    let client = |args: &[&str], addr: &str| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    let (mut child, addr) = server();
+    client(&["set", "key1", "value1"], &addr).assert().success();
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let (mut child, addr) = server();
+    client(&["get", "key1"], &addr)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
 }
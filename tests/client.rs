@@ -0,0 +1,538 @@
+use kvs::{
+    open_engine, Client, ClientPool, EngineKind, Envelope, GetResponse, Hello, KvsError,
+    PoolOptions, Protocol, Reply, Request, Response, Server, ServerHandle, SetResponse, Welcome,
+    MAGIC,
+};
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Runs a kvs server on a free port in the test process, storing in `dir`.
+fn spawn_server(dir: &TempDir) -> ServerHandle {
+    let engine = open_engine(dir.path(), EngineKind::Kvs).unwrap();
+    Server::bind(engine, "127.0.0.1:0").unwrap().spawn()
+}
+
+// Pipelined requests should be answered in order, each with its own result.
+#[test]
+fn pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let client = Client::connect(server.local_addr()).unwrap();
+
+    let mut pipeline = client.pipeline();
+    pipeline
+        .set("key1".to_owned(), "value1".to_owned())
+        .get("key1".to_owned())
+        .swap("key1".to_owned(), "value2".to_owned())
+        .take("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned());
+    assert_eq!(pipeline.len(), 6);
+    let responses = pipeline.execute().unwrap();
+    assert_eq!(responses.len(), 6);
+    assert_eq!(responses[0].as_ref().unwrap(), &None);
+    assert_eq!(responses[1].as_ref().unwrap().as_deref(), Some("value1"));
+    assert_eq!(responses[2].as_ref().unwrap().as_deref(), Some("value1"));
+    assert_eq!(responses[3].as_ref().unwrap().as_deref(), Some("value2"));
+    assert!(responses[4].is_err());
+    assert_eq!(responses[5].as_ref().unwrap(), &None);
+
+    // More requests than the socket buffers hold.
+    let mut pipeline = client.pipeline();
+    for i in 0..100_000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    assert!(pipeline.execute().unwrap().iter().all(|r| r.is_ok()));
+    let mut pipeline = client.pipeline();
+    for i in (0..100_000).step_by(1000) {
+        pipeline.get(format!("key{}", i));
+    }
+    for (i, response) in pipeline.execute().unwrap().into_iter().enumerate() {
+        assert_eq!(response.unwrap(), Some(format!("value{}", i * 1000)));
+    }
+
+    // The client still works one request at a time afterwards.
+    assert_eq!(
+        client.get("key5".to_owned()).unwrap().as_deref(),
+        Some("value5")
+    );
+    server.shutdown().unwrap();
+}
+
+// Threads sharing a client should each get the responses to their own requests.
+#[test]
+fn shared_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let client = Client::connect(server.local_addr()).unwrap();
+
+    thread::scope(|scope| {
+        for t in 0..8 {
+            let client = &client;
+            scope.spawn(move || {
+                for i in 0..200 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            });
+        }
+    });
+
+    // A clone shares the connection, with a namespace of its own.
+    client.create_namespace("ns".to_owned()).unwrap();
+    let mut namespaced = client.clone();
+    namespaced.set_namespace(Some("ns".to_owned()));
+    namespaced
+        .set("key0-0".to_owned(), "other".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.get("key0-0".to_owned()).unwrap().as_deref(),
+        Some("value0")
+    );
+    assert_eq!(
+        namespaced.get("key0-0".to_owned()).unwrap().as_deref(),
+        Some("other")
+    );
+    server.shutdown().unwrap();
+}
+
+// Connections beyond the limit of the server should wait for one to close.
+#[test]
+fn max_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = open_engine(temp_dir.path(), EngineKind::Kvs).unwrap();
+    let mut server = Server::bind(engine, "127.0.0.1:0").unwrap();
+    server.set_max_connections(1);
+    let server = server.spawn();
+    let addr = server.local_addr();
+
+    let first = Client::connect(addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let second = Client::connect(addr).unwrap();
+        sender.send(second.get("key1".to_owned()).unwrap()).unwrap();
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    drop(first);
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+        Some("value1".to_owned())
+    );
+    server.shutdown().unwrap();
+}
+
+// The pool should share connections between threads, bound their number,
+// evict idle ones and replace the ones the server closed.
+#[test]
+fn client_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let addr = server.local_addr();
+    let options = PoolOptions {
+        min_size: 1,
+        max_size: 2,
+        idle_timeout: Duration::from_secs(60),
+        checkout_timeout: Duration::from_millis(100),
+        health_check_after: Duration::ZERO,
+    };
+    let pool = ClientPool::new(addr, options.clone()).unwrap();
+    assert_eq!(pool.size(), 1);
+
+    thread::scope(|scope| {
+        for t in 0..4 {
+            let pool = &pool;
+            scope.spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    pool.set(key.clone(), "value".to_owned()).unwrap();
+                    assert_eq!(pool.get(key).unwrap().as_deref(), Some("value"));
+                }
+            });
+        }
+    });
+    assert!(pool.size() <= 2);
+
+    let first = pool.checkout().unwrap();
+    let second = pool.checkout().unwrap();
+    assert!(matches!(pool.checkout(), Err(KvsError::PoolTimeout)));
+    drop(first);
+    drop(second);
+    assert!(pool.checkout().is_ok());
+
+    // A restarted server closes the pooled connections, which fail their
+    // ping on checkout and get replaced.
+    server.shutdown().unwrap();
+    let engine = open_engine(temp_dir.path(), EngineKind::Kvs).unwrap();
+    let server = Server::bind(engine, addr).unwrap().spawn();
+    assert_eq!(
+        pool.get("key0-0".to_owned()).unwrap().as_deref(),
+        Some("value")
+    );
+    server.shutdown().unwrap();
+
+    // Idle connections beyond the minimum are closed.
+    let server = spawn_server(&temp_dir);
+    let pool = ClientPool::new(
+        server.local_addr(),
+        PoolOptions {
+            idle_timeout: Duration::ZERO,
+            ..options
+        },
+    )
+    .unwrap();
+    drop((pool.checkout().unwrap(), pool.checkout().unwrap()));
+    assert_eq!(pool.size(), 2);
+    let client = pool.checkout().unwrap();
+    assert_eq!(pool.size(), 1);
+    drop(client);
+    server.shutdown().unwrap();
+}
+
+// A server that stops answering shouldn't hang a checkout: the ping of an
+// idle connection gives up when the checkout timeout is reached.
+#[test]
+fn unresponsive_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (held, streams) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut magic = [0; MAGIC.len()];
+            stream.read_exact(&mut magic).unwrap();
+            let _: Hello = read_frame(&mut stream);
+            write_frame(
+                &mut stream,
+                &Welcome::Ok {
+                    version: 1,
+                    features: Vec::new(),
+                },
+            );
+            // the connection stays open, but nothing is ever answered.
+            if held.send(stream).is_err() {
+                break;
+            }
+        }
+    });
+
+    let options = PoolOptions {
+        min_size: 1,
+        max_size: 1,
+        idle_timeout: Duration::from_secs(60),
+        checkout_timeout: Duration::from_millis(200),
+        health_check_after: Duration::ZERO,
+    };
+    let pool = ClientPool::new(addr, options).unwrap();
+    let start = Instant::now();
+    let client = pool.checkout().unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    assert_eq!(pool.size(), 1);
+    assert!(matches!(
+        client.ping(Duration::from_millis(50)),
+        Err(KvsError::ServerTimeout)
+    ));
+    drop(client);
+    drop(streams);
+}
+
+fn write_frame<T: serde::Serialize>(stream: &mut TcpStream, message: &T) {
+    let payload = bincode::serialize(message).unwrap();
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&payload).unwrap();
+}
+
+fn read_frame<T: serde::de::DeserializeOwned>(stream: &mut TcpStream) -> T {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    bincode::deserialize(&payload).unwrap()
+}
+
+// The server should speak the binary protocol it negotiates, and JSON to
+// clients that don't open with a handshake.
+#[test]
+fn protocols() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let client = Client::connect(server.local_addr()).unwrap();
+    assert_eq!(client.protocol_version(), 1);
+    assert!(client.supports("batches"));
+    assert!(!client.supports("unknown"));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut json = TcpStream::connect(server.local_addr()).unwrap();
+    json.write_all(br#"{"id":7,"request":{"Get":{"key":"key1"}}}"#)
+        .unwrap();
+    let reply: serde_json::Value = serde_json::Deserializer::from_reader(&mut json)
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        reply,
+        serde_json::json!({"id": 7, "response": {"Value": "value1"}})
+    );
+
+    let mut binary = TcpStream::connect(server.local_addr()).unwrap();
+    binary.write_all(&MAGIC).unwrap();
+    write_frame(
+        &mut binary,
+        &Hello {
+            versions: (2, 3),
+            features: Vec::new(),
+        },
+    );
+    assert!(matches!(read_frame(&mut binary), Welcome::Err(_)));
+
+    let mut binary = TcpStream::connect(server.local_addr()).unwrap();
+    binary.write_all(&MAGIC).unwrap();
+    write_frame(
+        &mut binary,
+        &Hello {
+            versions: (1, 3),
+            features: vec!["namespaces".to_owned(), "unknown".to_owned()],
+        },
+    );
+    match read_frame(&mut binary) {
+        Welcome::Ok { version, features } => {
+            assert_eq!(version, 1);
+            assert_eq!(features, ["namespaces"]);
+        }
+        Welcome::Err(msg) => panic!("handshake failed: {}", msg),
+    }
+    // A malformed request is answered, and the connection goes on.
+    write_frame(&mut binary, &(3u64, 255u32));
+    let reply: Reply = read_frame(&mut binary);
+    assert_eq!(reply.id, 3);
+    assert!(matches!(reply.response, Response::Err(_)));
+    write_frame(
+        &mut binary,
+        &Envelope {
+            id: 4,
+            request: Request::Get {
+                key: "key1".to_owned(),
+                namespace: None,
+            },
+        },
+    );
+    let reply: Reply = read_frame(&mut binary);
+    assert_eq!(reply.id, 4);
+    assert_eq!(reply.response, Response::Value(Some("value1".to_owned())));
+
+    drop(client);
+    server.shutdown().unwrap();
+}
+
+// A client of the first version of the protocol sends requests without ids
+// and should get the answers of that version, in order.
+#[test]
+fn baseline_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .write_all(
+            concat!(
+                r#"{"Set":{"key":"key1","value":"value1"}}"#,
+                r#"{"Get":{"key":"key1"}}"#,
+                r#"{"Get":{"key":"key2"}}"#,
+                r#"{"Remove":{"key":"key2"}}"#,
+                r#"{"Remove":{"key":"key1"}}"#,
+                r#"{"Get":{"key":"key1"}}"#,
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut answers = String::new();
+    stream.read_to_string(&mut answers).unwrap();
+    assert_eq!(
+        answers,
+        concat!(
+            r#"{"Ok":null}"#,
+            r#"{"Ok":"value1"}"#,
+            r#"{"Ok":null}"#,
+            r#"{"Err":"Key not found"}"#,
+            r#"{"Ok":null}"#,
+            r#"{"Ok":null}"#,
+        )
+    );
+
+    // the answers read back as the types of the first version.
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .write_all(br#"{"Set":{"key":"key1","value":"value1"}}{"Get":{"key":"key1"}}"#)
+        .unwrap();
+    let mut answers = serde_json::Deserializer::from_reader(&mut stream);
+    assert!(matches!(
+        SetResponse::deserialize(&mut answers).unwrap(),
+        SetResponse::Ok(())
+    ));
+    assert!(matches!(
+        GetResponse::deserialize(&mut answers).unwrap(),
+        GetResponse::Ok(Some(value)) if value == "value1"
+    ));
+    drop(stream);
+    server.shutdown().unwrap();
+}
+
+// A slow request shouldn't hold up the requests sent after it on the same
+// connection, unless they use the same keys.
+#[test]
+fn concurrent_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.write_all(&MAGIC).unwrap();
+    write_frame(
+        &mut stream,
+        &Hello {
+            versions: (1, 1),
+            features: Vec::new(),
+        },
+    );
+    assert!(matches!(read_frame(&mut stream), Welcome::Ok { .. }));
+
+    let pairs = (0..100_000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let requests = [
+        Request::SetMany {
+            pairs,
+            namespace: None,
+        },
+        Request::Get {
+            key: "other".to_owned(),
+            namespace: None,
+        },
+        Request::Get {
+            key: "key99999".to_owned(),
+            namespace: None,
+        },
+    ];
+    for (id, request) in requests.into_iter().enumerate() {
+        write_frame(
+            &mut stream,
+            &Envelope {
+                id: id as u64,
+                request,
+            },
+        );
+    }
+    let replies: Vec<Reply> = (0..3).map(|_| read_frame(&mut stream)).collect();
+    assert_eq!(replies[0].id, 1);
+    assert_eq!(replies[0].response, Response::Value(None));
+    assert_eq!(replies[1].id, 0);
+    assert_eq!(replies[2].id, 2);
+    assert_eq!(
+        replies[2].response,
+        Response::Value(Some("value99999".to_owned()))
+    );
+    server.shutdown().unwrap();
+}
+
+// Sends a RESP command, and checks the raw reply.
+fn assert_resp(stream: &mut TcpStream, args: &[&str], expected: &str) {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(command.as_bytes()).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected, "{:?}", args);
+}
+
+// A server speaking RESP should answer Redis commands.
+#[test]
+fn resp() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = open_engine(temp_dir.path(), EngineKind::Kvs).unwrap();
+    let mut server = Server::bind(engine, "127.0.0.1:0").unwrap();
+    server.set_protocol(Protocol::Resp);
+    let server = server.spawn();
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+
+    assert_resp(&mut stream, &["PING"], "+PONG\r\n");
+    assert_resp(&mut stream, &["SET", "a", "1"], "+OK\r\n");
+    assert_resp(&mut stream, &["get", "a"], "$1\r\n1\r\n");
+    assert_resp(&mut stream, &["SET", "a", "2", "NX"], "$-1\r\n");
+    assert_resp(&mut stream, &["SET", "b", "2", "XX"], "$-1\r\n");
+    assert_resp(&mut stream, &["SET", "b", "2", "NX"], "+OK\r\n");
+    assert_resp(&mut stream, &["EXISTS", "a", "b", "c"], ":2\r\n");
+    assert_resp(&mut stream, &["DEL", "a", "c"], ":1\r\n");
+    assert_resp(&mut stream, &["GET", "a"], "$-1\r\n");
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "b*", "COUNT", "10"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nb\r\n",
+    );
+    // the cursor holds the last key, so removing keys between pages skips none.
+    assert_resp(&mut stream, &["SET", "c", "3"], "+OK\r\n");
+    assert_resp(&mut stream, &["SET", "d", "4"], "+OK\r\n");
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "COUNT", "2"],
+        "*2\r\n$2\r\n63\r\n*2\r\n$1\r\nb\r\n$1\r\nc\r\n",
+    );
+    assert_resp(&mut stream, &["DEL", "b", "c"], ":2\r\n");
+    assert_resp(
+        &mut stream,
+        &["SCAN", "63", "COUNT", "2"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nd\r\n",
+    );
+    assert_resp(&mut stream, &["SCAN", "x"], "-ERR invalid cursor\r\n");
+    assert_resp(&mut stream, &["DEL", "d"], ":1\r\n");
+    assert_resp(&mut stream, &["SET", "b", "2"], "+OK\r\n");
+
+    assert_resp(&mut stream, &["SET", "t", "v", "PX", "50"], "+OK\r\n");
+    assert_resp(&mut stream, &["EXISTS", "t"], ":1\r\n");
+    thread::sleep(Duration::from_millis(100));
+    assert_resp(&mut stream, &["GET", "t"], "$-1\r\n");
+    assert_resp(
+        &mut stream,
+        &["SET", "t", "v", "EX", "0"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    // expirations beyond i64::MAX milliseconds are refused, and the server goes on.
+    for ttl in [
+        ["EX", "18446744073709551615"],
+        ["EX", "9223372036854775807"],
+        ["PX", "18446744073709551615"],
+    ] {
+        assert_resp(
+            &mut stream,
+            &["SET", "t", "v", ttl[0], ttl[1]],
+            "-ERR invalid expire time in 'set' command\r\n",
+        );
+    }
+    assert_resp(&mut stream, &["GET", "t"], "$-1\r\n");
+    assert_resp(
+        &mut stream,
+        &["SET", "t", "v", "NX", "XX"],
+        "-ERR syntax error\r\n",
+    );
+    assert_resp(&mut stream, &["FOO"], "-ERR unknown command 'foo'\r\n");
+
+    // Inline commands, as typed in a terminal.
+    stream.write_all(b"PING hello\r\n").unwrap();
+    let mut reply = [0; 11];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"$5\r\nhello\r\n");
+
+    assert_resp(&mut stream, &["QUIT"], "+OK\r\n");
+    assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+    server.shutdown().unwrap();
+}
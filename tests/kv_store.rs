@@ -1,90 +1,142 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    open_engine, BTreeOptions, BTreeStore, ChaChaCipher, EngineKind, IndexMode, Keyring, KvStore,
+    KvStoreOptions, KvsEngine, KvsError, ListEnd, LsmOptions, LsmStore, Result, Retention,
+    WatchEvent,
+};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// Every engine, for the tests that should pass on all of them.
+const ENGINES: [EngineKind; 4] = [
+    EngineKind::Kvs,
+    EngineKind::Sled,
+    EngineKind::Lsm,
+    EngineKind::BTree,
+];
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = open_engine(temp_dir.path(), kind)?;
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("value1".to_owned()),
+            "{}",
+            kind
+        );
+        assert_eq!(
+            store.get("key2".to_owned())?,
+            Some("value2".to_owned()),
+            "{}",
+            kind
+        );
+    }
     Ok(())
 }
 
 // Should overwrite existent value
 #[test]
 fn overwrite_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-
-    // Open from disk again and check persistent data
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
 
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = open_engine(temp_dir.path(), kind)?;
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("value2".to_owned()),
+            "{}",
+            kind
+        );
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("value3".to_owned()),
+            "{}",
+            kind
+        );
+    }
     Ok(())
 }
 
 // Should get `None` when getting a non-existent key
 #[test]
 fn get_non_existent_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
 
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = open_engine(temp_dir.path(), kind)?;
+        assert_eq!(store.get("key2".to_owned())?, None, "{}", kind);
+    }
     Ok(())
 }
 
 #[test]
 fn remove_non_existent_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        assert!(store.remove("key1".to_owned()).is_err(), "{}", kind);
+    }
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove("key1".to_owned()).is_ok(), "{}", kind);
+        assert_eq!(store.get("key1".to_owned())?, None, "{}", kind);
+    }
     Ok(())
 }
 
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
+// Only the engines whose directory shrinks as they rewrite their files: sled
+// and the B+tree reuse space in place.
 #[test]
 fn compaction() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Lsm] {
+        compact(kind)?;
+    }
+    Ok(())
+}
+
+fn compact(kind: EngineKind) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = open_engine(temp_dir.path(), kind)?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,13 +166,1275 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = open_engine(temp_dir.path(), kind)?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)), "{}", kind);
         }
         return Ok(());
     }
 
-    panic!("No compaction detected");
+    panic!("No compaction detected for {}", kind);
+}
+
+// A record damaged in the middle of a log should be skipped by `repair`,
+// leaving the surrounding records readable and the damaged file in lost+found.
+#[test]
+fn repair_damaged_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.x");
+    let content = std::fs::read_to_string(&log)?;
+    let damaged = content.replacen(r#"{"Set":{"key":"key2""#, r#"#"Set":{"key":"key2""#, 1);
+    std::fs::write(&log, damaged)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.recovered_keys, 2);
+    assert!(report.suspect_keys.contains("key2"));
+    assert_eq!(report.quarantined.len(), 1);
+    assert!(temp_dir.path().join("lost+found").is_dir());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A blob that a damaged record may refer to should be moved to lost+found by
+// `repair` rather than deleted, and recovered blob values kept inline.
+#[test]
+fn repair_keeps_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: 16,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set("key1".to_owned(), "a value stored in a blob".to_owned())?;
+    store.set(
+        "key2".to_owned(),
+        "another value stored in a blob".to_owned(),
+    )?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.x");
+    let content = std::fs::read_to_string(&log)?;
+    let damaged = content.replacen(
+        r#"{"SetBlob":{"key":"key2""#,
+        r#"#"SetBlob":{"key":"key2""#,
+        1,
+    );
+    assert_ne!(damaged, content);
+    std::fs::write(&log, damaged)?;
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.recovered_keys, 1);
+    let blob = temp_dir.path().join("lost+found").join("1.blob");
+    assert!(report.quarantined.contains(&blob));
+    assert!(std::fs::read_to_string(blob)?.contains("another value stored in a blob"));
+    assert!(!temp_dir.path().join("1.blob").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("a value stored in a blob".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A second writable open of the same directory should fail while the first
+// store is alive, but read-only opens are always allowed.
+#[test]
+fn exclusive_directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked(_))
+    ));
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A read-only store should not touch the directory and should see records
+// written by a writer after `refresh`, including across compactions.
+#[test]
+fn read_only_refresh() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let files = || WalkDir::new(temp_dir.path()).into_iter().count();
+    let before = files();
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(files(), before);
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    reader.refresh()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `open_engine` should refuse directories created by another engine, whether
+// or not they carry the `engine` marker file.
+#[test]
+fn open_engine_detects_wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    open_engine(temp_dir.path(), EngineKind::Kvs)?;
+    assert!(matches!(
+        open_engine(temp_dir.path(), EngineKind::Sled),
+        Err(KvsError::WrongEngine {
+            expected: EngineKind::Sled,
+            found: EngineKind::Kvs,
+        })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(sled::open(temp_dir.path())?);
+    assert!(matches!(
+        open_engine(temp_dir.path(), EngineKind::Kvs),
+        Err(KvsError::WrongEngine {
+            expected: EngineKind::Kvs,
+            found: EngineKind::Sled,
+        })
+    ));
+    open_engine(temp_dir.path(), EngineKind::Sled)?;
+    Ok(())
+}
+
+// Cached values should be served until the key changes and stay valid
+// across compactions.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        cache_capacity: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().cache_hits, 1);
+    assert_eq!(store.stats().cache_misses, 1);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats().cache_misses, 2);
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats().cache_hits, 2);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats().cached_bytes, 0);
+
+    // values larger than the whole cache are never cached.
+    store.set("key2".to_owned(), "x".repeat(2048))?;
+    store.get("key2".to_owned())?;
+    assert_eq!(store.stats().cached_bytes, 0);
+    Ok(())
+}
+
+// Readers on many threads should see consistent values while a writer keeps
+// overwriting keys and compactions retire old log files.
+#[test]
+fn concurrent_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id))?;
+                        assert!(value.is_some_and(|value| value.parse::<u32>().is_ok()));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for iter in 1..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), iter.to_string())?;
+        }
+        if iter % 25 == 0 {
+            store.compact()?;
+        }
+    }
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// The hashed index should behave like the ordered one, including scans,
+// compaction and reopening.
+#[test]
+fn hashed_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index_mode: IndexMode::Hashed,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for key_id in 0..20 {
+        store.set(format!("key{:02}", key_id), "value1".to_owned())?;
+    }
+    store.set("key03".to_owned(), "value2".to_owned())?;
+    store.remove("key04".to_owned())?;
+    assert!(store.remove("key04".to_owned()).is_err());
+    assert_eq!(store.get("key03".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key04".to_owned())?, None);
+    assert_eq!(store.get("key20".to_owned())?, None);
+
+    let scanned = store.scan("key02".to_owned().."key06".to_owned())?;
+    let expected = vec![
+        ("key02".to_owned(), "value1".to_owned()),
+        ("key03".to_owned(), "value2".to_owned()),
+        ("key05".to_owned(), "value1".to_owned()),
+    ];
+    assert_eq!(scanned, expected);
+
+    store.compact()?;
+    assert_eq!(store.get("key03".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(
+        store.scan("key02".to_owned().."key06".to_owned())?,
+        expected
+    );
+    assert_eq!(store.scan(..)?.len(), 19);
+    assert_eq!(
+        store.scan_limit(Bound::Included("key02".to_owned()), Bound::Unbounded, 3)?,
+        expected
+    );
+    drop(store);
+
+    // the same log can be opened with the ordered index.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan("key02".to_owned().."key06".to_owned())?,
+        expected
+    );
+    Ok(())
+}
+
+// Values above the blob threshold should live in blob files, survive
+// compaction and reopening, and have their garbage collected.
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: 64,
+        ..KvStoreOptions::default()
+    };
+    let blob_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("blob".as_ref()))
+            .count()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter).repeat(100))?;
+        }
+    }
+    assert_eq!(blob_files(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("9".repeat(100)));
+
+    // nine tenths of the blob file are garbage, so it is rewritten.
+    store.compact()?;
+    assert_eq!(blob_files(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("9".repeat(100)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".repeat(100)));
+    }
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.scan(..)?.len(), 10);
+    drop(store);
+
+    // a store opened without the threshold still reads existing blobs.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("9".repeat(100)));
+    Ok(())
+}
+
+// The LSM engine should keep every write across memtable flushes, compactions
+// and reopening.
+#[test]
+fn lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        memtable_capacity: 1024,
+        block_size: 256,
+        table_size: 4 * 1024,
+        level0_tables: 2,
+        level1_size: 8 * 1024,
+    };
+    let count_tables = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+
+    let store = LsmStore::open_with_options(temp_dir.path(), &options)?;
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in (0..200).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(matches!(
+        store.remove("key0".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("19".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // flushed memtables were merged down instead of piling up in level 0.
+    let tables = count_tables();
+    assert!(tables > 0 && tables < 20, "{} tables", tables);
+
+    let store = LsmStore::open_with_options(temp_dir.path(), &options)?;
+    for key_id in 0..200 {
+        let expected = (key_id % 2 == 1).then(|| "19".to_owned());
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    drop(store);
+
+    let store = open_engine(temp_dir.path(), EngineKind::Lsm)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("19".to_owned()));
+    drop(store);
+    assert!(matches!(
+        open_engine(temp_dir.path(), EngineKind::Kvs),
+        Err(KvsError::WrongEngine { .. })
+    ));
+    Ok(())
+}
+
+// The B+tree engine should keep every write across node splits, overflow
+// values, removals and reopening.
+#[test]
+fn btree_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeOptions { cache_pages: 16 };
+
+    let store = BTreeStore::open_with_options(temp_dir.path(), &options)?;
+    for key_id in 0..2000 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    let large = "x".repeat(10_000);
+    store.set("key0005".to_owned(), large.clone())?;
+    for key_id in (0..2000).step_by(2) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    assert!(matches!(
+        store.remove("key0000".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.set("k".repeat(2000), "value".to_owned()),
+        Err(KvsError::KeyTooLarge(2000))
+    ));
+    drop(store);
+
+    let store = BTreeStore::open_with_options(temp_dir.path(), &options)?;
+    for key_id in 0..2000 {
+        let expected = match key_id {
+            5 => Some(large.clone()),
+            _ if key_id % 2 == 1 => Some(format!("value{}", key_id)),
+            _ => None,
+        };
+        assert_eq!(store.get(format!("key{:04}", key_id))?, expected);
+    }
+    let entries = store.scan(
+        Bound::Included("key0100".to_owned()),
+        Bound::Excluded("key0110".to_owned()),
+    )?;
+    let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(
+        keys,
+        ["key0101", "key0103", "key0105", "key0107", "key0109"]
+    );
+
+    // pages freed by copy-on-write are reused instead of growing the file.
+    let len = std::fs::metadata(temp_dir.path().join("data.btree"))?.len();
+    for key_id in (1..2000).step_by(2) {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(
+        std::fs::metadata(temp_dir.path().join("data.btree"))?.len(),
+        len
+    );
+    Ok(())
+}
+
+// A B+tree file left without an intact meta page by a crash before its first
+// commit should open as a new tree, while a committed tree that lost both of
+// them should fail to open.
+#[test]
+fn btree_torn_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data.btree");
+    let zero_meta = || -> Result<()> {
+        let mut data = std::fs::read(&path)?;
+        data[..2 * 4096].fill(0);
+        std::fs::write(&path, data)?;
+        Ok(())
+    };
+
+    drop(BTreeStore::open(temp_dir.path())?);
+    zero_meta()?;
+    let store = BTreeStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = BTreeStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    zero_meta()?;
+    assert!(BTreeStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Reads of the B+tree should go on while writes commit, none of them seeing
+// the pages of its tree reused by later commits.
+#[test]
+fn btree_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(BTreeStore::open_with_options(
+        temp_dir.path(),
+        &BTreeOptions { cache_pages: 4 },
+    )?);
+    // values this long are kept in overflow pages, which every write frees.
+    let value = |key: &str, round: usize| format!("{:-<2000}", format!("{}:{}", key, round));
+    for key_id in 0..200 {
+        let key = format!("key{:03}", key_id);
+        store.set(key.clone(), value(&key, 0))?;
+    }
+
+    let writer = {
+        let store = Arc::clone(&store);
+        thread::spawn(move || -> Result<()> {
+            for round in 1..5 {
+                for key_id in 0..200 {
+                    let key = format!("key{:03}", key_id);
+                    store.set(key.clone(), value(&key, round))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                // until the writer's last commit.
+                while store.get("key199".to_owned())? != Some(value("key199", 4)) {
+                    let entries = store.scan(Bound::Unbounded, Bound::Unbounded)?;
+                    assert_eq!(entries.len(), 200);
+                    for (key, value) in entries {
+                        assert!(value.starts_with(&format!("{}:", key)), "{}", key);
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Every engine should return the same ordered range scans.
+#[test]
+fn engine_scans() -> Result<()> {
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        for key_id in (0..100).rev() {
+            store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key15".to_owned())?;
+
+        let entries = store.scan(
+            Bound::Excluded("key10".to_owned()),
+            Bound::Included("key17".to_owned()),
+        )?;
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["key11", "key12", "key13", "key14", "key16", "key17"],
+            "{}",
+            kind
+        );
+        assert_eq!(entries[0].1, "value11");
+        assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 99);
+        let entries = store.scan_limit(Bound::Included("key14".to_owned()), Bound::Unbounded, 3)?;
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["key14", "key16", "key17"], "{}", kind);
+        assert!(store
+            .scan_limit(Bound::Unbounded, Bound::Unbounded, 0)?
+            .is_empty());
+        assert!(store
+            .scan(
+                Bound::Included("key50".to_owned()),
+                Bound::Excluded("key20".to_owned())
+            )?
+            .is_empty());
+    }
+    Ok(())
+}
+
+// Records and blobs should never hold plaintext when a keyring is set, and a
+// compaction should move everything to the newest key.
+#[test]
+fn encryption_at_rest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let key_file = temp_dir.path().join("keys");
+    std::fs::write(&key_file, format!("# old key\n1 {}\n", "11".repeat(32)))?;
+    let options = |keyring: Keyring| KvStoreOptions {
+        blob_threshold: 64,
+        keyring,
+        ..KvStoreOptions::default()
+    };
+    let contains_plaintext = || {
+        WalkDir::new(&store_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .any(|entry| {
+                let buf = std::fs::read(entry.path()).expect("unable to read store file");
+                buf.windows(6).any(|window| window == b"secret")
+            })
+    };
+
+    let large = "secret".repeat(100);
+    let store =
+        KvStore::open_with_options(&store_dir, &options(Keyring::from_key_file(&key_file)?))?;
+    for key_id in 0..10 {
+        store.set(format!("secret-key{}", key_id), "secret-value".to_owned())?;
+    }
+    store.set("large".to_owned(), large.clone())?;
+    store.remove("secret-key0".to_owned())?;
+    drop(store);
+    assert!(!contains_plaintext());
+
+    assert!(matches!(
+        KvStore::open(&store_dir),
+        Err(KvsError::MissingKey(1))
+    ));
+    // repairing without the key would drop every record.
+    assert!(matches!(
+        KvStore::repair(&store_dir),
+        Err(KvsError::MissingKey(1))
+    ));
+
+    // rotate to key 2, keeping key 1 to read the existing records.
+    std::fs::write(
+        &key_file,
+        format!("1 {}\n2 {}\n", "11".repeat(32), "22".repeat(32)),
+    )?;
+    let store =
+        KvStore::open_with_options(&store_dir, &options(Keyring::from_key_file(&key_file)?))?;
+    assert_eq!(
+        store.get("secret-key1".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    store.set("secret-key1".to_owned(), "secret-new".to_owned())?;
+    store.compact()?;
+    drop(store);
+    assert!(!contains_plaintext());
+
+    let mut keyring = Keyring::new();
+    keyring.add(2, ChaChaCipher::new(&[0x22; 32]));
+    let store = KvStore::open_with_options(&store_dir, &options(keyring))?;
+    assert_eq!(store.get("secret-key0".to_owned())?, None);
+    assert_eq!(
+        store.get("secret-key1".to_owned())?,
+        Some("secret-new".to_owned())
+    );
+    assert_eq!(
+        store.get("secret-key9".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    Ok(())
+}
+
+// A compaction after a key rotation should re-encrypt the blobs of past
+// versions too, so that the old key can be dropped.
+#[test]
+fn rotate_key_with_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |keys: &[u32]| {
+        let mut keyring = Keyring::new();
+        for &id in keys {
+            keyring.add(id, ChaChaCipher::new(&[id as u8; 32]));
+        }
+        KvStoreOptions {
+            retention: Retention::Versions(2),
+            blob_threshold: 16,
+            keyring,
+            ..KvStoreOptions::default()
+        }
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options(&[1]))?;
+    store.set("key".to_owned(), "a past value stored in a blob".to_owned())?;
+    thread::sleep(Duration::from_millis(5));
+    let past = SystemTime::now();
+    thread::sleep(Duration::from_millis(5));
+    store.set("key".to_owned(), "a value stored in a blob".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options(&[1, 2]))?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options(&[2]))?;
+    assert_eq!(
+        store.get("key".to_owned())?,
+        Some("a value stored in a blob".to_owned())
+    );
+    assert_eq!(
+        store.get_at("key".to_owned(), past)?,
+        Some("a past value stored in a blob".to_owned())
+    );
+    Ok(())
+}
+
+// Watchers should get the changes under their prefix in commit order, and a
+// watcher that falls behind should be told how much it missed.
+#[test]
+fn watch_changes() -> Result<()> {
+    for kind in [
+        EngineKind::Kvs,
+        EngineKind::Sled,
+        EngineKind::Lsm,
+        EngineKind::BTree,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        let watcher = store.watch("user:".to_owned())?;
+        store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set("order:1".to_owned(), "book".to_owned())?;
+        store.set("user:1".to_owned(), "bob".to_owned())?;
+        store.remove("user:1".to_owned())?;
+
+        let events: Vec<_> = (0..3)
+            .map(|_| watcher.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                WatchEvent::Set {
+                    key: "user:1".to_owned(),
+                    value: "alice".to_owned()
+                },
+                WatchEvent::Set {
+                    key: "user:1".to_owned(),
+                    value: "bob".to_owned()
+                },
+                WatchEvent::Remove {
+                    key: "user:1".to_owned()
+                },
+            ],
+            "{}",
+            kind
+        );
+        assert!(watcher.try_recv().is_err());
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch(String::new())?;
+    for i in 0..1100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..1024 {
+        assert_eq!(
+            watcher.try_recv().unwrap(),
+            WatchEvent::Set {
+                key: format!("key{}", i),
+                value: "value".to_owned()
+            }
+        );
+    }
+    assert!(watcher.try_recv().is_err());
+    store.remove("key0".to_owned())?;
+    assert_eq!(
+        watcher.try_recv().unwrap(),
+        WatchEvent::Lagged { missed: 76 }
+    );
+    assert_eq!(
+        watcher.try_recv().unwrap(),
+        WatchEvent::Remove {
+            key: "key0".to_owned()
+        }
+    );
+    Ok(())
+}
+
+// Namespaces should keep keys apart, survive a reopen and a compaction, and
+// take their keys with them when dropped.
+#[test]
+fn namespaces() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        store.set("key1".to_owned(), "default".to_owned())?;
+        store.create_namespace("a".to_owned())?;
+        store.create_namespace("b".to_owned())?;
+        assert!(matches!(
+            store.create_namespace("a".to_owned()),
+            Err(KvsError::NamespaceExists(_))
+        ));
+        assert_eq!(store.list_namespaces()?, ["a", "b"], "{}", kind);
+
+        let a = store.namespace("a".to_owned())?;
+        a.set("key1".to_owned(), "in a".to_owned())?;
+        a.set("key2".to_owned(), "in a".to_owned())?;
+        a.remove("key2".to_owned())?;
+        assert_eq!(a.get("key1".to_owned())?, Some("in a".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+        let b = store.namespace("b".to_owned())?;
+        b.set("key3".to_owned(), "in b".to_owned())?;
+        assert_eq!(b.get("key1".to_owned())?, None);
+        assert_eq!(b.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 1);
+        drop((a, b));
+        drop(store);
+
+        let store = open_engine(temp_dir.path(), kind)?;
+        let a = store.namespace("a".to_owned())?;
+        assert_eq!(a.get("key1".to_owned())?, Some("in a".to_owned()));
+        assert_eq!(a.get("key2".to_owned())?, None);
+        drop(a);
+        store.drop_namespace("a".to_owned())?;
+        assert_eq!(store.list_namespaces()?, ["b"]);
+        assert!(matches!(
+            store.namespace("a".to_owned()),
+            Err(KvsError::NamespaceNotFound(_))
+        ));
+        assert!(matches!(
+            store.drop_namespace("a".to_owned()),
+            Err(KvsError::NamespaceNotFound(_))
+        ));
+        assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("a".to_owned())?;
+    store.create_namespace("empty".to_owned())?;
+    let a = store.namespace("a".to_owned())?;
+    for iter in 0..100 {
+        a.set("key".to_owned(), format!("{}", iter))?;
+    }
+    drop(a);
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.list_namespaces()?, ["a", "empty"]);
+    let a = store.namespace("a".to_owned())?;
+    assert_eq!(a.get("key".to_owned())?, Some("99".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_engine(temp_dir.path(), EngineKind::Lsm)?;
+    assert!(matches!(
+        store.create_namespace("a".to_owned()),
+        Err(KvsError::NamespacesUnsupported(EngineKind::Lsm))
+    ));
+    Ok(())
+}
+
+// Concurrent increments should never lose an update, and values that aren't
+// integers should be left alone.
+#[test]
+fn counters() -> Result<()> {
+    for kind in [
+        EngineKind::Kvs,
+        EngineKind::Sled,
+        EngineKind::Lsm,
+        EngineKind::BTree,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store: Arc<dyn KvsEngine> = Arc::from(open_engine(temp_dir.path(), kind)?);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..50 {
+                        store.incr_by("counter".to_owned(), 2)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(
+            store.get("counter".to_owned())?,
+            Some("800".to_owned()),
+            "{}",
+            kind
+        );
+        assert_eq!(store.incr_by("counter".to_owned(), -801)?, -1);
+        assert_eq!(store.incr_by("fresh".to_owned(), -3)?, -3);
+
+        store.set("name".to_owned(), "kvs".to_owned())?;
+        assert!(matches!(
+            store.incr_by("name".to_owned(), 1),
+            Err(KvsError::NotAnInteger)
+        ));
+        assert_eq!(store.get("name".to_owned())?, Some("kvs".to_owned()));
+        store.set("max".to_owned(), i64::MAX.to_string())?;
+        assert!(matches!(
+            store.incr_by("max".to_owned(), 1),
+            Err(KvsError::IncrementOverflow)
+        ));
+    }
+    Ok(())
+}
+
+// Lists, hashes and sets should survive reopening and compaction, and never
+// mix with string keys.
+#[test]
+fn collections() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        let list = || "list".to_owned();
+        assert_eq!(store.list_push(list(), ListEnd::Back, "b".to_owned())?, 1);
+        assert_eq!(store.list_push(list(), ListEnd::Back, "c".to_owned())?, 2);
+        assert_eq!(store.list_push(list(), ListEnd::Front, "a".to_owned())?, 3);
+        assert_eq!(
+            store.list_range(list(), 0, -1)?,
+            ["a", "b", "c"],
+            "{}",
+            kind
+        );
+        assert_eq!(store.list_range(list(), -2, 10)?, ["b", "c"]);
+        assert_eq!(store.list_pop(list(), ListEnd::Back)?, Some("c".to_owned()));
+
+        store.hash_set("hash".to_owned(), "f1".to_owned(), "v1".to_owned())?;
+        store.hash_set("hash".to_owned(), "f2".to_owned(), "v2".to_owned())?;
+        assert!(store.hash_remove("hash".to_owned(), "f2".to_owned())?);
+        assert!(!store.hash_remove("hash".to_owned(), "f2".to_owned())?);
+
+        assert!(store.set_add("set".to_owned(), "x".to_owned())?);
+        assert!(!store.set_add("set".to_owned(), "x".to_owned())?);
+        assert!(store.set_add("set".to_owned(), "y".to_owned())?);
+        assert!(store.set_remove("set".to_owned(), "y".to_owned())?);
+
+        store.set("string".to_owned(), "value".to_owned())?;
+        assert!(matches!(
+            store.list_push("string".to_owned(), ListEnd::Back, "v".to_owned()),
+            Err(KvsError::WrongType)
+        ));
+        assert!(matches!(
+            store.get("hash".to_owned()),
+            Err(KvsError::WrongType)
+        ));
+        assert!(matches!(
+            store.set_add("hash".to_owned(), "x".to_owned()),
+            Err(KvsError::WrongType)
+        ));
+        store.set_add("gone".to_owned(), "x".to_owned())?;
+        store.remove("gone".to_owned())?;
+        assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 1);
+        drop(store);
+
+        let store = open_engine(temp_dir.path(), kind)?;
+        assert_eq!(store.list_range(list(), 0, -1)?, ["a", "b"], "{}", kind);
+        assert_eq!(
+            store.hash_get("hash".to_owned(), "f1".to_owned())?,
+            Some("v1".to_owned())
+        );
+        assert_eq!(store.hash_get("hash".to_owned(), "f2".to_owned())?, None);
+        assert_eq!(store.set_members("set".to_owned())?, ["x"]);
+        assert_eq!(store.set_members("gone".to_owned())?, Vec::<String>::new());
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.list_push("list".to_owned(), ListEnd::Back, format!("{}", iter))?;
+        if iter % 2 == 0 {
+            store.list_pop("list".to_owned(), ListEnd::Front)?;
+        }
+    }
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let values = store.list_range("list".to_owned(), 0, 1)?;
+    assert_eq!(values, ["50", "51"]);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_engine(temp_dir.path(), EngineKind::BTree)?;
+    assert!(matches!(
+        store.set_members("set".to_owned()),
+        Err(KvsError::CollectionsUnsupported(EngineKind::BTree))
+    ));
+    Ok(())
+}
+
+// A string write racing a collection write on the same key should leave the
+// key holding only one of them.
+#[test]
+fn racing_kinds() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store: Arc<dyn KvsEngine> = Arc::from(open_engine(temp_dir.path(), kind)?);
+        let writers: Vec<_> = (0..3)
+            .map(|writer| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for key_id in 0..300 {
+                        let key = format!("key{}", key_id);
+                        // losing the race is a WrongType error, which is fine.
+                        let _ = match writer {
+                            0 => store.set(key, "value".to_owned()),
+                            1 => store.incr_by(key, 1).map(drop),
+                            _ => store
+                                .list_push(key, ListEnd::Back, "v".to_owned())
+                                .map(drop),
+                        };
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for key_id in 0..300 {
+            let key = format!("key{}", key_id);
+            if store.get(key.clone()).is_ok() {
+                store.take(key.clone())?;
+                assert!(store.list_range(key, 0, -1)?.is_empty(), "{}", kind);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Past versions should be kept as the retention asks, across compactions.
+#[test]
+fn versioned_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention: Retention::Versions(3),
+        blob_threshold: 16,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    let mut times = Vec::new();
+    for value in ["v1", "v2", "a value stored in a blob", "v4"] {
+        store.set("key".to_owned(), value.to_owned())?;
+        thread::sleep(Duration::from_millis(5));
+        times.push(SystemTime::now());
+        thread::sleep(Duration::from_millis(5));
+    }
+    store.remove("key".to_owned())?;
+    let values: Vec<_> = store
+        .history("key".to_owned())?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(
+        values,
+        [
+            Some("a value stored in a blob".to_owned()),
+            Some("v4".to_owned()),
+            None
+        ]
+    );
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(
+        store.get_at("key".to_owned(), times[3])?,
+        Some("v4".to_owned())
+    );
+    assert_eq!(store.get_at("key".to_owned(), times[0])?, None);
+
+    for iter in 0..1000 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(
+        store.get_at("key".to_owned(), times[2])?,
+        Some("a value stored in a blob".to_owned())
+    );
+    assert_eq!(store.history("other".to_owned())?.len(), 3);
+    drop(store);
+
+    // versions that were superseded before the window are dropped.
+    let options = KvStoreOptions {
+        retention: Retention::Window(Duration::from_millis(50)),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    thread::sleep(Duration::from_millis(60));
+    store.set("other".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    let history = store.history("other".to_owned())?;
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].value, Some("999".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key".to_owned())?, []);
+    assert_eq!(store.history("other".to_owned())?.len(), 1);
+    Ok(())
+}
+
+// `swap` and `take` should hand back the value they replace or remove.
+#[test]
+fn swap_and_take() -> Result<()> {
+    for kind in [
+        EngineKind::Kvs,
+        EngineKind::Sled,
+        EngineKind::Lsm,
+        EngineKind::BTree,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        assert_eq!(store.swap("key".to_owned(), "v1".to_owned())?, None);
+        assert_eq!(
+            store.swap("key".to_owned(), "v2".to_owned())?,
+            Some("v1".to_owned()),
+            "{}",
+            kind
+        );
+        assert_eq!(store.take("key".to_owned())?, "v2");
+        assert_eq!(store.get("key".to_owned())?, None);
+        assert!(matches!(
+            store.take("key".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_add("set".to_owned(), "member".to_owned())?;
+    assert!(matches!(
+        store.take("set".to_owned()),
+        Err(KvsError::WrongType)
+    ));
+    Ok(())
+}
+
+// Batches should set, get and remove many keys in order on every engine, and
+// fail whole on a key holding a collection.
+#[test]
+fn batches() -> Result<()> {
+    for kind in ENGINES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        store.set_many(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key1".to_owned(), "value3".to_owned()),
+        ])?;
+        assert_eq!(
+            store.get_many(vec![
+                "key1".to_owned(),
+                "key3".to_owned(),
+                "key2".to_owned()
+            ])?,
+            vec![Some("value3".to_owned()), None, Some("value2".to_owned())],
+            "{}",
+            kind
+        );
+        assert_eq!(
+            store.remove_many(vec!["key1".to_owned(), "key3".to_owned()])?,
+            1
+        );
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    // A batch is applied whole or not at all.
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(temp_dir.path(), kind)?;
+        store.set_add("set".to_owned(), "member".to_owned())?;
+        assert!(matches!(
+            store.set_many(vec![
+                ("key".to_owned(), "value".to_owned()),
+                ("set".to_owned(), "value".to_owned()),
+            ]),
+            Err(KvsError::WrongType)
+        ));
+        assert_eq!(store.get("key".to_owned())?, None, "{}", kind);
+        assert_eq!(store.get_many(vec!["set".to_owned()])?, vec![None]);
+    }
+    Ok(())
+}
+
+// A batch cut off by a crash should be dropped whole, on reload and by
+// `repair`, keeping the records before it.
+#[test]
+fn torn_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_many(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+        ("key1".to_owned(), "value4".to_owned()),
+    ])?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // cut the log inside the last record of the batch.
+    let log = temp_dir.path().join("1.x");
+    let content = std::fs::read(&log)?;
+    std::fs::write(&log, &content[..content.len() - 10])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.recovered_keys, 2);
+    assert!(report.suspect_keys.contains("key2"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Readers and watchers should never see part of a batch.
+#[test]
+fn atomic_batches() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::BTree] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store: Arc<dyn KvsEngine> = Arc::from(open_engine(temp_dir.path(), kind)?);
+        let watcher = store.watch("key".to_owned())?;
+        let batch = |iter: u32| {
+            (0..10)
+                .map(|key_id| (format!("key{}", key_id), iter.to_string()))
+                .collect::<Vec<_>>()
+        };
+        store.set_many(batch(0))?;
+
+        let reader = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..200 {
+                    // keys read later can't be from an older batch.
+                    let keys = (0..10).map(|key_id| format!("key{}", key_id)).collect();
+                    let values: Vec<u32> = store
+                        .get_many(keys)?
+                        .into_iter()
+                        .map(|value| value.expect("key is set").parse().expect("a number"))
+                        .collect();
+                    assert!(
+                        values.windows(2).all(|pair| pair[0] <= pair[1]),
+                        "{:?}",
+                        values
+                    );
+                }
+                Ok(())
+            })
+        };
+        for iter in 1..100 {
+            store.set_many(batch(iter))?;
+        }
+        reader.join().expect("reader thread panicked")?;
+
+        // the events of a batch arrive together, in order.
+        let events: Vec<_> = std::iter::from_fn(|| watcher.try_recv().ok()).collect();
+        assert_eq!(events.len(), 1000, "{}", kind);
+        for (i, event) in events.into_iter().enumerate() {
+            let expected = WatchEvent::Set {
+                key: format!("key{}", i % 10),
+                value: (i / 10).to_string(),
+            };
+            assert_eq!(event, expected, "{}", kind);
+        }
+    }
+    Ok(())
 }